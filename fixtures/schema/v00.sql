@@ -0,0 +1,11 @@
+-- The only schema written before migrations and the schema versions existed, do not edit
+CREATE TABLE atmosphere_data (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp TEXT NOT NULL,
+    average_temperature REAL NOT NULL,
+    average_humidity REAL NOT NULL,
+    fridge_status TEXT NOT NULL,
+    dehumidifier_status TEXT NOT NULL,
+    humidifier_status TEXT NOT NULL,
+    ventilator_status TEXT NOT NULL
+);
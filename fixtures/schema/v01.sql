@@ -0,0 +1,12 @@
+-- Schema version 1 as released, do not edit
+CREATE TABLE atmosphere_data (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            average_temperature REAL NOT NULL,
+            average_humidity REAL NOT NULL,
+            fridge_status TEXT NOT NULL,
+            dehumidifier_status TEXT NOT NULL,
+            humidifier_status TEXT NOT NULL,
+            ventilator_status TEXT NOT NULL
+        );
+PRAGMA user_version = 1;
@@ -0,0 +1,18 @@
+-- Schema version 3 as released, do not edit
+CREATE TABLE atmosphere_data (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            average_temperature REAL NOT NULL,
+            average_humidity REAL NOT NULL,
+            fridge_status TEXT NOT NULL,
+            dehumidifier_status TEXT NOT NULL,
+            humidifier_status TEXT NOT NULL,
+            ventilator_status TEXT NOT NULL
+        , dew_point REAL, absolute_humidity REAL, vapour_pressure_deficit REAL);
+CREATE TABLE maintenance_sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at TEXT NOT NULL,
+            ended_at TEXT,
+            trigger TEXT NOT NULL
+        );
+PRAGMA user_version = 3;
@@ -0,0 +1,33 @@
+-- Schema version 6 as released, do not edit
+CREATE TABLE atmosphere_data (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            average_temperature REAL NOT NULL,
+            average_humidity REAL NOT NULL,
+            fridge_status TEXT NOT NULL,
+            dehumidifier_status TEXT NOT NULL,
+            humidifier_status TEXT NOT NULL,
+            ventilator_status TEXT NOT NULL
+        , dew_point REAL, absolute_humidity REAL, vapour_pressure_deficit REAL, atmospheric_quality_index REAL, hanging_weight REAL);
+CREATE TABLE maintenance_sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at TEXT NOT NULL,
+            ended_at TEXT,
+            trigger TEXT NOT NULL
+        );
+CREATE TABLE batches (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            start_weight REAL NOT NULL,
+            target_loss_percent REAL NOT NULL,
+            program TEXT,
+            started_at TEXT NOT NULL,
+            finished_at TEXT
+        );
+CREATE TABLE weigh_ins (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            batch_id INTEGER NOT NULL REFERENCES batches(id),
+            timestamp TEXT NOT NULL,
+            weight REAL NOT NULL
+        , source TEXT NOT NULL DEFAULT 'manual');
+PRAGMA user_version = 6;
@@ -0,0 +1,57 @@
+-- Schema version 8 as released, do not edit
+CREATE TABLE atmosphere_data (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            average_temperature REAL NOT NULL,
+            average_humidity REAL NOT NULL,
+            fridge_status TEXT NOT NULL,
+            dehumidifier_status TEXT NOT NULL,
+            humidifier_status TEXT NOT NULL,
+            ventilator_status TEXT NOT NULL
+        , dew_point REAL, absolute_humidity REAL, vapour_pressure_deficit REAL, atmospheric_quality_index REAL, hanging_weight REAL);
+CREATE TABLE maintenance_sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at TEXT NOT NULL,
+            ended_at TEXT,
+            trigger TEXT NOT NULL
+        );
+CREATE TABLE batches (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            start_weight REAL NOT NULL,
+            target_loss_percent REAL NOT NULL,
+            program TEXT,
+            started_at TEXT NOT NULL,
+            finished_at TEXT
+        , recipe_id INTEGER REFERENCES recipes(id));
+CREATE TABLE weigh_ins (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            batch_id INTEGER NOT NULL REFERENCES batches(id),
+            timestamp TEXT NOT NULL,
+            weight REAL NOT NULL
+        , source TEXT NOT NULL DEFAULT 'manual');
+CREATE TABLE recipes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            definition TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+CREATE TABLE sensor_readings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            atmosphere_data_id INTEGER NOT NULL REFERENCES atmosphere_data(id),
+            sensor_id INTEGER NOT NULL,
+            metric TEXT NOT NULL,
+            value REAL NOT NULL
+        );
+CREATE INDEX sensor_readings_atmosphere_data_id
+            ON sensor_readings(atmosphere_data_id);
+CREATE TABLE actuator_states (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            atmosphere_data_id INTEGER NOT NULL REFERENCES atmosphere_data(id),
+            actuator TEXT NOT NULL,
+            status TEXT NOT NULL
+        );
+CREATE INDEX actuator_states_atmosphere_data_id
+            ON actuator_states(atmosphere_data_id);
+PRAGMA user_version = 8;
@@ -0,0 +1,111 @@
+-- Schema version 12 as released, do not edit
+CREATE TABLE atmosphere_data (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            average_temperature REAL NOT NULL,
+            average_humidity REAL NOT NULL,
+            fridge_status TEXT NOT NULL,
+            dehumidifier_status TEXT NOT NULL,
+            humidifier_status TEXT NOT NULL,
+            ventilator_status TEXT NOT NULL
+        , dew_point REAL, absolute_humidity REAL, vapour_pressure_deficit REAL, atmospheric_quality_index REAL, hanging_weight REAL, timestamp_ms INTEGER);
+CREATE TABLE maintenance_sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at TEXT NOT NULL,
+            ended_at TEXT,
+            trigger TEXT NOT NULL
+        );
+CREATE TABLE batches (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            start_weight REAL NOT NULL,
+            target_loss_percent REAL NOT NULL,
+            program TEXT,
+            started_at TEXT NOT NULL,
+            finished_at TEXT
+        , recipe_id INTEGER REFERENCES recipes(id));
+CREATE TABLE weigh_ins (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            batch_id INTEGER NOT NULL REFERENCES batches(id),
+            timestamp TEXT NOT NULL,
+            weight REAL NOT NULL
+        , source TEXT NOT NULL DEFAULT 'manual');
+CREATE TABLE recipes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            definition TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+CREATE TABLE sensor_readings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            atmosphere_data_id INTEGER NOT NULL REFERENCES atmosphere_data(id),
+            sensor_id INTEGER NOT NULL,
+            metric TEXT NOT NULL,
+            value REAL NOT NULL
+        );
+CREATE INDEX sensor_readings_atmosphere_data_id
+            ON sensor_readings(atmosphere_data_id);
+CREATE TABLE actuator_states (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            atmosphere_data_id INTEGER NOT NULL REFERENCES atmosphere_data(id),
+            actuator TEXT NOT NULL,
+            status TEXT NOT NULL
+        );
+CREATE INDEX actuator_states_atmosphere_data_id
+            ON actuator_states(atmosphere_data_id);
+CREATE TABLE actuator_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            actuator TEXT NOT NULL,
+            old_status TEXT NOT NULL,
+            new_status TEXT NOT NULL,
+            source TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            readings TEXT NOT NULL
+        , timestamp_ms INTEGER);
+CREATE INDEX actuator_events_actuator ON actuator_events(actuator);
+CREATE INDEX atmosphere_data_timestamp_ms ON atmosphere_data(timestamp_ms);
+CREATE INDEX actuator_events_timestamp_ms ON actuator_events(timestamp_ms);
+CREATE TABLE atmosphere_hourly (
+                    bucket_start_ms INTEGER PRIMARY KEY,
+                    bucket_start TEXT NOT NULL,
+                    samples INTEGER NOT NULL,
+                    min_temperature REAL NOT NULL,
+                    max_temperature REAL NOT NULL,
+                    avg_temperature REAL NOT NULL,
+                    min_humidity REAL NOT NULL,
+                    max_humidity REAL NOT NULL,
+                    avg_humidity REAL NOT NULL,
+                    avg_vapour_pressure_deficit REAL,
+                    avg_atmospheric_quality_index REAL,
+                    fridge_duty_cycle REAL NOT NULL,
+                    humidifier_duty_cycle REAL NOT NULL,
+                    dehumidifier_duty_cycle REAL NOT NULL,
+                    ventilator_duty_cycle REAL NOT NULL,
+                    heater_duty_cycle REAL
+                );
+CREATE TABLE atmosphere_daily (
+                    bucket_start_ms INTEGER PRIMARY KEY,
+                    bucket_start TEXT NOT NULL,
+                    samples INTEGER NOT NULL,
+                    min_temperature REAL NOT NULL,
+                    max_temperature REAL NOT NULL,
+                    avg_temperature REAL NOT NULL,
+                    min_humidity REAL NOT NULL,
+                    max_humidity REAL NOT NULL,
+                    avg_humidity REAL NOT NULL,
+                    avg_vapour_pressure_deficit REAL,
+                    avg_atmospheric_quality_index REAL,
+                    fridge_duty_cycle REAL NOT NULL,
+                    humidifier_duty_cycle REAL NOT NULL,
+                    dehumidifier_duty_cycle REAL NOT NULL,
+                    ventilator_duty_cycle REAL NOT NULL,
+                    heater_duty_cycle REAL
+                );
+CREATE TABLE controller_state (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            saved_at TEXT NOT NULL,
+            state TEXT NOT NULL
+        );
+PRAGMA user_version = 12;
@@ -0,0 +1,208 @@
+use crate::clock::Clock;
+use crate::config::{BackupSettings, Settings};
+use crate::error::AtmosError;
+use crate::sqlite_client::SqliteClient;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use time::macros::format_description;
+use time::OffsetDateTime;
+use tokio::time::interval;
+
+const BACKUP_PREFIX: &str = "atmos-";
+const BACKUP_EXTENSION: &str = ".db";
+
+// `atmos-20240501T031200Z.db`, names sort in the order the backups were taken
+fn backup_file_name(now: OffsetDateTime) -> String {
+    let format = format_description!("[year][month][day]T[hour][minute][second]Z");
+    let timestamp = now
+        .to_offset(time::UtcOffset::UTC)
+        .format(format)
+        .unwrap_or_else(|_| now.unix_timestamp().to_string());
+    format!("{}{}{}", BACKUP_PREFIX, timestamp, BACKUP_EXTENSION)
+}
+
+// Backups in `directory`, oldest first
+pub fn list_backups(directory: &Path) -> Result<Vec<PathBuf>, AtmosError> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_EXTENSION)
+                })
+        })
+        .collect();
+    backups.sort();
+    Ok(backups)
+}
+
+// Takes a backup into `backup.directory` and deletes the oldest ones past `backup.keep`.
+// The copy is written under a temporary name first so a half-written file never looks
+// like a backup.
+pub fn create_backup(
+    sqlite_client: &SqliteClient,
+    backup: &BackupSettings,
+    now: OffsetDateTime,
+) -> Result<PathBuf, AtmosError> {
+    let directory = Path::new(&backup.directory);
+    fs::create_dir_all(directory)?;
+
+    let path = directory.join(backup_file_name(now));
+    let partial = path.with_extension("db.partial");
+    if let Err(e) = sqlite_client.backup_to(&partial) {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    fs::rename(&partial, &path)?;
+
+    let backups = list_backups(directory)?;
+    let expired = backups.len().saturating_sub(backup.keep.max(1));
+    for old in &backups[..expired] {
+        if let Err(e) = fs::remove_file(old) {
+            warn!("Failed to delete old backup {}: {}", old.display(), e);
+        }
+    }
+    Ok(path)
+}
+
+// Backs the database up every `backup.interval` seconds, starting one interval after
+// startup.
+pub async fn monitor_backups(
+    settings: Settings,
+    sqlite_client: Arc<SqliteClient>,
+    clock: Arc<dyn Clock>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    let backup = settings.backup;
+    if !backup.enabled {
+        info!("Scheduled backups disabled");
+        let _ = shutdown_rx.recv().await;
+        return Ok(());
+    }
+
+    let mut interval = interval(std::time::Duration::from_secs(backup.interval));
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let (backup, now) = (backup.clone(), clock.now());
+                let result = sqlite_client
+                    .call(move |db| create_backup(db, &backup, now))
+                    .await;
+                match result {
+                    Ok(path) => info!("backup() -> {}", path.display()),
+                    Err(e) => error!("Failed to back up the database: {}", e),
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Received shutdown signal");
+                break Ok(());
+            }
+        }
+    }
+}
+
+// `atmos restore --from <backup> [--config <name>] [--db <file>]`. Meant to be run with
+// the controller stopped, the database being replaced is first copied next to itself.
+pub fn restore_command(options: &HashMap<String, String>) -> Result<(), AtmosError> {
+    let backup = options
+        .get("from")
+        .map(PathBuf::from)
+        .ok_or_else(|| AtmosError::InvalidInput("Missing --from <backup>".to_string()))?;
+    if !backup.is_file() {
+        return Err(AtmosError::InvalidInput(format!(
+            "Backup {} not found",
+            backup.display()
+        )));
+    }
+    let db_name = match options.get("db") {
+        Some(db_name) => db_name.clone(),
+        None => {
+            Settings::from_file(options.get("config").map_or("config", String::as_str))?
+                .sqlite
+                .db_name
+        }
+    };
+
+    let sqlite_client = SqliteClient::new(&db_name)?;
+    let previous = PathBuf::from(format!("{}.before-restore", db_name));
+    sqlite_client.backup_to(&previous)?;
+    sqlite_client.restore_from(&backup)?;
+    println!(
+        "Restored {} from {}, the previous database was saved to {}",
+        db_name,
+        backup.display(),
+        previous.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psychrometrics::DerivedMetrics;
+    use crate::relay_ctrl::RelayStatus;
+    use crate::sqlite_client::{AtmosphereRow, HistoryQuery};
+    use time::Duration;
+
+    #[test]
+    fn test_backups_are_rotated_and_restorable() {
+        let directory = std::env::temp_dir().join(format!("atmos-backup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let backup = BackupSettings {
+            enabled: true,
+            directory: directory.to_string_lossy().into_owned(),
+            interval: 3600,
+            keep: 2,
+        };
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(10);
+        let insert = |timestamp| {
+            sqlite_client
+                .insert_atmosphere_row(&AtmosphereRow {
+                    timestamp,
+                    average_temperature: 12.0,
+                    average_humidity: 80.0,
+                    derived_metrics: DerivedMetrics::default(),
+                    atmospheric_quality_index: 90.0,
+                    hanging_weight: None,
+                    fridge_status: RelayStatus::Off,
+                    dehumidifier_status: RelayStatus::Off,
+                    humidifier_status: RelayStatus::Off,
+                    ventilator_status: RelayStatus::Off,
+                    heater_status: RelayStatus::Off,
+                    sensor_readings: Vec::new(),
+                })
+                .unwrap()
+        };
+
+        insert(now);
+        let first = create_backup(&sqlite_client, &backup, now).unwrap();
+        insert(now + Duration::HOUR);
+        for hours in 1..=3 {
+            create_backup(&sqlite_client, &backup, now + Duration::hours(hours)).unwrap();
+        }
+        let backups = list_backups(&directory).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(!backups.contains(&first));
+
+        let restored = SqliteClient::new(":memory:").unwrap();
+        restored.restore_from(&backups[0]).unwrap();
+        let (_, count) = restored
+            .read_atmosphere_data(&HistoryQuery {
+                from: now,
+                to: now + Duration::DAY,
+                limit: 10,
+                offset: 0,
+                ascending: true,
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+use crate::clock::Clock;
+use crate::error::AtmosError;
+use crate::event_bus::BusEvent;
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::{parse_timestamp, SqliteClient};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::time::{interval, Duration};
+
+// How often the active batch is checked for a stage change
+const STAGE_CHECK_INTERVAL: u64 = 60;
+
+// Number of most recent weights the weight-loss trend is fitted over. Drying slows down
+// as the product loses water, so older weigh-ins would make the projection optimistic.
+const TREND_POINTS: usize = 5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Batch {
+    pub id: i64,
+    pub name: String,
+    /// Weight when the batch went into the chamber (in grams)
+    pub start_weight: f32,
+    /// Weight loss at which the batch is done (in percent of the start weight)
+    pub target_loss_percent: f32,
+    /// Curing program the batch is hung under
+    pub program: Option<String>,
+    /// Recipe the batch was started from
+    pub recipe_id: Option<i64>,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WeighIn {
+    pub id: i64,
+    pub batch_id: i64,
+    pub timestamp: String,
+    /// Weight (in grams)
+    pub weight: f32,
+    /// `manual` or `load_cell`
+    pub source: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewBatch {
+    pub name: String,
+    pub start_weight: f32,
+    pub target_loss_percent: f32,
+    pub program: Option<String>,
+}
+
+impl NewBatch {
+    pub fn validate(&self) -> Result<(), AtmosError> {
+        if self.name.trim().is_empty() {
+            return Err(AtmosError::InvalidInput(
+                "Batch name must not be empty".to_string(),
+            ));
+        }
+        if !self.start_weight.is_finite() || self.start_weight <= 0.0 {
+            return Err(AtmosError::InvalidInput(
+                "Start weight must be positive".to_string(),
+            ));
+        }
+        if !self.target_loss_percent.is_finite()
+            || self.target_loss_percent <= 0.0
+            || self.target_loss_percent >= 100.0
+        {
+            return Err(AtmosError::InvalidInput(
+                "Target loss must be between 0 and 100 %".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchProgress {
+    pub current_weight: f32,
+    pub target_weight: f32,
+    pub weight_loss_percent: f32,
+    pub target_reached: bool,
+    /// Weight lost per day according to the recent trend (in grams)
+    pub loss_per_day: Option<f32>,
+    /// When the trend reaches the target weight
+    pub projected_completion: Option<String>,
+}
+
+// Least-squares slope and intercept of weight over days since `origin`
+fn weight_trend(points: &[(OffsetDateTime, f32)], origin: OffsetDateTime) -> Option<(f32, f32)> {
+    if points.len() < 2 {
+        return None;
+    }
+    let xy: Vec<(f32, f32)> = points
+        .iter()
+        .map(|(timestamp, weight)| ((*timestamp - origin).as_seconds_f32() / 86400.0, *weight))
+        .collect();
+    let n = xy.len() as f32;
+    let mean_x = xy.iter().map(|(x, _)| x).sum::<f32>() / n;
+    let mean_y = xy.iter().map(|(_, y)| y).sum::<f32>() / n;
+    let covariance: f32 = xy.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance: f32 = xy.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if variance == 0.0 {
+        return None;
+    }
+    let slope = covariance / variance;
+    Some((slope, mean_y - slope * mean_x))
+}
+
+// `weigh_ins` must be sorted by timestamp.
+pub fn batch_progress(batch: &Batch, weigh_ins: &[WeighIn]) -> Result<BatchProgress, AtmosError> {
+    let started_at = parse_timestamp(&batch.started_at)?;
+    let mut points = vec![(started_at, batch.start_weight)];
+    for weigh_in in weigh_ins {
+        points.push((parse_timestamp(&weigh_in.timestamp)?, weigh_in.weight));
+    }
+
+    let current_weight = points
+        .last()
+        .map_or(batch.start_weight, |(_, weight)| *weight);
+    let target_weight = batch.start_weight * (1.0 - batch.target_loss_percent / 100.0);
+    let target_reached = current_weight <= target_weight;
+
+    let recent = &points[points.len().saturating_sub(TREND_POINTS)..];
+    let trend = weight_trend(recent, started_at).filter(|(slope, _)| *slope < 0.0);
+    let projected_completion = match trend {
+        Some(_) if target_reached => None,
+        Some((slope, intercept)) => {
+            let days = (target_weight - intercept) / slope;
+            Some((started_at + time::Duration::seconds_f32(days * 86400.0)).to_string())
+        }
+        None => None,
+    };
+
+    Ok(BatchProgress {
+        current_weight,
+        target_weight,
+        weight_loss_percent: (batch.start_weight - current_weight) / batch.start_weight * 100.0,
+        target_reached,
+        loss_per_day: trend.map(|(slope, _)| -slope),
+        projected_completion,
+    })
+}
+
+// The active batch with the index and name of the recipe stage it is in, `None` when no
+// batch is running or it wasn't started from a recipe.
+fn current_stage(
+    sqlite_client: &SqliteClient,
+    now: OffsetDateTime,
+) -> Result<Option<(Batch, usize, String)>, AtmosError> {
+    let Some(batch) = sqlite_client.read_active_batch()? else {
+        return Ok(None);
+    };
+    let Some(recipe) = batch
+        .recipe_id
+        .map(|id| sqlite_client.read_recipe(id))
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(None);
+    };
+    let elapsed_days = (now - parse_timestamp(&batch.started_at)?).as_seconds_f32() / 86400.0;
+    Ok(recipe.recipe.stage_at(elapsed_days).map(|stage| {
+        let name = recipe.recipe.stages[stage].name.clone();
+        (batch, stage, name)
+    }))
+}
+
+// Publishes `StageAdvanced` when the active batch moves on to the next stage of its
+// recipe. The stage found on startup is only remembered, it was announced before.
+pub async fn monitor_stages(
+    sd: AccessSharedData,
+    sqlite_client: Arc<SqliteClient>,
+    clock: Arc<dyn Clock>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    let mut last_seen: Option<Option<(i64, usize)>> = None;
+    let mut interval = interval(Duration::from_secs(STAGE_CHECK_INTERVAL));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let now = clock.now();
+                let current = match sqlite_client.call(move |db| current_stage(db, now)).await {
+                    Ok(current) => current,
+                    Err(e) => {
+                        error!("Failed to read the current batch stage: {}", e);
+                        continue;
+                    }
+                };
+                let key = current.as_ref().map(|(batch, stage, _)| (batch.id, *stage));
+                if let (Some(previous), Some((batch, stage, stage_name))) = (last_seen, current) {
+                    if previous != key {
+                        info!("stages() -> batch {} entered stage {}", batch.name, stage_name);
+                        sd.publish(BusEvent::StageAdvanced {
+                            timestamp: now.to_string(),
+                            batch_id: batch.id,
+                            batch_name: batch.name,
+                            stage,
+                            stage_name,
+                        });
+                    }
+                }
+                last_seen = Some(key);
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Received shutdown signal");
+                break Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Duration;
+
+    fn weigh_in(days: i64, weight: f32) -> WeighIn {
+        WeighIn {
+            id: days,
+            batch_id: 1,
+            timestamp: (OffsetDateTime::UNIX_EPOCH + Duration::days(days)).to_string(),
+            weight,
+            source: "manual".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_batch_progress_projects_completion() {
+        let batch = Batch {
+            id: 1,
+            name: "bresaola".to_string(),
+            start_weight: 1000.0,
+            target_loss_percent: 30.0,
+            program: None,
+            recipe_id: None,
+            started_at: OffsetDateTime::UNIX_EPOCH.to_string(),
+            finished_at: None,
+        };
+
+        // 20 g a day puts the 700 g target at day 15
+        let weigh_ins = vec![weigh_in(5, 900.0), weigh_in(10, 800.0)];
+        let progress = batch_progress(&batch, &weigh_ins).unwrap();
+        assert_eq!(progress.current_weight, 800.0);
+        assert!((progress.weight_loss_percent - 20.0).abs() < 0.01);
+        assert!(!progress.target_reached);
+        assert!((progress.loss_per_day.unwrap() - 20.0).abs() < 0.01);
+        let projected = parse_timestamp(&progress.projected_completion.unwrap()).unwrap();
+        assert!(
+            (projected - (OffsetDateTime::UNIX_EPOCH + Duration::days(15))).abs()
+                < Duration::minutes(1)
+        );
+
+        let no_weigh_ins = batch_progress(&batch, &[]).unwrap();
+        assert_eq!(no_weigh_ins.weight_loss_percent, 0.0);
+        assert!(no_weigh_ins.projected_completion.is_none());
+    }
+}
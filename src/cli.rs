@@ -0,0 +1,44 @@
+use crate::backup::restore_command;
+use crate::error::AtmosError;
+use crate::export::export_command;
+use crate::replay::replay_command;
+use crate::retention::vacuum_command;
+use std::collections::HashMap;
+
+const USAGE: &str = "usage: atmos [replay [--from <time>] [--to <time>] [--csv <file>] [--config <name>] [--db <file>]]
+       atmos [export <readings|events|batches> [--from <time>] [--to <time>] [--format csv|ndjson] [--output <file>] [--config <name>] [--db <file>]]\n       atmos [restore --from <backup> [--config <name>] [--db <file>]]\n       atmos [vacuum [--config <name>] [--db <file>]]";
+
+// Collects `--key value` pairs following a subcommand.
+fn parse_options(args: &[String]) -> Result<HashMap<String, String>, AtmosError> {
+    let mut options = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let key = arg.strip_prefix("--").ok_or_else(|| {
+            AtmosError::InvalidInput(format!("Unexpected argument {}\n{}", arg, USAGE))
+        })?;
+        let value = args.next().ok_or_else(|| {
+            AtmosError::InvalidInput(format!("Missing value for --{}\n{}", key, USAGE))
+        })?;
+        options.insert(key.to_string(), value.clone());
+    }
+    Ok(options)
+}
+
+// Runs a one-shot subcommand instead of the controller. `args` excludes the program name.
+pub async fn run_command(args: &[String]) -> Result<(), AtmosError> {
+    match args[0].as_str() {
+        "replay" => replay_command(&parse_options(&args[1..])?).await,
+        "restore" => restore_command(&parse_options(&args[1..])?),
+        "vacuum" => vacuum_command(&parse_options(&args[1..])?),
+        "export" => {
+            let dataset = args.get(1).ok_or_else(|| {
+                AtmosError::InvalidInput(format!("Missing export dataset\n{}", USAGE))
+            })?;
+            export_command(dataset, &parse_options(&args[2..])?)
+        }
+        command => Err(AtmosError::InvalidInput(format!(
+            "Unknown command {}\n{}",
+            command, USAGE
+        ))),
+    }
+}
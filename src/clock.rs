@@ -0,0 +1,73 @@
+use futures::future::BoxFuture;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use tokio::time::{Duration, Instant};
+
+// Source of "now" for the control loop. Cooldowns and intervals are computed from it,
+// which lets the simulator drive the loop on tokio's (pausable) virtual time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> OffsetDateTime;
+
+    /// Waits while an actuator runs for a fixed duration (humidifier pulse, ventilation)
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
+
+// Wall-clock time derived from tokio's clock, so it stands still or jumps ahead together
+// with `tokio::time::pause()` / `advance()`.
+pub struct TokioClock {
+    start: OffsetDateTime,
+    origin: Instant,
+}
+
+impl TokioClock {
+    pub fn new(start: OffsetDateTime) -> Self {
+        TokioClock {
+            start,
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> OffsetDateTime {
+        self.start + self.origin.elapsed()
+    }
+}
+
+// Clock for replaying recorded history: time only moves when the replay sets it to the
+// next record, and actuator run times elapse instantly.
+pub struct ReplayClock {
+    now: Mutex<OffsetDateTime>,
+}
+
+impl ReplayClock {
+    pub fn new(start: OffsetDateTime) -> Self {
+        ReplayClock {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn set(&self, now: OffsetDateTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, _duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+}
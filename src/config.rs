@@ -1,23 +1,43 @@
 use crate::error::AtmosError;
-use config::{Config, File};
-use serde::Deserialize;
+use config::{Config, File, FileFormat};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::ops::Range;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
     pub temperature: TemperatureSettings,
     pub humidity: HumiditySettings,
+    #[serde(default)]
+    pub vpd: VpdSettings,
     pub ventilation: VentilationSettings,
     pub relay_pins: RelayPinSettings,
     pub webserver: WebserverSettings,
     pub sensor_read_cooldown: SensorReadCooldownSettings,
     pub polling_interval: PollingIntervalSettings,
     pub sqlite: SqliteSettings,
+    #[serde(default)]
+    pub quality_index: QualityIndexSettings,
+    #[serde(default)]
+    pub risk: RiskSettings,
+    #[serde(default)]
+    pub load_cell: LoadCellSettings,
+    #[serde(default)]
+    pub maintenance: MaintenanceSettings,
+    #[serde(default)]
+    pub dry_run: DryRunSettings,
+    #[serde(default)]
+    pub retention: RetentionSettings,
+    #[serde(default)]
+    pub backup: BackupSettings,
+    #[serde(default)]
+    pub write_buffer: WriteBufferSettings,
+    #[serde(default)]
+    pub controller_state: ControllerStateSettings,
     //pub email: EmailConfig,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TemperatureSettings {
     pub low_range_start: f32,
     pub low_range_end: f32,
@@ -42,7 +62,7 @@ impl TemperatureSettings {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct HumiditySettings {
     pub low_range_start: f32,
     pub low_range_end: f32,
@@ -53,6 +73,17 @@ pub struct HumiditySettings {
     pub humidifier_cooldown_duration: u64,
     pub humidifier_activation_duration: u64,
     pub dehumidifier_cooldown_duration: u64,
+    /// Whether the humidifier/dehumidifier follow relative humidity or VPD
+    #[serde(default)]
+    pub control_target: HumidityControlTarget,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HumidityControlTarget {
+    #[default]
+    RelativeHumidity,
+    Vpd,
 }
 
 impl HumiditySettings {
@@ -69,19 +100,329 @@ impl HumiditySettings {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+// Vapour pressure deficit bands (in kPa). A high VPD means dry air, so the humidifier
+// runs in the high range and the dehumidifier in the low range.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct VpdSettings {
+    pub low_range_start: f32,
+    pub low_range_end: f32,
+    pub high_range_start: f32,
+    pub high_range_end: f32,
+    pub ideal_range_start: f32,
+    pub ideal_range_end: f32,
+}
+
+impl Default for VpdSettings {
+    fn default() -> Self {
+        VpdSettings {
+            low_range_start: 0.0,
+            low_range_end: 0.29,
+            high_range_start: 0.61,
+            high_range_end: 10.0,
+            ideal_range_start: 0.3,
+            ideal_range_end: 0.6,
+        }
+    }
+}
+
+impl VpdSettings {
+    pub fn low_range(&self) -> Range<f32> {
+        self.low_range_start..self.low_range_end
+    }
+
+    pub fn high_range(&self) -> Range<f32> {
+        self.high_range_start..self.high_range_end
+    }
+
+    pub fn ideal_range(&self) -> Range<f32> {
+        self.ideal_range_start..self.ideal_range_end
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct VentilationSettings {
     pub interval: u64,
     pub duration: u64,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+// Weights of the atmospheric quality index components and the values at which each
+// component bottoms out at zero.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct QualityIndexSettings {
+    pub distance_weight: f32,
+    pub time_out_of_range_weight: f32,
+    pub rate_of_change_weight: f32,
+    pub sensor_health_weight: f32,
+    /// °C outside the ideal range
+    pub temperature_distance_limit: f32,
+    /// %RH outside the ideal range
+    pub humidity_distance_limit: f32,
+    /// Seconds spent outside the ideal ranges
+    pub out_of_range_duration_limit: u64,
+    /// °C per hour
+    pub temperature_rate_limit: f32,
+    /// %RH per hour
+    pub humidity_rate_limit: f32,
+    /// Seconds the rate of change is measured over, so sensor noise between two ticks
+    /// doesn't count as a change
+    pub rate_window: u64,
+    /// °C between the two sensors
+    pub temperature_disagreement_limit: f32,
+    /// %RH between the two sensors
+    pub humidity_disagreement_limit: f32,
+    /// Seconds after which the last sensor reading counts as stale
+    pub stale_reading_after: u64,
+}
+
+impl Default for QualityIndexSettings {
+    fn default() -> Self {
+        QualityIndexSettings {
+            distance_weight: 0.4,
+            time_out_of_range_weight: 0.2,
+            rate_of_change_weight: 0.2,
+            sensor_health_weight: 0.2,
+            temperature_distance_limit: 3.0,
+            humidity_distance_limit: 15.0,
+            out_of_range_duration_limit: 21600,
+            temperature_rate_limit: 4.0,
+            humidity_rate_limit: 20.0,
+            rate_window: 1800,
+            temperature_disagreement_limit: 2.0,
+            humidity_disagreement_limit: 10.0,
+            stale_reading_after: 300,
+        }
+    }
+}
+
+// Thresholds of the mold and case-hardening risk model
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RiskSettings {
+    /// %RH at or above which surface mold can grow
+    pub mold_humidity_threshold: f32,
+    /// °C at or above which surface mold can grow
+    pub mold_temperature_threshold: f32,
+    /// Hours of history the mold risk looks at
+    pub mold_window: u64,
+    /// Hours above the mold thresholds within the window that count as full risk
+    pub mold_hours_limit: f32,
+    /// Hours of history the humidity gradient is fitted over
+    pub gradient_window: u64,
+    /// Humidity drop (%RH per hour) that counts as full case-hardening risk
+    pub humidity_drop_limit: f32,
+    /// Seconds between two records beyond which the gap isn't counted
+    pub max_record_gap: u64,
+    /// Risk (0 to 1) at which an alert is raised
+    pub alert_threshold: f32,
+    /// Seconds between two risk evaluations
+    pub evaluation_interval: u64,
+}
+
+impl Default for RiskSettings {
+    fn default() -> Self {
+        RiskSettings {
+            mold_humidity_threshold: 85.0,
+            mold_temperature_threshold: 10.0,
+            mold_window: 24,
+            mold_hours_limit: 24.0,
+            gradient_window: 6,
+            humidity_drop_limit: 3.0,
+            max_record_gap: 600,
+            alert_threshold: 0.8,
+            evaluation_interval: 600,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LoadCellSettings {
+    /// Read the hanging weight from an HX711 load cell amplifier
+    pub enabled: bool,
+    /// GPIO pin wired to the HX711 DT (DOUT) pin
+    pub data_pin: u8,
+    /// GPIO pin wired to the HX711 SCK pin
+    pub clock_pin: u8,
+    /// Raw reading with nothing hanging, as reported by a tare
+    pub offset: i32,
+    /// Raw counts per gram, as reported by a calibration
+    pub scale: f32,
+    /// Conversions averaged per weight reading
+    pub samples: u8,
+    /// Seconds between two automatic weigh-ins of the active batch
+    pub weigh_in_interval: u64,
+}
+
+impl Default for LoadCellSettings {
+    fn default() -> Self {
+        LoadCellSettings {
+            enabled: false,
+            data_pin: 5,
+            clock_pin: 6,
+            offset: 0,
+            scale: 1.0,
+            samples: 10,
+            weigh_in_interval: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MaintenanceSettings {
+    /// GPIO pin of the optional door reed switch (wired to ground, internal pull-up)
+    pub door_switch_pin: Option<u8>,
+    /// Run the ventilator for the whole maintenance session
+    pub run_fan: bool,
+    /// Seconds to wait after the door closes before automatic control resumes
+    pub settle_delay: u64,
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        MaintenanceSettings {
+            door_switch_pin: None,
+            run_fan: false,
+            settle_delay: 600,
+        }
+    }
+}
+
+// How long history is kept at each resolution. Raw rows are rolled up into hourly
+// aggregates, hourly aggregates into daily ones, which are kept forever.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetentionSettings {
+    /// Roll up and delete old history, off keeps every raw row forever
+    pub enabled: bool,
+    /// Days raw rows are kept before being rolled up into hourly aggregates
+    pub raw_days: u64,
+    /// Days hourly aggregates are kept before being rolled up into daily ones
+    pub hourly_days: u64,
+    /// Seconds between two retention runs
+    pub interval: u64,
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        RetentionSettings {
+            enabled: false,
+            raw_days: 30,
+            hourly_days: 365,
+            interval: 3600,
+        }
+    }
+}
+
+// The controller state (relay timestamps for the cooldowns, maintenance mode, ...) is
+// saved to the database periodically and on shutdown, and restored on boot.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ControllerStateSettings {
+    pub enabled: bool,
+    /// Seconds between two saves
+    pub save_interval: u64,
+}
+
+impl Default for ControllerStateSettings {
+    fn default() -> Self {
+        ControllerStateSettings {
+            enabled: true,
+            save_interval: 30,
+        }
+    }
+}
+
+// How hard SQLite works to get a committed transaction onto the SD card
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Leaves syncing to the OS, a power cut can lose or corrupt recent data
+    Off,
+    /// Syncs at WAL checkpoints, a power cut can lose the last flushes but not corrupt
+    #[default]
+    Normal,
+    /// Syncs every flush
+    Full,
+}
+
+impl Durability {
+    pub fn synchronous(&self) -> &'static str {
+        match self {
+            Durability::Off => "OFF",
+            Durability::Normal => "NORMAL",
+            Durability::Full => "FULL",
+        }
+    }
+}
+
+// Readings and actuator events are kept in memory and written in one transaction every
+// `flush_interval` seconds or `max_rows` rows, instead of one write per tick. A crash loses
+// at most one window.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WriteBufferSettings {
+    pub enabled: bool,
+    /// Seconds between two flushes
+    pub flush_interval: u64,
+    /// Rows that trigger a flush before the interval is up
+    pub max_rows: usize,
+    pub durability: Durability,
+}
+
+impl Default for WriteBufferSettings {
+    fn default() -> Self {
+        WriteBufferSettings {
+            enabled: true,
+            flush_interval: 60,
+            max_rows: 200,
+            durability: Durability::Normal,
+        }
+    }
+}
+
+// Scheduled copies of the database, so a dead SD card doesn't take the history with it.
+// `directory` is best pointed at another drive.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BackupSettings {
+    pub enabled: bool,
+    pub directory: String,
+    /// Seconds between two backups
+    pub interval: u64,
+    /// Number of backups kept, older ones are deleted
+    pub keep: usize,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        BackupSettings {
+            enabled: false,
+            directory: "backups".to_string(),
+            interval: 86400,
+            keep: 7,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct DryRunSettings {
+    /// Run a shadow controller that records its decisions instead of switching relays
+    pub enabled: bool,
+    /// Configuration file evaluated by the shadow controller, the live settings if unset
+    pub settings_file: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InfluxDbSettings {
     pub host: String,
     pub database: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RelayPinSettings {
     pub humidifier: u8,
     pub dehumidifier: u8,
@@ -91,8 +432,12 @@ pub struct RelayPinSettings {
 
 impl Settings {
     pub fn new() -> Result<Self, AtmosError> {
+        Self::from_file("config")
+    }
+
+    pub fn from_file(name: &str) -> Result<Self, AtmosError> {
         let s = Config::builder()
-            .add_source(File::with_name("config"))
+            .add_source(File::with_name(name))
             .add_source(config::Environment::with_prefix("ATMOS"))
             .build()?;
 
@@ -101,9 +446,41 @@ impl Settings {
         Ok(settings)
     }
 
+    pub fn from_toml(toml: &str) -> Result<Self, AtmosError> {
+        let s = Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()?;
+
+        let settings: Settings = s.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
     fn validate(&self) -> Result<(), AtmosError> {
         self.temperature.validate()?;
         self.humidity.validate()?;
+        self.vpd.validate()?;
+        // Each of these drives a `tokio::time::interval`, which panics on zero
+        for (name, seconds) in [
+            ("polling_interval.duration", self.polling_interval.duration),
+            (
+                "write_buffer.flush_interval",
+                self.write_buffer.flush_interval,
+            ),
+            (
+                "controller_state.save_interval",
+                self.controller_state.save_interval,
+            ),
+            ("backup.interval", self.backup.interval),
+            ("retention.interval", self.retention.interval),
+            ("risk.evaluation_interval", self.risk.evaluation_interval),
+        ] {
+            if seconds == 0 {
+                return Err(AtmosError::ConfigError(config::ConfigError::Message(
+                    format!("{} must be at least 1 second", name),
+                )));
+            }
+        }
         Ok(())
     }
 }
@@ -140,6 +517,22 @@ impl HumiditySettings {
     }
 }
 
+impl VpdSettings {
+    fn validate(&self) -> Result<(), AtmosError> {
+        if !(self.low_range().start <= self.low_range().end
+            && self.low_range().end < self.ideal_range().start
+            && self.ideal_range().start <= self.ideal_range().end
+            && self.ideal_range().end < self.high_range().start
+            && self.high_range().start <= self.high_range().end)
+        {
+            return Err(AtmosError::ConfigError(config::ConfigError::Message(
+                "Invalid VPD ranges".into(),
+            )));
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for Settings {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -150,7 +543,7 @@ impl fmt::Display for Settings {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WebserverSettings {
     pub host: String,
     pub port: u16,
@@ -162,17 +555,17 @@ impl fmt::Display for WebserverSettings {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SensorReadCooldownSettings {
     pub duration: u64,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PollingIntervalSettings {
     pub duration: u64,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SqliteSettings {
     pub db_name: String,
 }
@@ -198,3 +591,78 @@ impl fmt::Display for SqliteSettings {
 //        write!(f, "Email: {:?}", self)
 //    }
 //}
+
+// Settings for unit tests, so they don't depend on the config.toml of the machine running them
+#[cfg(test)]
+pub fn test_settings() -> Settings {
+    Settings::from_toml(
+        r#"
+        [temperature]
+        low_range_start = -20.0
+        low_range_end = 10.9
+        high_range_start = 14.1
+        high_range_end = 100.0
+        ideal_range_start = 11.0
+        ideal_range_end = 14.0
+        fridge_cooldown_duration = 300
+
+        [humidity]
+        low_range_start = 0.0
+        low_range_end = 59.9
+        high_range_start = 80.1
+        high_range_end = 100.0
+        ideal_range_start = 60.0
+        ideal_range_end = 80.0
+        humidifier_cooldown_duration = 300
+        humidifier_activation_duration = 1
+        dehumidifier_cooldown_duration = 60
+
+        [ventilation]
+        interval = 1800
+        duration = 60
+
+        [sqlite]
+        db_name = ":memory:"
+
+        [relay_pins]
+        humidifier = 14
+        dehumidifier = 15
+        ventilator_or_heater = 18
+        fridge = 17
+
+        [webserver]
+        host = "127.0.0.1"
+        port = 8080
+
+        [sensor_read_cooldown]
+        duration = 45
+
+        [polling_interval]
+        duration = 60
+        "#,
+    )
+    .expect("Invalid test settings")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zero_intervals_are_rejected() {
+        assert!(test_settings().validate().is_ok());
+
+        let mut settings = test_settings();
+        settings.backup.interval = 0;
+        assert!(settings.validate().is_err());
+
+        let mut settings = test_settings();
+        settings.write_buffer.flush_interval = 0;
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_write_buffer_is_on_without_a_section() {
+        assert!(test_settings().write_buffer.enabled);
+    }
+}
@@ -0,0 +1,335 @@
+use crate::clock::Clock;
+use crate::config::Settings;
+use crate::error::AtmosError;
+use crate::events::{ActuatorEvent, EventLog, EventSource};
+use crate::relay_ctrl::{Actuator, RelayDriver, RelayStatus};
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::{parse_timestamp, SqliteClient};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::time::interval;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActuatorState {
+    pub status: RelayStatus,
+    pub turned_on_at: String,
+    pub turned_off_at: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    pub started_at: String,
+    pub released_at: Option<String>,
+    pub session_id: Option<i64>,
+}
+
+// What the controller needs to pick up where it left off after a restart. Sensor values
+// and the polling count aren't part of it, control waits for fresh readings after a boot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControllerState {
+    pub saved_at: String,
+    pub fridge: ActuatorState,
+    pub humidifier: ActuatorState,
+    pub dehumidifier: ActuatorState,
+    pub ventilator: ActuatorState,
+    pub heater: ActuatorState,
+    /// Set while maintenance mode was on
+    pub maintenance: Option<MaintenanceState>,
+    /// Statuses set through the API that automatic control hadn't changed yet
+    #[serde(default)]
+    pub manual_overrides: BTreeMap<Actuator, RelayStatus>,
+}
+
+fn actuator_state(
+    status: RelayStatus,
+    turned_on_at: OffsetDateTime,
+    turned_off_at: OffsetDateTime,
+) -> ActuatorState {
+    ActuatorState {
+        status,
+        turned_on_at: turned_on_at.to_string(),
+        turned_off_at: turned_off_at.to_string(),
+    }
+}
+
+// Startup switches every relay off, so one that was on when the state was saved counts as
+// turned off at boot and goes through its full cooldown.
+fn restored_datetimes(
+    state: &ActuatorState,
+    now: OffsetDateTime,
+) -> Result<(OffsetDateTime, OffsetDateTime), AtmosError> {
+    let turned_off_at = match state.status {
+        RelayStatus::On => now,
+        RelayStatus::Off => parse_timestamp(&state.turned_off_at)?,
+    };
+    Ok((parse_timestamp(&state.turned_on_at)?, turned_off_at))
+}
+
+impl ControllerState {
+    pub fn capture(sd: &AccessSharedData, now: OffsetDateTime) -> Self {
+        let state = sd.snapshot();
+        ControllerState {
+            saved_at: now.to_string(),
+            fridge: actuator_state(
+                state.fridge_status,
+                state.fridge_turn_on_datetime,
+                state.fridge_turn_off_datetime,
+            ),
+            humidifier: actuator_state(
+                state.humidifier_status,
+                state.humidifier_turn_on_datetime,
+                state.humidifier_turn_off_datetime,
+            ),
+            dehumidifier: actuator_state(
+                state.dehumidifier_status,
+                state.dehumidifier_turn_on_datetime,
+                state.dehumidifier_turn_off_datetime,
+            ),
+            ventilator: actuator_state(
+                state.ventilator_status,
+                state.ventilator_turn_on_datetime,
+                state.ventilator_turn_off_datetime,
+            ),
+            heater: actuator_state(
+                state.heater_status,
+                state.heater_turn_on_datetime,
+                state.heater_turn_off_datetime,
+            ),
+            maintenance: state.maintenance_active.then(|| MaintenanceState {
+                started_at: state.maintenance_start_datetime.to_string(),
+                released_at: state
+                    .maintenance_release_datetime
+                    .map(|released_at| released_at.to_string()),
+                session_id: state.maintenance_session_id,
+            }),
+            manual_overrides: state.manual_overrides,
+        }
+    }
+
+    // Relay statuses are left alone, the relays are all off after initialization and the
+    // manual overrides are switched back on by `apply_manual_overrides`
+    pub fn restore(&self, sd: &AccessSharedData, now: OffsetDateTime) -> Result<(), AtmosError> {
+        let (fridge_on, fridge_off) = restored_datetimes(&self.fridge, now)?;
+        let (humidifier_on, humidifier_off) = restored_datetimes(&self.humidifier, now)?;
+        let (dehumidifier_on, dehumidifier_off) = restored_datetimes(&self.dehumidifier, now)?;
+        let (ventilator_on, ventilator_off) = restored_datetimes(&self.ventilator, now)?;
+        let (heater_on, heater_off) = restored_datetimes(&self.heater, now)?;
+        let maintenance = match &self.maintenance {
+            Some(maintenance) => Some((
+                parse_timestamp(&maintenance.started_at)?,
+                maintenance
+                    .released_at
+                    .as_deref()
+                    .map(parse_timestamp)
+                    .transpose()?,
+                maintenance.session_id,
+            )),
+            None => None,
+        };
+
+        // Parsed up front, a bad timestamp leaves the shared data untouched
+        sd.update(|state| {
+            state.fridge_turn_on_datetime = fridge_on;
+            state.fridge_turn_off_datetime = fridge_off;
+            state.humidifier_turn_on_datetime = humidifier_on;
+            state.humidifier_turn_off_datetime = humidifier_off;
+            state.dehumidifier_turn_on_datetime = dehumidifier_on;
+            state.dehumidifier_turn_off_datetime = dehumidifier_off;
+            state.ventilator_turn_on_datetime = ventilator_on;
+            state.ventilator_turn_off_datetime = ventilator_off;
+            state.heater_turn_on_datetime = heater_on;
+            state.heater_turn_off_datetime = heater_off;
+            if let Some((started_at, released_at, session_id)) = maintenance {
+                state.maintenance_active = true;
+                state.maintenance_start_datetime = started_at;
+                state.maintenance_release_datetime = released_at;
+                state.maintenance_session_id = session_id;
+            }
+            state.manual_overrides = self.manual_overrides.clone();
+        });
+        Ok(())
+    }
+}
+
+fn cooldown(settings: &Settings, actuator: Actuator) -> Duration {
+    Duration::from_secs(match actuator {
+        Actuator::Fridge => settings.temperature.fridge_cooldown_duration,
+        Actuator::Humidifier => settings.humidity.humidifier_cooldown_duration,
+        Actuator::Dehumidifier => settings.humidity.dehumidifier_cooldown_duration,
+        Actuator::Ventilator => 0,
+    })
+}
+
+// Switches the actuators turned on through the API before the restart back on, once the
+// relays are initialized. One still in its cooldown stays off and loses its override.
+pub fn apply_manual_overrides(
+    settings: &Settings,
+    sd: &AccessSharedData,
+    driver: &dyn RelayDriver,
+    events: &EventLog,
+    now: OffsetDateTime,
+) -> Result<(), AtmosError> {
+    for (actuator, status) in sd.snapshot().manual_overrides {
+        if status == RelayStatus::Off {
+            continue;
+        }
+        if now - sd.actuator_turn_off_datetime(actuator) < cooldown(settings, actuator) {
+            warn!(
+                "Not restoring the manual override of the {}, it is cooling down",
+                actuator
+            );
+            sd.update(|state| {
+                state.manual_overrides.remove(&actuator);
+            });
+            continue;
+        }
+        driver.change_relay_status(actuator, actuator.pin(&settings.relay_pins), status)?;
+        events.record(ActuatorEvent::new(
+            sd,
+            now,
+            actuator,
+            RelayStatus::Off,
+            status,
+            EventSource::Manual,
+            "manual override restored after a restart",
+        ));
+        sd.update(|state| state.record_switch(actuator, status, now));
+    }
+    Ok(())
+}
+
+// Saves the controller state every `save_interval` seconds and once more on shutdown
+pub async fn monitor_controller_state(
+    sd: AccessSharedData,
+    settings: Settings,
+    sqlite_client: Arc<SqliteClient>,
+    clock: Arc<dyn Clock>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    let controller_state = settings.controller_state;
+    if !controller_state.enabled {
+        info!("Controller state persistence disabled");
+        let _ = shutdown_rx.recv().await;
+        return Ok(());
+    }
+
+    let mut interval = interval(std::time::Duration::from_secs(
+        controller_state.save_interval,
+    ));
+    loop {
+        let shutdown = tokio::select! {
+            _ = interval.tick() => false,
+            _ = shutdown_rx.recv() => true,
+        };
+        let state = ControllerState::capture(&sd, clock.now());
+        if let Err(e) = sqlite_client
+            .call(move |db| db.save_controller_state(&state))
+            .await
+        {
+            error!("Failed to save the controller state: {}", e);
+        }
+        if shutdown {
+            info!("Received shutdown signal");
+            break Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_settings;
+    use crate::initialization::initialize_shared_data;
+    use crate::mock_relay_ctrl::MockRelayDriver;
+    use time::Duration;
+
+    fn shared_data() -> AccessSharedData {
+        AccessSharedData::new(initialize_shared_data())
+    }
+
+    #[test]
+    fn test_state_survives_a_restart() {
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        let saved_at = OffsetDateTime::UNIX_EPOCH + Duration::days(10);
+
+        let before = shared_data();
+        before.update(|state| {
+            state.polling_iterations = 7;
+            state
+                .manual_overrides
+                .insert(Actuator::Ventilator, RelayStatus::On);
+            state.fridge_status = RelayStatus::On;
+            state.fridge_turn_on_datetime = saved_at - Duration::minutes(5);
+            state.humidifier_turn_off_datetime = saved_at - Duration::minutes(2);
+            state.maintenance_active = true;
+            state.maintenance_start_datetime = saved_at - Duration::minutes(1);
+            state.maintenance_session_id = Some(3);
+        });
+        sqlite_client
+            .save_controller_state(&ControllerState::capture(&before, saved_at))
+            .unwrap();
+
+        let after = shared_data();
+        let boot = saved_at + Duration::minutes(1);
+        sqlite_client
+            .read_controller_state()
+            .unwrap()
+            .unwrap()
+            .restore(&after, boot)
+            .unwrap();
+
+        // Control waits for readings taken since the boot
+        assert_eq!(after.polling_iterations(), 0);
+        assert_eq!(
+            after.snapshot().manual_overrides,
+            BTreeMap::from([(Actuator::Ventilator, RelayStatus::On)])
+        );
+        // The fridge was running, its cooldown starts at boot
+        assert_eq!(after.fridge_status(), RelayStatus::Off);
+        assert_eq!(after.fridge_turn_off_datetime(), boot);
+        assert_eq!(
+            after.humidifier_turn_off_datetime(),
+            saved_at - Duration::minutes(2)
+        );
+        assert!(after.maintenance_active());
+        assert_eq!(after.maintenance_session_id(), Some(3));
+        assert_eq!(after.maintenance_release_datetime(), None);
+    }
+
+    #[test]
+    fn test_manual_overrides_are_switched_back_on() {
+        let settings = test_settings();
+        let pins = &settings.relay_pins;
+        let driver = MockRelayDriver::default();
+        let boot = OffsetDateTime::UNIX_EPOCH + Duration::days(10);
+        let sd = shared_data();
+        sd.update(|state| {
+            state.manual_overrides = BTreeMap::from([
+                (Actuator::Fridge, RelayStatus::On),
+                (Actuator::Humidifier, RelayStatus::Off),
+                (Actuator::Ventilator, RelayStatus::On),
+            ]);
+            // Switched off by the shutdown a minute ago, still cooling down
+            state.fridge_turn_off_datetime = boot - Duration::minutes(1);
+        });
+
+        apply_manual_overrides(&settings, &sd, &driver, &EventLog::disabled(), boot).unwrap();
+
+        assert_eq!(driver.status(pins.ventilator_or_heater), RelayStatus::On);
+        assert_eq!(sd.ventilator_status(), RelayStatus::On);
+        assert_eq!(sd.ventilator_turn_on_datetime(), boot);
+        assert_eq!(driver.status(pins.fridge), RelayStatus::Off);
+        assert_eq!(sd.fridge_status(), RelayStatus::Off);
+        assert_eq!(
+            sd.snapshot().manual_overrides,
+            BTreeMap::from([
+                (Actuator::Humidifier, RelayStatus::Off),
+                (Actuator::Ventilator, RelayStatus::On),
+            ])
+        );
+    }
+}
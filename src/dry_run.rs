@@ -0,0 +1,210 @@
+use crate::clock::{Clock, SystemClock};
+use crate::config::Settings;
+use crate::error::AtmosError;
+use crate::events::EventLog;
+use crate::initialization::initialize_shared_data;
+use crate::monitor_atmosphere::run_control_handlers;
+use crate::relay_ctrl::{Actuator, RelayDriver, RelayStatus};
+use crate::shared_data::AccessSharedData;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+use tokio::time::{interval, Duration};
+
+const MAX_RECORDED_ACTIONS: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunAction {
+    pub timestamp: String,
+    pub actuator: Actuator,
+    pub pin: u8,
+    pub status: RelayStatus,
+    /// What the live controller had the same relay set to at that moment
+    pub live_status: RelayStatus,
+}
+
+// Relay driver that never touches GPIO. Every would-be relay change is logged and kept
+// in a bounded in-memory list, next to the live relay status it can be compared with.
+pub struct RecordingRelayDriver {
+    live_sd: AccessSharedData,
+    actions: Mutex<VecDeque<DryRunAction>>,
+}
+
+impl RecordingRelayDriver {
+    pub fn new(live_sd: AccessSharedData) -> Self {
+        RecordingRelayDriver {
+            live_sd,
+            actions: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn actions(&self) -> Vec<DryRunAction> {
+        let actions = self.actions.lock().unwrap();
+        actions.iter().cloned().collect()
+    }
+}
+
+impl RelayDriver for RecordingRelayDriver {
+    fn change_relay_status(
+        &self,
+        actuator: Actuator,
+        pin: u8,
+        status: RelayStatus,
+    ) -> Result<(), AtmosError> {
+        let live_status = self.live_sd.actuator_status(actuator);
+        info!(
+            "dry_run() -> would turn {} (pin {}) {:?}, live controller has it {:?}",
+            actuator, pin, status, live_status
+        );
+
+        let mut actions = self.actions.lock().unwrap();
+        if actions.len() == MAX_RECORDED_ACTIONS {
+            actions.pop_front();
+        }
+        actions.push_back(DryRunAction {
+            timestamp: OffsetDateTime::now_utc().to_string(),
+            actuator,
+            pin,
+            status,
+            live_status,
+        });
+        Ok(())
+    }
+}
+
+fn load_dry_run_settings(settings: &Settings) -> Result<Settings, AtmosError> {
+    match &settings.dry_run.settings_file {
+        Some(file) => Settings::from_file(file),
+        None => Ok(settings.clone()),
+    }
+}
+
+// Mirrors the live sensor readings into a private copy of the shared data and runs the
+// control handlers on it with the dry-run settings. The live relays are never touched.
+pub async fn run_shadow_controller(
+    live_sd: AccessSharedData,
+    settings: Settings,
+    driver: Arc<RecordingRelayDriver>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    if !settings.dry_run.enabled {
+        info!("Dry-run mode disabled");
+        let _ = shutdown_rx.recv().await;
+        return Ok(());
+    }
+
+    let settings = load_dry_run_settings(&settings)?;
+    let shadow_sd = AccessSharedData::new(initialize_shared_data());
+    let driver: Arc<dyn RelayDriver> = driver;
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let events = EventLog::disabled();
+    let mut interval = interval(Duration::from_secs(settings.polling_interval.duration));
+    info!("Dry-run shadow controller started");
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                mirror_readings(&live_sd, &shadow_sd);
+
+                if live_sd.maintenance_active() {
+                    debug!("dry_run() -> maintenance mode active, shadow control paused");
+                } else if live_sd.polling_iterations() > 4 {
+                    let now = clock.now();
+                    for e in run_control_handlers(&shadow_sd, &settings, &driver, &clock, &events, now).await {
+                        warn!("dry_run() -> shadow controller error: {}", e);
+                    }
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Received shutdown signal");
+                break Ok(());
+            }
+        }
+    }
+}
+
+fn mirror_readings(live_sd: &AccessSharedData, shadow_sd: &AccessSharedData) {
+    let live = live_sd.snapshot();
+    shadow_sd.update(|shadow| {
+        shadow.temp_1 = live.temp_1;
+        shadow.humidity_1 = live.humidity_1;
+        shadow.temp_2 = live.temp_2;
+        shadow.humidity_2 = live.humidity_2;
+        shadow.average_temp = live.average_temp;
+        shadow.average_humidity = live.average_humidity;
+        shadow.derived_metrics_1 = live.derived_metrics_1;
+        shadow.derived_metrics_2 = live.derived_metrics_2;
+        shadow.average_derived_metrics = live.average_derived_metrics;
+        shadow.last_reading_time = live.last_reading_time;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ReplayClock;
+    use crate::config::test_settings;
+
+    #[test]
+    fn test_recording_driver_keeps_the_latest_actions() {
+        let live_sd = AccessSharedData::new(initialize_shared_data());
+        live_sd.update(|state| state.fridge_status = RelayStatus::On);
+        let driver = RecordingRelayDriver::new(live_sd);
+
+        for _ in 0..MAX_RECORDED_ACTIONS {
+            driver
+                .change_relay_status(Actuator::Humidifier, 14, RelayStatus::On)
+                .unwrap();
+        }
+        driver
+            .change_relay_status(Actuator::Fridge, 17, RelayStatus::Off)
+            .unwrap();
+
+        let actions = driver.actions();
+        assert_eq!(actions.len(), MAX_RECORDED_ACTIONS);
+        let last = actions.last().unwrap();
+        assert_eq!(last.actuator, Actuator::Fridge);
+        assert_eq!(last.status, RelayStatus::Off);
+        assert_eq!(last.live_status, RelayStatus::On);
+    }
+
+    #[tokio::test]
+    async fn test_shadow_decisions_are_recorded_not_applied() {
+        let settings = test_settings();
+        let live_sd = AccessSharedData::new(initialize_shared_data());
+        live_sd.update(|state| {
+            state.average_temp = 20.0;
+            state.average_humidity = 70.0;
+        });
+        let shadow_sd = AccessSharedData::new(initialize_shared_data());
+        mirror_readings(&live_sd, &shadow_sd);
+
+        // The GPIO driver would fail off a Raspberry Pi, the recording one never touches it
+        let recording = Arc::new(RecordingRelayDriver::new(live_sd.clone()));
+        let driver: Arc<dyn RelayDriver> = recording.clone();
+        let now = OffsetDateTime::now_utc();
+        let clock: Arc<dyn Clock> = Arc::new(ReplayClock::new(now));
+        let errors = run_control_handlers(
+            &shadow_sd,
+            &settings,
+            &driver,
+            &clock,
+            &EventLog::disabled(),
+            now,
+        )
+        .await;
+        assert!(errors.is_empty());
+
+        let fridge = recording
+            .actions()
+            .into_iter()
+            .find(|action| action.actuator == Actuator::Fridge)
+            .unwrap();
+        assert_eq!(fridge.status, RelayStatus::On);
+        assert_eq!(fridge.live_status, RelayStatus::Off);
+        assert_eq!(shadow_sd.fridge_status(), RelayStatus::On);
+        assert_eq!(live_sd.fridge_status(), RelayStatus::Off);
+    }
+}
@@ -1,3 +1,6 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
 use std::fmt;
 
 #[derive(Debug)]
@@ -18,6 +21,10 @@ pub enum AtmosError {
     RelayError(String),
     SensorError(String),
     TaskJoinError(String),
+    InvalidInput(String),
+    MigrationError(String),
+    NotFound(String),
+    Conflict(String),
 }
 
 impl fmt::Display for AtmosError {
@@ -39,12 +46,90 @@ impl fmt::Display for AtmosError {
             AtmosError::RelayError(e) => write!(f, "Relay error: {}", e),
             AtmosError::SensorError(e) => write!(f, "Sensor error: {}", e),
             AtmosError::TaskJoinError(e) => write!(f, "Task join error: {}", e),
+            AtmosError::InvalidInput(e) => write!(f, "Invalid input: {}", e),
+            AtmosError::MigrationError(e) => write!(f, "Database migration error: {}", e),
+            AtmosError::NotFound(e) => write!(f, "Not found: {}", e),
+            AtmosError::Conflict(e) => write!(f, "Conflict: {}", e),
         }
     }
 }
 
 impl std::error::Error for AtmosError {}
 
+impl AtmosError {
+    // Stable identifier for API clients to match on, the message is for humans
+    pub fn code(&self) -> &'static str {
+        match self {
+            AtmosError::ConfigError(_) => "config_error",
+            AtmosError::IoError(_) => "io_error",
+            AtmosError::JsonError(_) | AtmosError::JsonParseError(_) => "invalid_json",
+            AtmosError::GpioError(_)
+            | AtmosError::RelayControlError(_)
+            | AtmosError::FridgeError(_)
+            | AtmosError::DehumidifierError(_)
+            | AtmosError::HumidifierError(_)
+            | AtmosError::VentilatorError(_)
+            | AtmosError::RelayError(_) => "relay_error",
+            AtmosError::SensorReadError(_) | AtmosError::SensorError(_) => "sensor_error",
+            AtmosError::SqliteError(_) if self.is_constraint_violation() => "conflict",
+            AtmosError::SqliteError(_) | AtmosError::MigrationError(_) => "database_error",
+            AtmosError::HttpError(_) => "http_error",
+            AtmosError::TaskJoinError(_) => "internal_error",
+            AtmosError::InvalidInput(_) => "invalid_input",
+            AtmosError::NotFound(_) => "not_found",
+            AtmosError::Conflict(_) => "conflict",
+        }
+    }
+
+    // A UNIQUE or foreign key constraint rejected the write, the client's fault
+    pub fn is_constraint_violation(&self) -> bool {
+        matches!(
+            self,
+            AtmosError::SqliteError(rusqlite::Error::SqliteFailure(error, _))
+                if error.code == rusqlite::ErrorCode::ConstraintViolation
+        )
+    }
+}
+
+// Errors returned from the v2 API become `{"error": {"code": ..., "message": ...}}` with a
+// matching status.
+impl ResponseError for AtmosError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AtmosError::JsonError(_)
+            | AtmosError::JsonParseError(_)
+            | AtmosError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            AtmosError::NotFound(_) => StatusCode::NOT_FOUND,
+            AtmosError::Conflict(_) => StatusCode::CONFLICT,
+            AtmosError::SqliteError(_) if self.is_constraint_violation() => StatusCode::CONFLICT,
+            AtmosError::GpioError(_)
+            | AtmosError::RelayControlError(_)
+            | AtmosError::FridgeError(_)
+            | AtmosError::DehumidifierError(_)
+            | AtmosError::HumidifierError(_)
+            | AtmosError::VentilatorError(_)
+            | AtmosError::RelayError(_)
+            | AtmosError::SensorReadError(_)
+            | AtmosError::SensorError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AtmosError::ConfigError(_)
+            | AtmosError::IoError(_)
+            | AtmosError::SqliteError(_)
+            | AtmosError::MigrationError(_)
+            | AtmosError::HttpError(_)
+            | AtmosError::TaskJoinError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "error": {
+                "code": self.code(),
+                "message": self.to_string(),
+            }
+        }))
+    }
+}
+
 impl From<config::ConfigError> for AtmosError {
     fn from(err: config::ConfigError) -> Self {
         AtmosError::ConfigError(err)
@@ -80,3 +165,70 @@ impl From<tokio::task::JoinError> for AtmosError {
         AtmosError::TaskJoinError(err.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constraint_violation() -> AtmosError {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (name TEXT UNIQUE); INSERT INTO t VALUES ('a');")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES ('a')", [])
+            .unwrap_err()
+            .into()
+    }
+
+    #[test]
+    fn test_status_codes() {
+        let cases = [
+            (
+                AtmosError::InvalidInput("x".into()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                AtmosError::JsonParseError("x".into()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (AtmosError::NotFound("x".into()), StatusCode::NOT_FOUND),
+            (AtmosError::Conflict("x".into()), StatusCode::CONFLICT),
+            (constraint_violation(), StatusCode::CONFLICT),
+            (
+                AtmosError::SqliteError(rusqlite::Error::QueryReturnedNoRows),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                AtmosError::RelayError("x".into()),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                AtmosError::SensorError("x".into()),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                AtmosError::TaskJoinError("x".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status_code(), status, "{:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_body() {
+        let response = AtmosError::NotFound("Program 7".to_string()).error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "error": { "code": "not_found", "message": "Not found: Program 7" } })
+        );
+
+        let conflict = constraint_violation();
+        assert_eq!(conflict.code(), "conflict");
+    }
+}
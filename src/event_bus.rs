@@ -0,0 +1,79 @@
+use crate::events::ActuatorEvent;
+use log::debug;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+// Events a subscriber can fall behind by before it starts missing them
+const EVENT_BUS_CAPACITY: usize = 256;
+
+// What happened in the controller, for integrations (MQTT, webhooks, the live stream) to
+// react to without hooking into the control loop.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BusEvent {
+    /// Both sensors were read
+    ReadingReceived {
+        timestamp: String,
+        temperature_1: f32,
+        humidity_1: f32,
+        temperature_2: f32,
+        humidity_2: f32,
+    },
+    /// A relay was switched, by the control loop, the API or on startup and shutdown
+    ActuatorChanged(ActuatorEvent),
+    /// A condition that needs the operator's attention
+    AlertRaised {
+        timestamp: String,
+        alert_type: String,
+        details: String,
+        /// Raised during maintenance, when the chamber is expected to be out of range
+        suppressed: bool,
+    },
+    /// The active batch moved on to the next stage of its recipe
+    StageAdvanced {
+        timestamp: String,
+        batch_id: i64,
+        batch_name: String,
+        stage: usize,
+        stage_name: String,
+    },
+}
+
+impl BusEvent {
+    // Same as the serialized `type`
+    pub fn name(&self) -> &'static str {
+        match self {
+            BusEvent::ReadingReceived { .. } => "reading_received",
+            BusEvent::ActuatorChanged(_) => "actuator_changed",
+            BusEvent::AlertRaised { .. } => "alert_raised",
+            BusEvent::StageAdvanced { .. } => "stage_advanced",
+        }
+    }
+}
+
+// Typed broadcast channel. Publishing never blocks and nothing is kept when there are no
+// subscribers; a subscriber that falls more than `EVENT_BUS_CAPACITY` events behind gets
+// `RecvError::Lagged` and continues from the oldest event still buffered.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<BusEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        EventBus { tx }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: BusEvent) {
+        if self.tx.send(event).is_err() {
+            debug!("event_bus() -> no subscribers");
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<BusEvent> {
+        self.tx.subscribe()
+    }
+}
@@ -0,0 +1,270 @@
+use crate::error::AtmosError;
+use crate::event_bus::{BusEvent, EventBus};
+use crate::relay_ctrl::{Actuator, RelayStatus};
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::SqliteClient;
+use crate::write_buffer::BufferedWrite;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+// What switched a relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventSource {
+    /// The control loop
+    Auto,
+    /// The relay control API or maintenance mode
+    Manual,
+    /// Startup found a relay in an unexpected state
+    Failsafe,
+    Shutdown,
+}
+
+impl fmt::Display for EventSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventSource::Auto => write!(f, "auto"),
+            EventSource::Manual => write!(f, "manual"),
+            EventSource::Failsafe => write!(f, "failsafe"),
+            EventSource::Shutdown => write!(f, "shutdown"),
+        }
+    }
+}
+
+impl FromStr for EventSource {
+    type Err = AtmosError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(EventSource::Auto),
+            "manual" => Ok(EventSource::Manual),
+            "failsafe" => Ok(EventSource::Failsafe),
+            "shutdown" => Ok(EventSource::Shutdown),
+            _ => Err(AtmosError::InvalidInput(format!(
+                "Unknown event source {}, expected auto, manual, failsafe or shutdown",
+                s
+            ))),
+        }
+    }
+}
+
+// The readings the decision was based on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EventReadings {
+    pub average_temperature: f32,
+    pub average_humidity: f32,
+    pub vapour_pressure_deficit: f32,
+    pub temperature_1: f32,
+    pub humidity_1: f32,
+    pub temperature_2: f32,
+    pub humidity_2: f32,
+}
+
+impl EventReadings {
+    pub fn from_shared_data(sd: &AccessSharedData) -> Self {
+        let state = sd.snapshot();
+        EventReadings {
+            average_temperature: state.average_temp,
+            average_humidity: state.average_humidity,
+            vapour_pressure_deficit: state.average_derived_metrics.vpd,
+            temperature_1: state.temp_1,
+            humidity_1: state.humidity_1,
+            temperature_2: state.temp_2,
+            humidity_2: state.humidity_2,
+        }
+    }
+}
+
+// One relay transition, answering "why did the fridge turn on at 03:12?"
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActuatorEvent {
+    pub timestamp: String,
+    pub actuator: Actuator,
+    pub old_status: RelayStatus,
+    pub new_status: RelayStatus,
+    pub source: EventSource,
+    pub reason: String,
+    pub readings: EventReadings,
+}
+
+impl ActuatorEvent {
+    pub fn new(
+        sd: &AccessSharedData,
+        timestamp: OffsetDateTime,
+        actuator: Actuator,
+        old_status: RelayStatus,
+        new_status: RelayStatus,
+        source: EventSource,
+        reason: impl Into<String>,
+    ) -> Self {
+        ActuatorEvent {
+            timestamp: timestamp.to_string(),
+            actuator,
+            old_status,
+            new_status,
+            source,
+            reason: reason.into(),
+            readings: EventReadings::from_shared_data(sd),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredActuatorEvent {
+    pub id: i64,
+    #[serde(flatten)]
+    pub event: ActuatorEvent,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EventFilter {
+    pub actuator: Option<Actuator>,
+    pub source: Option<EventSource>,
+    /// RFC 3339 bounds, inclusive
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<usize>,
+}
+
+// Where relay transitions are written. Replay and the dry-run shadow controller use a
+// disabled log so simulated decisions never end up next to real ones.
+#[derive(Clone)]
+pub struct EventLog {
+    sqlite_client: Option<Arc<SqliteClient>>,
+    bus: Option<EventBus>,
+}
+
+impl EventLog {
+    // Records to the database and publishes `ActuatorChanged` on `bus`
+    pub fn new(sqlite_client: Arc<SqliteClient>, bus: EventBus) -> Self {
+        EventLog {
+            sqlite_client: Some(sqlite_client),
+            bus: Some(bus),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        EventLog {
+            sqlite_client: None,
+            bus: None,
+        }
+    }
+
+    // Queued for the next flush of the write buffer. With buffering off the row is written
+    // on the blocking pool, this is called from the control loop and HTTP handlers. A failed
+    // write is logged rather than returned, losing an event must not stop the relay from
+    // being controlled. Re-asserting the current status isn't a transition and is skipped.
+    pub fn record(&self, event: ActuatorEvent) {
+        if event.old_status == event.new_status {
+            return;
+        }
+        debug!(
+            "event() -> {} {} -> {} ({}): {}",
+            event.actuator, event.old_status, event.new_status, event.source, event.reason
+        );
+        if let Some(bus) = &self.bus {
+            bus.publish(BusEvent::ActuatorChanged(event.clone()));
+        }
+        let Some(sqlite_client) = &self.sqlite_client else {
+            return;
+        };
+        let actuator = event.actuator;
+        if let Some(write) = sqlite_client.queue_write(BufferedWrite::ActuatorEvent(event)) {
+            let sqlite_client = sqlite_client.clone();
+            let write_now = move || {
+                if let Err(e) = sqlite_client.write_now(&write) {
+                    error!("Failed to record {} event: {}", actuator, e);
+                }
+            };
+            // Outside a runtime (CLI commands, tests) there are no workers to keep free
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => drop(runtime.spawn_blocking(write_now)),
+                Err(_) => write_now(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::initialize_shared_data;
+
+    #[test]
+    fn test_events_are_recorded_and_filtered() {
+        let sqlite_client = Arc::new(SqliteClient::new(":memory:").unwrap());
+        let bus = EventBus::default();
+        let mut published = bus.subscribe();
+        let events = EventLog::new(sqlite_client.clone(), bus);
+        let sd = AccessSharedData::new(initialize_shared_data());
+        let now = OffsetDateTime::now_utc();
+
+        let event = |actuator, old_status, new_status, source| {
+            ActuatorEvent::new(&sd, now, actuator, old_status, new_status, source, "test")
+        };
+        events.record(event(
+            Actuator::Fridge,
+            RelayStatus::Off,
+            RelayStatus::On,
+            EventSource::Auto,
+        ));
+        events.record(event(
+            Actuator::Fridge,
+            RelayStatus::On,
+            RelayStatus::Off,
+            EventSource::Manual,
+        ));
+        events.record(event(
+            Actuator::Ventilator,
+            RelayStatus::Off,
+            RelayStatus::On,
+            EventSource::Auto,
+        ));
+        // Not a transition
+        events.record(event(
+            Actuator::Humidifier,
+            RelayStatus::Off,
+            RelayStatus::Off,
+            EventSource::Shutdown,
+        ));
+
+        let all = sqlite_client
+            .read_actuator_events(&EventFilter::default(), None, None)
+            .unwrap();
+        assert_eq!(all.len(), 3);
+        // Only the transitions reach subscribers
+        for _ in 0..3 {
+            assert!(matches!(
+                published.try_recv(),
+                Ok(BusEvent::ActuatorChanged(_))
+            ));
+        }
+        assert!(published.try_recv().is_err());
+        assert_eq!(all[0].event.actuator, Actuator::Ventilator);
+        assert_eq!(all[2].event.readings.average_temperature, sd.average_temp());
+
+        let filter = EventFilter {
+            actuator: Some(Actuator::Fridge),
+            source: Some(EventSource::Auto),
+            ..Default::default()
+        };
+        let fridge = sqlite_client
+            .read_actuator_events(&filter, None, None)
+            .unwrap();
+        assert_eq!(fridge.len(), 1);
+        assert_eq!(fridge[0].event.new_status, RelayStatus::On);
+
+        let later = sqlite_client
+            .read_actuator_events(
+                &EventFilter::default(),
+                Some(now + time::Duration::SECOND),
+                None,
+            )
+            .unwrap();
+        assert!(later.is_empty());
+    }
+}
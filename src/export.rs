@@ -0,0 +1,312 @@
+use crate::error::AtmosError;
+use crate::sqlite_client::{parse_timestamp, Rollup, SqliteClient};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+use time::OffsetDateTime;
+
+// Rows read per database query, the lock is released between pages so the controller
+// can keep writing during a long export.
+pub const EXPORT_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportDataset {
+    /// Atmosphere readings with the per-sensor values and the heater status
+    Readings,
+    /// Actuator events
+    Events,
+    /// Weigh-ins of every batch, with the batch they belong to
+    Batches,
+}
+
+impl FromStr for ExportDataset {
+    type Err = AtmosError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "readings" => Ok(ExportDataset::Readings),
+            "events" => Ok(ExportDataset::Events),
+            "batches" => Ok(ExportDataset::Batches),
+            _ => Err(AtmosError::InvalidInput(format!(
+                "Unknown export dataset {}, expected readings, events or batches",
+                s
+            ))),
+        }
+    }
+}
+
+impl ExportDataset {
+    // Column order of the CSV export. The readings columns match what `atmos replay --csv`
+    // reads.
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            ExportDataset::Readings => &[
+                "timestamp",
+                "average_temperature",
+                "average_humidity",
+                "fridge_status",
+                "dehumidifier_status",
+                "humidifier_status",
+                "ventilator_status",
+                "heater_status",
+                "dew_point",
+                "absolute_humidity",
+                "vapour_pressure_deficit",
+                "atmospheric_quality_index",
+                "hanging_weight",
+                "temperature_1",
+                "humidity_1",
+                "temperature_2",
+                "humidity_2",
+            ],
+            ExportDataset::Events => &[
+                "timestamp",
+                "actuator",
+                "old_status",
+                "new_status",
+                "source",
+                "reason",
+                "readings",
+            ],
+            ExportDataset::Batches => &[
+                "batch_id",
+                "batch_name",
+                "start_weight",
+                "target_loss_percent",
+                "started_at",
+                "finished_at",
+                "timestamp",
+                "weight",
+                "source",
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl FromStr for ExportFormat {
+    type Err = AtmosError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            _ => Err(AtmosError::InvalidInput(format!(
+                "Unknown export format {}, expected csv or ndjson",
+                s
+            ))),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+fn csv_field(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+fn format_row(dataset: ExportDataset, format: ExportFormat, row: Vec<Value>) -> String {
+    match format {
+        ExportFormat::Csv => {
+            let fields: Vec<String> = row.iter().map(csv_field).collect();
+            fields.join(",") + "\n"
+        }
+        ExportFormat::Ndjson => {
+            let object: Map<String, Value> = dataset
+                .columns()
+                .iter()
+                .map(|column| column.to_string())
+                .zip(row)
+                .collect();
+            Value::Object(object).to_string() + "\n"
+        }
+    }
+}
+
+// Writes the pages `read_page` returns until it runs out, `read_page` gets the key of the
+// last row read so far.
+fn export_pages(
+    dataset: ExportDataset,
+    format: ExportFormat,
+    mut after: i64,
+    mut read_page: impl FnMut(i64) -> Result<(Vec<Vec<Value>>, Option<i64>), AtmosError>,
+    write: &mut impl FnMut(String) -> Result<(), AtmosError>,
+) -> Result<(), AtmosError> {
+    loop {
+        let (rows, last) = read_page(after)?;
+        let Some(last) = last else {
+            return Ok(());
+        };
+        after = last;
+
+        let mut chunk = String::new();
+        for row in rows {
+            chunk.push_str(&format_row(dataset, format, row));
+        }
+        write(chunk)?;
+    }
+}
+
+// Streams the rows of `dataset` recorded between `from` and `to` to `write`, one chunk per
+// page so memory use doesn't grow with the range. Readings already rolled up by the
+// retention are exported first, one row per day and then per hour.
+pub fn export(
+    sqlite_client: &SqliteClient,
+    dataset: ExportDataset,
+    format: ExportFormat,
+    from: OffsetDateTime,
+    to: OffsetDateTime,
+    mut write: impl FnMut(String) -> Result<(), AtmosError>,
+) -> Result<(), AtmosError> {
+    if format == ExportFormat::Csv {
+        write(dataset.columns().join(",") + "\n")?;
+    }
+
+    if dataset == ExportDataset::Readings {
+        for rollup in [Rollup::Daily, Rollup::Hourly] {
+            export_pages(
+                dataset,
+                format,
+                i64::MIN,
+                |after_ms| {
+                    sqlite_client.read_rollup_export_page(
+                        rollup,
+                        from,
+                        to,
+                        after_ms,
+                        EXPORT_PAGE_SIZE,
+                    )
+                },
+                &mut write,
+            )?;
+        }
+    }
+    export_pages(
+        dataset,
+        format,
+        0,
+        |after_id| sqlite_client.read_export_page(dataset, from, to, after_id, EXPORT_PAGE_SIZE),
+        &mut write,
+    )
+}
+
+// `atmos export <readings|events|batches> [--from <time>] [--to <time>]
+// [--format csv|ndjson] [--output <file>] [--config <name>] [--db <file>]`
+pub fn export_command(dataset: &str, options: &HashMap<String, String>) -> Result<(), AtmosError> {
+    let dataset: ExportDataset = dataset.parse()?;
+    let format: ExportFormat = options
+        .get("format")
+        .map_or("csv", String::as_str)
+        .parse()?;
+    let from = match options.get("from") {
+        Some(from) => parse_timestamp(from)?,
+        None => OffsetDateTime::UNIX_EPOCH,
+    };
+    let to = match options.get("to") {
+        Some(to) => parse_timestamp(to)?,
+        None => OffsetDateTime::now_utc(),
+    };
+    let db_name = match options.get("db") {
+        Some(db_name) => db_name.clone(),
+        None => {
+            crate::config::Settings::from_file(
+                options.get("config").map_or("config", String::as_str),
+            )?
+            .sqlite
+            .db_name
+        }
+    };
+    let sqlite_client = SqliteClient::new(&db_name)?;
+
+    let mut output: Box<dyn Write> = match options.get("output") {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    export(&sqlite_client, dataset, format, from, to, |chunk| {
+        Ok(output.write_all(chunk.as_bytes())?)
+    })?;
+    Ok(output.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field(&Value::Null), "");
+        assert_eq!(csv_field(&Value::from(12.5)), "12.5");
+        assert_eq!(
+            csv_field(&Value::from("temperature 14.6, \"high\"")),
+            "\"temperature 14.6, \"\"high\"\"\""
+        );
+    }
+
+    #[test]
+    fn test_batches_export_across_pages() {
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        let start = OffsetDateTime::UNIX_EPOCH + time::Duration::days(10);
+        let batch_id = sqlite_client
+            .create_batch("coppa", 1500.0, 33.0, None, None, start)
+            .unwrap();
+        // A full page of weigh-ins before the range, then three inside it
+        for i in 0..EXPORT_PAGE_SIZE as i64 + 3 {
+            sqlite_client
+                .insert_weigh_in(batch_id, start + time::Duration::hours(i), 1500.0, "manual")
+                .unwrap();
+        }
+        let from = start + time::Duration::hours(EXPORT_PAGE_SIZE as i64);
+
+        let mut output = String::new();
+        export(
+            &sqlite_client,
+            ExportDataset::Batches,
+            ExportFormat::Ndjson,
+            from,
+            from + time::Duration::DAY,
+            |chunk| {
+                output.push_str(&chunk);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let rows: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0]["batch_name"], "coppa");
+        assert_eq!(rows[0]["weight"], 1500.0);
+        assert_eq!(rows[0]["finished_at"], Value::Null);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::error::AtmosError;
+use serde::Serialize;
+use time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MetricSummary {
+    pub min: f32,
+    pub max: f32,
+    pub avg: f32,
+}
+
+// Share of the samples in a bucket during which each actuator was on, from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DutyCycle {
+    pub fridge: f32,
+    pub humidifier: f32,
+    pub dehumidifier: f32,
+    pub ventilator: f32,
+    /// Unknown for rows recorded before actuator states were stored
+    pub heater: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryBucket {
+    pub start: String,
+    pub samples: u32,
+    pub temperature: MetricSummary,
+    pub humidity: MetricSummary,
+    pub vapour_pressure_deficit: Option<MetricSummary>,
+    pub atmospheric_quality_index: Option<MetricSummary>,
+    pub duty_cycle: DutyCycle,
+}
+
+// Parses bucket sizes like "30s", "15m", "1h" or "1d".
+pub fn parse_bucket(bucket: &str) -> Result<Duration, AtmosError> {
+    let invalid = || {
+        AtmosError::InvalidInput(format!(
+            "Invalid bucket {}, expected a number followed by s, m, h or d",
+            bucket
+        ))
+    };
+    let (split, _) = bucket.char_indices().last().ok_or_else(invalid)?;
+    let (amount, unit) = bucket.split_at(split);
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    if amount <= 0 {
+        return Err(invalid());
+    }
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3_600,
+        "d" => 86_400,
+        _ => return Err(invalid()),
+    };
+    let seconds = amount.checked_mul(unit_seconds).ok_or_else(invalid)?;
+    Ok(Duration::seconds(seconds))
+}
+
+// Largest-Triangle-Three-Buckets: picks `threshold` of the points that keep the visual
+// shape of the series, always including the first and last one. Returns their indices.
+pub fn lttb(points: &[(f64, f64)], threshold: usize) -> Vec<usize> {
+    if threshold >= points.len() || threshold < 3 {
+        return (0..points.len()).collect();
+    }
+
+    let mut selected = Vec::with_capacity(threshold);
+    selected.push(0);
+    // The points between the first and last are split into threshold - 2 buckets
+    let bucket_size = (points.len() - 2) as f64 / (threshold - 2) as f64;
+    let mut previous = 0;
+
+    for bucket in 0..threshold - 2 {
+        let start = (bucket as f64 * bucket_size) as usize + 1;
+        let end = ((bucket + 1) as f64 * bucket_size) as usize + 1;
+
+        // Average of the next bucket, the last point for the final bucket
+        let next_start = end;
+        let next_end = (((bucket + 2) as f64 * bucket_size) as usize + 1).min(points.len());
+        let next = &points[next_start..next_end.max(next_start + 1)];
+        let (avg_x, avg_y) = next
+            .iter()
+            .fold((0.0, 0.0), |(x, y), point| (x + point.0, y + point.1));
+        let (avg_x, avg_y) = (avg_x / next.len() as f64, avg_y / next.len() as f64);
+
+        let (ax, ay) = points[previous];
+        let mut best = start;
+        let mut best_area = -1.0;
+        for (index, &(x, y)) in points.iter().enumerate().take(end).skip(start) {
+            let area = ((ax - avg_x) * (y - ay) - (ax - x) * (avg_y - ay)).abs();
+            if area > best_area {
+                best_area = area;
+                best = index;
+            }
+        }
+        selected.push(best);
+        previous = best;
+    }
+
+    selected.push(points.len() - 1);
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bucket() {
+        assert_eq!(parse_bucket("15m").unwrap(), Duration::minutes(15));
+        assert_eq!(parse_bucket("1d").unwrap(), Duration::days(1));
+        for invalid in ["", "m", "0h", "-5m", "15", "15w", "15µ", "999999999999999d"] {
+            assert!(parse_bucket(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_lttb_keeps_peaks_and_ends() {
+        let mut points: Vec<(f64, f64)> = (0..1000).map(|i| (i as f64, 12.0)).collect();
+        points[400].1 = 20.0;
+        points[700].1 = 4.0;
+
+        let selected = lttb(&points, 50);
+        assert_eq!(selected.len(), 50);
+        assert_eq!(selected[0], 0);
+        assert_eq!(selected[49], 999);
+        assert!(selected.contains(&400));
+        assert!(selected.contains(&700));
+        assert!(selected.windows(2).all(|pair| pair[0] < pair[1]));
+
+        assert_eq!(lttb(&points[..10], 50).len(), 10);
+    }
+}
@@ -1,12 +1,15 @@
 use crate::config::Settings;
-use crate::relay_ctrl::{self, RelayStatus};
-use crate::shared_data::SharedData;
+use crate::error::AtmosError;
+use crate::events::{ActuatorEvent, EventLog, EventSource};
+use crate::relay_ctrl::{self, Actuator, RelayDriver, RelayStatus};
+use crate::shared_data::{AccessSharedData, AtmosphereState};
+use crate::sqlite_client::SqliteClient;
 use log::{error, info};
 use time::macros::offset;
 use time::OffsetDateTime;
 
-pub fn initialize_shared_data() -> SharedData {
-    SharedData::new(
+pub fn initialize_shared_data() -> AtmosphereState {
+    AtmosphereState::new(
         0,
         13.0,
         80.0,
@@ -34,14 +37,35 @@ pub fn initialize_shared_data() -> SharedData {
     )
 }
 
-pub async fn initialize_relay_pins(settings: &Settings) -> std::io::Result<()> {
+// Restores the state saved by the previous run, see `ControllerState`. Returns whether
+// there was one.
+pub fn restore_controller_state(
+    settings: &Settings,
+    sd: &AccessSharedData,
+    sqlite_client: &SqliteClient,
+) -> Result<bool, AtmosError> {
+    if !settings.controller_state.enabled {
+        return Ok(false);
+    }
+    match sqlite_client.read_controller_state()? {
+        Some(state) => {
+            state.restore(sd, OffsetDateTime::now_utc())?;
+            info!("Restored the controller state saved at {}", state.saved_at);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Relays left on by a crash are switched off, which is recorded as a failsafe event.
+pub async fn initialize_relay_pins(
+    settings: &Settings,
+    sd: &AccessSharedData,
+    events: &EventLog,
+) -> std::io::Result<()> {
     info!("Starting relay pin initialization");
-    for pin in &[
-        settings.relay_pins.humidifier,
-        settings.relay_pins.dehumidifier,
-        settings.relay_pins.ventilator_or_heater,
-        settings.relay_pins.fridge,
-    ] {
+    for actuator in Actuator::ALL {
+        let pin = &actuator.pin(&settings.relay_pins);
         info!("Initializing relay pin {}", pin);
         let current_status = match relay_ctrl::check_relay_status(*pin) {
             Ok(status) => {
@@ -50,23 +74,28 @@ pub async fn initialize_relay_pins(settings: &Settings) -> std::io::Result<()> {
             }
             Err(e) => {
                 error!("Failed to check status of pin {}: {}", pin, e);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    e.to_string(),
-                ));
+                return Err(std::io::Error::other(e.to_string()));
             }
         };
 
         if current_status == RelayStatus::On {
             info!("Pin {} is On, attempting to turn it Off", pin);
             match relay_ctrl::change_relay_status(*pin, RelayStatus::Off) {
-                Ok(_) => info!("Successfully turned off pin {}", pin),
+                Ok(_) => {
+                    info!("Successfully turned off pin {}", pin);
+                    events.record(ActuatorEvent::new(
+                        sd,
+                        OffsetDateTime::now_utc(),
+                        actuator,
+                        RelayStatus::On,
+                        RelayStatus::Off,
+                        EventSource::Failsafe,
+                        "relay found on at startup",
+                    ));
+                }
                 Err(e) => {
                     error!("Failed to turn off pin {}: {}", pin, e);
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        e.to_string(),
-                    ));
+                    return Err(std::io::Error::other(e.to_string()));
                 }
             }
         } else {
@@ -77,29 +106,39 @@ pub async fn initialize_relay_pins(settings: &Settings) -> std::io::Result<()> {
     Ok(())
 }
 
-pub async fn deinitialize_relay_pins(
+// Switches every relay off once the tasks have stopped, so the saved state starts the
+// cooldowns now.
+pub fn deinitialize_relay_pins(
     settings: &Settings,
-    mut rx: tokio::sync::broadcast::Receiver<()>,
-) -> std::io::Result<()> {
-    info!("Waiting for shutdown signal");
-    rx.recv().await.unwrap();
-    info!("Shutdown signal received");
+    sd: &AccessSharedData,
+    driver: &dyn RelayDriver,
+    events: &EventLog,
+) -> Result<(), AtmosError> {
     info!("Starting relay pin deinitialization");
-    for pin in &[
-        settings.relay_pins.humidifier,
-        settings.relay_pins.dehumidifier,
-        settings.relay_pins.ventilator_or_heater,
-        settings.relay_pins.fridge,
-    ] {
+    for actuator in Actuator::ALL {
+        let pin = actuator.pin(&settings.relay_pins);
         info!("Deinitializing relay pin {}", pin);
-        match relay_ctrl::change_relay_status(*pin, RelayStatus::Off) {
-            Ok(_) => info!("Successfully turned off pin {}", pin),
+        match driver.change_relay_status(actuator, pin, RelayStatus::Off) {
+            Ok(_) => {
+                info!("Successfully turned off pin {}", pin);
+                let now = OffsetDateTime::now_utc();
+                let old_status = sd.actuator_status(actuator);
+                events.record(ActuatorEvent::new(
+                    sd,
+                    now,
+                    actuator,
+                    old_status,
+                    RelayStatus::Off,
+                    EventSource::Shutdown,
+                    "program shutting down",
+                ));
+                if old_status == RelayStatus::On {
+                    sd.update(|state| state.record_switch(actuator, RelayStatus::Off, now));
+                }
+            }
             Err(e) => {
                 error!("Failed to turn off pin {}: {}", pin, e);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    e.to_string(),
-                ));
+                return Err(e);
             }
         }
     }
@@ -0,0 +1,338 @@
+use crate::config::LoadCellSettings;
+use crate::error::AtmosError;
+use rppal::gpio::{Gpio, InputPin, Level, OutputPin};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// How long to wait for the HX711 to signal a conversion is ready (it samples at 10 Hz)
+const READY_TIMEOUT_MS: u64 = 500;
+// Extra clock pulses after the 24 data bits select channel A with a gain of 128
+const GAIN_128_PULSES: u8 = 1;
+
+// The two wires of the HX711 serial interface, so the bit-banging can be tested without
+// hardware.
+pub trait Hx711Pins: Send {
+    fn set_clock(&mut self, high: bool);
+    fn read_data(&mut self) -> bool;
+    fn wait(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+pub struct GpioHx711Pins {
+    clock: OutputPin,
+    data: InputPin,
+}
+
+impl GpioHx711Pins {
+    pub fn new(clock_pin: u8, data_pin: u8) -> Result<Self, AtmosError> {
+        let gpio = Gpio::new()?;
+        let mut clock = gpio.get(clock_pin)?.into_output();
+        // Holding the clock high for more than 60 µs powers the HX711 down
+        clock.set_low();
+        Ok(GpioHx711Pins {
+            clock,
+            data: gpio.get(data_pin)?.into_input(),
+        })
+    }
+}
+
+impl Hx711Pins for GpioHx711Pins {
+    fn set_clock(&mut self, high: bool) {
+        if high {
+            self.clock.set_high();
+        } else {
+            self.clock.set_low();
+        }
+    }
+
+    fn read_data(&mut self) -> bool {
+        self.data.read() == Level::High
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LoadCellCalibration {
+    /// Raw reading with nothing hanging
+    pub offset: i32,
+    /// Raw counts per gram
+    pub scale: f32,
+}
+
+pub struct Hx711 {
+    pins: Box<dyn Hx711Pins>,
+    pub calibration: LoadCellCalibration,
+}
+
+impl Hx711 {
+    pub fn new(pins: Box<dyn Hx711Pins>, calibration: LoadCellCalibration) -> Self {
+        Hx711 { pins, calibration }
+    }
+
+    // Clocks out one 24-bit two's complement conversion, MSB first.
+    pub fn read_raw(&mut self) -> Result<i32, AtmosError> {
+        // DOUT goes low once a conversion is ready
+        let mut waited = 0;
+        while self.pins.read_data() {
+            if waited >= READY_TIMEOUT_MS {
+                return Err(AtmosError::SensorError(
+                    "HX711 not ready, check the load cell wiring".into(),
+                ));
+            }
+            self.pins.wait(Duration::from_millis(1));
+            waited += 1;
+        }
+
+        let mut value: u32 = 0;
+        for _ in 0..24 {
+            self.pins.set_clock(true);
+            value = (value << 1) | self.pins.read_data() as u32;
+            self.pins.set_clock(false);
+        }
+        for _ in 0..GAIN_128_PULSES {
+            self.pins.set_clock(true);
+            self.pins.set_clock(false);
+        }
+
+        // Sign-extend the 24-bit value
+        Ok(((value << 8) as i32) >> 8)
+    }
+
+    pub fn read_average(&mut self, samples: u8) -> Result<f32, AtmosError> {
+        let samples = samples.max(1);
+        let mut sum = 0.0;
+        for _ in 0..samples {
+            sum += self.read_raw()? as f32;
+        }
+        Ok(sum / samples as f32)
+    }
+
+    pub fn tare(&mut self, samples: u8) -> Result<LoadCellCalibration, AtmosError> {
+        self.calibration.offset = self.read_average(samples)?.round() as i32;
+        Ok(self.calibration)
+    }
+
+    // Derives the scale from a reference weight (in grams) hanging on the tared load cell.
+    pub fn calibrate(
+        &mut self,
+        known_weight: f32,
+        samples: u8,
+    ) -> Result<LoadCellCalibration, AtmosError> {
+        if !known_weight.is_finite() || known_weight <= 0.0 {
+            return Err(AtmosError::InvalidInput(
+                "Calibration weight must be positive".into(),
+            ));
+        }
+        let counts = self.read_average(samples)? - self.calibration.offset as f32;
+        if counts == 0.0 {
+            return Err(AtmosError::SensorError(
+                "Load cell reading didn't change with the calibration weight".into(),
+            ));
+        }
+        let scale = counts / known_weight;
+        if !valid_scale(scale) {
+            return Err(AtmosError::SensorError(format!(
+                "Calibration gave an unusable scale of {}",
+                scale
+            )));
+        }
+        self.calibration.scale = scale;
+        Ok(self.calibration)
+    }
+
+    // Hanging weight in grams. Refused without a usable scale, which would turn every
+    // reading into an infinite or NaN weight.
+    pub fn weight(&mut self, samples: u8) -> Result<f32, AtmosError> {
+        if !valid_scale(self.calibration.scale) {
+            return Err(AtmosError::InvalidInput(format!(
+                "Load cell scale {} is unusable, calibrate the load cell",
+                self.calibration.scale
+            )));
+        }
+        let raw = self.read_average(samples)?;
+        Ok((raw - self.calibration.offset as f32) / self.calibration.scale)
+    }
+}
+
+fn valid_scale(scale: f32) -> bool {
+    scale.is_finite() && scale != 0.0
+}
+
+// The optional load cell of the chamber, shared between the sensor loop and the API.
+pub struct LoadCell {
+    hx711: Mutex<Option<Hx711>>,
+    samples: u8,
+}
+
+impl LoadCell {
+    pub fn from_settings(settings: &LoadCellSettings) -> Result<Self, AtmosError> {
+        let hx711 = if settings.enabled {
+            let pins = GpioHx711Pins::new(settings.clock_pin, settings.data_pin)?;
+            Some(Hx711::new(
+                Box::new(pins),
+                LoadCellCalibration {
+                    offset: settings.offset,
+                    scale: settings.scale,
+                },
+            ))
+        } else {
+            None
+        };
+        Ok(LoadCell {
+            hx711: Mutex::new(hx711),
+            samples: settings.samples,
+        })
+    }
+
+    pub fn new(hx711: Option<Hx711>, samples: u8) -> Self {
+        LoadCell {
+            hx711: Mutex::new(hx711),
+            samples,
+        }
+    }
+
+    fn with_hx711<T>(
+        &self,
+        f: impl FnOnce(&mut Hx711) -> Result<T, AtmosError>,
+    ) -> Result<T, AtmosError> {
+        let mut hx711 = self.hx711.lock().unwrap();
+        match hx711.as_mut() {
+            Some(hx711) => f(hx711),
+            None => Err(AtmosError::InvalidInput("No load cell configured".into())),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.hx711.lock().unwrap().is_some()
+    }
+
+    pub fn calibration(&self) -> Option<LoadCellCalibration> {
+        self.hx711
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|hx711| hx711.calibration)
+    }
+
+    pub fn read_weight(&self) -> Result<f32, AtmosError> {
+        self.with_hx711(|hx711| hx711.weight(self.samples))
+    }
+
+    pub fn tare(&self) -> Result<LoadCellCalibration, AtmosError> {
+        self.with_hx711(|hx711| hx711.tare(self.samples))
+    }
+
+    pub fn calibrate(&self, known_weight: f32) -> Result<LoadCellCalibration, AtmosError> {
+        self.with_hx711(|hx711| hx711.calibrate(known_weight, self.samples))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // Shifts out `raw` on every conversion like an HX711 would, MSB first on each rising
+    // clock edge.
+    struct FakeHx711Pins {
+        raw: Arc<Mutex<i32>>,
+        bit: u8,
+        clock: bool,
+        data: bool,
+    }
+
+    impl FakeHx711Pins {
+        fn new(raw: Arc<Mutex<i32>>) -> Self {
+            FakeHx711Pins {
+                raw,
+                bit: 0,
+                clock: false,
+                data: false,
+            }
+        }
+    }
+
+    impl Hx711Pins for FakeHx711Pins {
+        fn set_clock(&mut self, high: bool) {
+            if high && !self.clock {
+                if self.bit < 24 {
+                    let raw = *self.raw.lock().unwrap() as u32;
+                    self.data = (raw >> (23 - self.bit)) & 1 == 1;
+                } else {
+                    // Gain pulse, the next conversion is immediately ready
+                    self.data = false;
+                }
+                self.bit = (self.bit + 1) % (24 + GAIN_128_PULSES);
+            }
+            self.clock = high;
+        }
+
+        fn read_data(&mut self) -> bool {
+            self.data
+        }
+
+        fn wait(&mut self, _duration: Duration) {}
+    }
+
+    #[test]
+    fn test_read_raw_sign_extends() {
+        let raw = Arc::new(Mutex::new(-12345));
+        let mut hx711 = Hx711::new(
+            Box::new(FakeHx711Pins::new(raw.clone())),
+            LoadCellCalibration {
+                offset: 0,
+                scale: 1.0,
+            },
+        );
+        assert_eq!(hx711.read_raw().unwrap(), -12345);
+
+        *raw.lock().unwrap() = 0x7fffff;
+        assert_eq!(hx711.read_raw().unwrap(), 0x7fffff);
+    }
+
+    #[test]
+    fn test_tare_and_calibrate() {
+        let raw = Arc::new(Mutex::new(8000));
+        let mut hx711 = Hx711::new(
+            Box::new(FakeHx711Pins::new(raw.clone())),
+            LoadCellCalibration {
+                offset: 0,
+                scale: 1.0,
+            },
+        );
+
+        hx711.tare(3).unwrap();
+        assert_eq!(hx711.weight(3).unwrap(), 0.0);
+
+        // 500 g reference weight moves the reading by 21000 counts
+        *raw.lock().unwrap() = 29000;
+        let calibration = hx711.calibrate(500.0, 3).unwrap();
+        assert_eq!(calibration.offset, 8000);
+        assert_eq!(calibration.scale, 42.0);
+
+        *raw.lock().unwrap() = 8000 + 42 * 1200;
+        assert!((hx711.weight(3).unwrap() - 1200.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_unusable_scale_is_rejected() {
+        let raw = Arc::new(Mutex::new(8000));
+        let mut hx711 = Hx711::new(
+            Box::new(FakeHx711Pins::new(raw.clone())),
+            LoadCellCalibration {
+                offset: 0,
+                scale: 0.0,
+            },
+        );
+        assert!(hx711.weight(3).is_err());
+
+        hx711.calibration.scale = f32::NAN;
+        assert!(hx711.weight(3).is_err());
+
+        // A reference weight so small the scale overflows
+        hx711.calibration.scale = 1.0;
+        assert!(hx711.calibrate(f32::MIN_POSITIVE, 3).is_err());
+        assert_eq!(hx711.calibration.scale, 1.0);
+    }
+}
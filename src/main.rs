@@ -1,85 +1,139 @@
+pub mod backup;
+pub mod batches;
+pub mod cli;
+pub mod clock;
 pub mod config;
+pub mod controller_state;
+pub mod dry_run;
 //pub mod email_notification;
 pub mod error;
+pub mod event_bus;
+pub mod events;
+pub mod export;
+pub mod history;
 pub mod initialization;
+pub mod load_cell;
+pub mod maintenance;
+pub mod migrations;
 pub mod mock_relay_ctrl;
 pub mod monitor_atmosphere;
+pub mod psychrometrics;
+pub mod quality_index;
 pub mod read_atmosphere;
+pub mod recipes;
 pub mod relay_ctrl;
+pub mod replay;
 pub mod request_atmosphere;
+pub mod retention;
+pub mod risk_model;
 pub mod routes;
 pub mod shared_data;
+pub mod shutdown;
+pub mod simulator;
 pub mod webserver;
+pub mod write_buffer;
+use crate::clock::SystemClock;
 use crate::config::Settings;
 use crate::shared_data::AccessSharedData;
 use dotenv::dotenv;
-use relay_ctrl::RelayStatus;
+use relay_ctrl::{GpioRelayDriver, RelayDriver, RelayStatus};
 use std::sync::Arc;
-use std::sync::Mutex;
 mod sqlite_client;
+use crate::backup::monitor_backups;
+use crate::batches::monitor_stages;
+use crate::controller_state::{apply_manual_overrides, monitor_controller_state};
+use crate::dry_run::{run_shadow_controller, RecordingRelayDriver};
 use crate::error::AtmosError;
+use crate::events::EventLog;
 use crate::initialization::{
-    deinitialize_relay_pins, initialize_relay_pins, initialize_shared_data,
+    initialize_relay_pins, initialize_shared_data, restore_controller_state,
 };
+use crate::load_cell::LoadCell;
+use crate::maintenance::monitor_door;
 use crate::monitor_atmosphere::monitor_atmosphere;
 use crate::request_atmosphere::request_atmosphere;
+use crate::retention::monitor_retention;
+use crate::risk_model::monitor_risk;
+use crate::shutdown::shut_down;
+use crate::write_buffer::monitor_write_buffer;
+use futures::future::select_all;
 use sqlite_client::SqliteClient;
-use tokio;
 
 #[tokio::main]
 async fn main() -> Result<(), AtmosError> {
     dotenv().ok();
     env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run_command(&args).await;
+    }
+
     log::info!("Starting atmospheric control system");
 
     // Load configuration
     let settings = Settings::new().expect("Failed to load configuration");
 
+    let sqlite_client = Arc::new(SqliteClient::new(&settings.sqlite.db_name)?);
+    sqlite_client.configure_write_buffer(&settings.write_buffer)?;
+
     // Initialize shared data and relay pins
-    let common_data = initialize_shared_data();
-    initialize_relay_pins(&settings).await?;
+    let shared_data = AccessSharedData::new(initialize_shared_data());
+    let events = EventLog::new(sqlite_client.clone(), shared_data.events.clone());
+    // Before the relays are checked, so one found on counts as just switched off
+    if let Err(e) = restore_controller_state(&settings, &shared_data, &sqlite_client) {
+        log::error!("Failed to restore the controller state: {}", e);
+    }
+    initialize_relay_pins(&settings, &shared_data, &events).await?;
+    if let Err(e) = apply_manual_overrides(
+        &settings,
+        &shared_data,
+        &GpioRelayDriver,
+        &events,
+        time::OffsetDateTime::now_utc(),
+    ) {
+        log::error!("Failed to restore the manual overrides: {}", e);
+    }
 
     // Create a channel for shutdown signal
     let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel::<()>(1);
 
-    let shared_data = AccessSharedData {
-        sd: Arc::new(Mutex::new(common_data)),
-    };
-
     // Spawn the main task
     let main_task = tokio::spawn(run_main(
         shared_data.clone(),
         settings.clone(),
+        sqlite_client.clone(),
         shutdown_rx.resubscribe(),
     ));
 
     // Wait for Ctrl+C
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            println!("Received Ctrl+C, shutting down...");
-            // close all relays
-            deinitialize_relay_pins(&settings, shutdown_rx.resubscribe()).await?;
-        }
-    }
-
-    // Send shutdown signal
-    shutdown_tx
-        .send(())
-        .expect("Failed to send shutdown signal");
-
-    // Wait for the main task to finish
-    main_task.await.expect("Main task panicked")?;
+    tokio::signal::ctrl_c().await?;
+    println!("Received Ctrl+C, shutting down...");
+    shut_down(
+        &shutdown_tx,
+        main_task,
+        &settings,
+        &shared_data,
+        &sqlite_client,
+        &GpioRelayDriver,
+        &events,
+    )
+    .await?;
 
     println!("Shutdown complete");
     Ok(())
 }
 
+// How long the tasks get to finish what they're doing after the shutdown signal
+const TASK_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 async fn run_main(
     shared_data: AccessSharedData,
     settings: Settings,
+    sqlite_client: Arc<SqliteClient>,
     mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
 ) -> Result<(), AtmosError> {
-    let sqlite_client = Arc::new(SqliteClient::new(&settings.sqlite.db_name)?);
+    let relay_driver: Arc<dyn RelayDriver> = Arc::new(GpioRelayDriver);
 
     let monitor_shared_data = shared_data.clone();
     let monitor_settings = settings.clone();
@@ -88,33 +142,146 @@ async fn run_main(
         monitor_shared_data,
         monitor_settings,
         monitor_sqlite_client,
+        relay_driver.clone(),
+        Arc::new(SystemClock),
         shutdown_rx.resubscribe(),
     ));
 
+    let dry_run_driver = Arc::new(RecordingRelayDriver::new(shared_data.clone()));
+    let dry_run_task = tokio::spawn(run_shadow_controller(
+        shared_data.clone(),
+        settings.clone(),
+        dry_run_driver.clone(),
+        shutdown_rx.resubscribe(),
+    ));
+
+    let load_cell = match LoadCell::from_settings(&settings.load_cell) {
+        Ok(load_cell) => load_cell,
+        Err(e) => {
+            log::error!(
+                "Failed to open the load cell, weighing is unavailable: {}",
+                e
+            );
+            LoadCell::new(None, settings.load_cell.samples)
+        }
+    };
+    let load_cell = Arc::new(load_cell);
+
     let request_shared_data = shared_data.clone();
     let request_settings = settings.clone();
+    let request_sqlite_client = sqlite_client.clone();
     let request_task = tokio::spawn(request_atmosphere(
         request_shared_data,
         request_settings,
+        request_sqlite_client,
+        load_cell.clone(),
+        shutdown_rx.resubscribe(),
+    ));
+
+    let door_shared_data = shared_data.clone();
+    let door_settings = settings.clone();
+    let door_sqlite_client = sqlite_client.clone();
+    let door_task = tokio::spawn(monitor_door(
+        door_shared_data,
+        door_settings,
+        door_sqlite_client,
+        relay_driver.clone(),
+        shutdown_rx.resubscribe(),
+    ));
+
+    let risk_task = tokio::spawn(monitor_risk(
+        shared_data.clone(),
+        settings.clone(),
+        sqlite_client.clone(),
+        Arc::new(SystemClock),
+        shutdown_rx.resubscribe(),
+    ));
+
+    let stage_task = tokio::spawn(monitor_stages(
+        shared_data.clone(),
+        sqlite_client.clone(),
+        Arc::new(SystemClock),
+        shutdown_rx.resubscribe(),
+    ));
+
+    let retention_task = tokio::spawn(monitor_retention(
+        settings.clone(),
+        sqlite_client.clone(),
+        Arc::new(SystemClock),
+        shutdown_rx.resubscribe(),
+    ));
+
+    let controller_state_task = tokio::spawn(monitor_controller_state(
+        shared_data.clone(),
+        settings.clone(),
+        sqlite_client.clone(),
+        Arc::new(SystemClock),
+        shutdown_rx.resubscribe(),
+    ));
+
+    let write_buffer_task = tokio::spawn(monitor_write_buffer(
+        settings.clone(),
+        sqlite_client.clone(),
+        shutdown_rx.resubscribe(),
+    ));
+
+    let backup_task = tokio::spawn(monitor_backups(
+        settings.clone(),
+        sqlite_client.clone(),
+        Arc::new(SystemClock),
         shutdown_rx.resubscribe(),
     ));
 
     let webserver_shared_data = shared_data.clone();
     let webserver_settings = settings.clone();
     let webserver_sqlite_client = sqlite_client.clone();
-    let webserver_task = tokio::spawn(webserver::run_app(
-        webserver_shared_data,
-        webserver_settings,
-        shutdown_rx.resubscribe(),
-        webserver_sqlite_client,
-    ));
+    let webserver_shutdown_rx = shutdown_rx.resubscribe();
+    let webserver_task = tokio::spawn(async move {
+        Ok(webserver::run_app(
+            webserver_shared_data,
+            webserver_settings,
+            webserver_shutdown_rx,
+            webserver_sqlite_client,
+            relay_driver,
+            dry_run_driver,
+            load_cell,
+        )
+        .await?)
+    });
 
-    tokio::select! {
-        _ = monitor_task => println!("Monitor task finished"),
-        _ = request_task => println!("Request task finished"),
-        _ = webserver_task => println!("Webserver task finished"),
-        _ = shutdown_rx.recv() => println!("Received shutdown signal"),
+    let mut tasks = vec![
+        ("Monitor", monitor_task),
+        ("Request", request_task),
+        ("Door", door_task),
+        ("Risk", risk_task),
+        ("Stage", stage_task),
+        ("Retention", retention_task),
+        ("Backup", backup_task),
+        ("Write buffer", write_buffer_task),
+        ("Controller state", controller_state_task),
+        ("Dry-run", dry_run_task),
+        ("Webserver", webserver_task),
+    ];
+    let finished = tokio::select! {
+        (_, index, _) = select_all(tasks.iter_mut().map(|(_, task)| task)) => Some(index),
+        _ = shutdown_rx.recv() => None,
+    };
+    if let Some(index) = finished {
+        println!("{} task finished", tasks[index].0);
+        return Ok(());
     }
 
+    // The relays are switched off after this returns, no task may still be switching them
+    println!("Received shutdown signal");
+    let stopped = futures::future::join_all(tasks.iter_mut().map(|(_, task)| task));
+    if tokio::time::timeout(TASK_SHUTDOWN_TIMEOUT, stopped)
+        .await
+        .is_err()
+    {
+        log::warn!("Tasks still running after the shutdown signal, aborting them");
+        for (_, task) in &tasks {
+            task.abort();
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,364 @@
+use crate::config::Settings;
+use crate::error::AtmosError;
+use crate::events::{ActuatorEvent, EventLog, EventSource};
+use crate::relay_ctrl::{Actuator, RelayDriver, RelayStatus};
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::SqliteClient;
+use log::{error, info, warn};
+use rppal::gpio::{Gpio, InputPin, Level};
+use std::fmt;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::time::{interval, Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceTrigger {
+    Api,
+    DoorSwitch,
+}
+
+impl fmt::Display for MaintenanceTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaintenanceTrigger::Api => write!(f, "api"),
+            MaintenanceTrigger::DoorSwitch => write!(f, "door_switch"),
+        }
+    }
+}
+
+// Pauses automatic control: every actuator is switched off, the ventilator optionally
+// switched on, and a maintenance session is opened in SQLite. Control is only paused once
+// all of that succeeded, so a failed relay or insert leaves the control loop in charge.
+// Starting maintenance while a session is already settling (door re-opened) simply
+// cancels the pending resume.
+pub async fn start_maintenance(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    sqlite_client: &Arc<SqliteClient>,
+    driver: &Arc<dyn RelayDriver>,
+    events: &EventLog,
+    trigger: MaintenanceTrigger,
+) -> Result<(), AtmosError> {
+    // Waits for a control handler that is switching a relay right now, the ones asleep
+    // leave the relays alone once maintenance is active
+    let _relays = sd.relays.lock().await;
+    if sd.maintenance_active() {
+        if sd.maintenance_release_datetime().is_some() {
+            info!("maintenance() -> resume cancelled by {}", trigger);
+            sd.update(|state| state.maintenance_release_datetime = None);
+        }
+        return Ok(());
+    }
+
+    let now = OffsetDateTime::now_utc();
+    info!("maintenance() -> entering maintenance mode ({})", trigger);
+    let reason = format!("maintenance started ({})", trigger);
+    let switch = |actuator: Actuator, new_status| -> Result<(), AtmosError> {
+        let old_status = sd.actuator_status(actuator);
+        driver.change_relay_status(actuator, actuator.pin(&settings.relay_pins), new_status)?;
+        events.record(ActuatorEvent::new(
+            sd,
+            now,
+            actuator,
+            old_status,
+            new_status,
+            EventSource::Manual,
+            reason.as_str(),
+        ));
+        if old_status != new_status {
+            sd.update(|state| state.record_switch(actuator, new_status, now));
+        }
+        Ok(())
+    };
+
+    if sd.fridge_status() == RelayStatus::On {
+        switch(Actuator::Fridge, RelayStatus::Off)?;
+    }
+    if sd.dehumidifier_status() == RelayStatus::On {
+        switch(Actuator::Dehumidifier, RelayStatus::Off)?;
+    }
+    switch(Actuator::Humidifier, RelayStatus::Off)?;
+    if settings.maintenance.run_fan {
+        info!("maintenance() -> running ventilator for the session");
+        switch(Actuator::Ventilator, RelayStatus::On)?;
+    } else if sd.ventilator_status() == RelayStatus::On {
+        switch(Actuator::Ventilator, RelayStatus::Off)?;
+    }
+
+    let session_id = sqlite_client
+        .call(move |db| db.start_maintenance_session(now, &trigger.to_string()))
+        .await?;
+    sd.update(|state| {
+        state.maintenance_active = true;
+        state.maintenance_start_datetime = now;
+        state.maintenance_release_datetime = None;
+        state.maintenance_session_id = Some(session_id);
+    });
+    Ok(())
+}
+
+// Starts the settle delay. Automatic control resumes once `finish_maintenance_if_settled`
+// sees that the delay has elapsed.
+pub fn release_maintenance(sd: &AccessSharedData, now: OffsetDateTime) {
+    if sd.maintenance_active() && sd.maintenance_release_datetime().is_none() {
+        info!("maintenance() -> released, waiting for the chamber to settle");
+        sd.update(|state| state.maintenance_release_datetime = Some(now));
+    }
+}
+
+pub fn maintenance_resume_datetime(
+    sd: &AccessSharedData,
+    settings: &Settings,
+) -> Option<OffsetDateTime> {
+    sd.maintenance_release_datetime()
+        .map(|released| released + Duration::from_secs(settings.maintenance.settle_delay))
+}
+
+// Called on every monitor tick. Returns true while automatic control must stay paused.
+pub async fn finish_maintenance_if_settled(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    sqlite_client: &Arc<SqliteClient>,
+    driver: &Arc<dyn RelayDriver>,
+    events: &EventLog,
+    now: OffsetDateTime,
+) -> Result<bool, AtmosError> {
+    if !sd.maintenance_active() {
+        return Ok(false);
+    }
+    match maintenance_resume_datetime(sd, settings) {
+        Some(resume_at) if now >= resume_at => {
+            info!("maintenance() -> settle delay elapsed, resuming automatic control");
+            if settings.maintenance.run_fan {
+                driver.change_relay_status(
+                    Actuator::Ventilator,
+                    settings.relay_pins.ventilator_or_heater,
+                    RelayStatus::Off,
+                )?;
+                events.record(ActuatorEvent::new(
+                    sd,
+                    now,
+                    Actuator::Ventilator,
+                    sd.ventilator_status(),
+                    RelayStatus::Off,
+                    EventSource::Manual,
+                    "maintenance finished",
+                ));
+                sd.update(|state| state.record_switch(Actuator::Ventilator, RelayStatus::Off, now));
+            }
+            let mut session_id = None;
+            sd.update(|state| {
+                state.maintenance_active = false;
+                state.maintenance_release_datetime = None;
+                session_id = state.maintenance_session_id.take();
+            });
+            if let Some(id) = session_id {
+                sqlite_client
+                    .call(move |db| db.end_maintenance_session(id, now))
+                    .await?;
+            }
+            Ok(false)
+        }
+        _ => Ok(true),
+    }
+}
+
+fn open_door_switch(pin: u8) -> Result<InputPin, AtmosError> {
+    Ok(Gpio::new()?.get(pin)?.into_input_pullup())
+}
+
+pub async fn monitor_door(
+    sd: AccessSharedData,
+    settings: Settings,
+    sqlite_client: Arc<SqliteClient>,
+    driver: Arc<dyn RelayDriver>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    let Some(pin) = settings.maintenance.door_switch_pin else {
+        info!("No door switch configured, maintenance mode is API only");
+        let _ = shutdown_rx.recv().await;
+        return Ok(());
+    };
+
+    let door_switch = match open_door_switch(pin) {
+        Ok(door_switch) => door_switch,
+        Err(e) => {
+            error!(
+                "Failed to open door switch on pin {}, maintenance mode is API only: {}",
+                pin, e
+            );
+            let _ = shutdown_rx.recv().await;
+            return Ok(());
+        }
+    };
+
+    info!("Watching door switch on pin {}", pin);
+    let events = EventLog::new(sqlite_client.clone(), sd.events.clone());
+    let mut interval = interval(Duration::from_secs(1));
+    // `None` until the first reading, which counts as a transition so a session restored at
+    // boot is released when the door reads closed
+    let mut last_door_open = None;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                // The reed switch shorts the pin to ground while the magnet is near (door closed)
+                let door_open = door_switch.read() == Level::High;
+                if last_door_open == Some(door_open) {
+                    continue;
+                }
+                last_door_open = Some(door_open);
+                sd.update(|state| state.door_open = door_open);
+                if door_open {
+                    warn!("Chamber door opened");
+                    if let Err(e) = start_maintenance(
+                        &sd,
+                        &settings,
+                        &sqlite_client,
+                        &driver,
+                        &events,
+                        MaintenanceTrigger::DoorSwitch,
+                    )
+                    .await
+                    {
+                        error!("Failed to enter maintenance mode: {}", e);
+                    }
+                } else {
+                    info!("Chamber door closed");
+                    release_maintenance(&sd, OffsetDateTime::now_utc());
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Received shutdown signal");
+                break Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_settings;
+    use crate::initialization::initialize_shared_data;
+    use crate::mock_relay_ctrl::MockRelayDriver;
+
+    // Fails every switch of one actuator
+    struct FailingRelayDriver(Actuator, MockRelayDriver);
+
+    impl RelayDriver for FailingRelayDriver {
+        fn change_relay_status(
+            &self,
+            actuator: Actuator,
+            pin: u8,
+            status: RelayStatus,
+        ) -> Result<(), AtmosError> {
+            if actuator == self.0 {
+                return Err(AtmosError::RelayError(format!("pin {} stuck", pin)));
+            }
+            self.1.change_relay_status(actuator, pin, status)
+        }
+    }
+
+    fn maintenance_settings() -> Settings {
+        let mut settings = test_settings();
+        settings.maintenance.run_fan = true;
+        settings.maintenance.settle_delay = 600;
+        settings
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_start_release_and_resume() {
+        let settings = maintenance_settings();
+        let sqlite_client = Arc::new(SqliteClient::new(":memory:").unwrap());
+        let relays = MockRelayDriver::default();
+        let driver: Arc<dyn RelayDriver> = Arc::new(relays.clone());
+        let sd = AccessSharedData::new(initialize_shared_data());
+        let events = EventLog::disabled();
+        let pins = &settings.relay_pins;
+        driver
+            .change_relay_status(Actuator::Fridge, pins.fridge, RelayStatus::On)
+            .unwrap();
+        sd.update(|state| {
+            state.fridge_status = RelayStatus::On;
+            state.humidifier_status = RelayStatus::On;
+        });
+
+        start_maintenance(
+            &sd,
+            &settings,
+            &sqlite_client,
+            &driver,
+            &events,
+            MaintenanceTrigger::Api,
+        )
+        .await
+        .unwrap();
+        assert!(sd.maintenance_active());
+        assert!(sd.maintenance_session_id().is_some());
+        assert_eq!(relays.status(pins.fridge), RelayStatus::Off);
+        assert_eq!(relays.status(pins.ventilator_or_heater), RelayStatus::On);
+        assert_eq!(sd.humidifier_status(), RelayStatus::Off);
+        assert_eq!(
+            sd.humidifier_turn_off_datetime(),
+            sd.maintenance_start_datetime()
+        );
+
+        // Paused until the settle delay after the release has passed
+        let released = OffsetDateTime::now_utc();
+        release_maintenance(&sd, released);
+        let settling = released + Duration::from_secs(599);
+        assert!(finish_maintenance_if_settled(
+            &sd,
+            &settings,
+            &sqlite_client,
+            &driver,
+            &events,
+            settling
+        )
+        .await
+        .unwrap());
+        let settled = released + Duration::from_secs(600);
+        assert!(!finish_maintenance_if_settled(
+            &sd,
+            &settings,
+            &sqlite_client,
+            &driver,
+            &events,
+            settled
+        )
+        .await
+        .unwrap());
+        assert!(!sd.maintenance_active());
+        assert_eq!(sd.maintenance_session_id(), None);
+        assert_eq!(relays.status(pins.ventilator_or_heater), RelayStatus::Off);
+        assert!(sqlite_client
+            .read_maintenance_sessions(10)
+            .unwrap()
+            .contains("ended_at\":\""));
+    }
+
+    #[tokio::test]
+    async fn test_failed_relay_leaves_control_running() {
+        let settings = maintenance_settings();
+        let sqlite_client = Arc::new(SqliteClient::new(":memory:").unwrap());
+        let driver: Arc<dyn RelayDriver> = Arc::new(FailingRelayDriver(
+            Actuator::Humidifier,
+            MockRelayDriver::default(),
+        ));
+        let sd = AccessSharedData::new(initialize_shared_data());
+
+        let result = start_maintenance(
+            &sd,
+            &settings,
+            &sqlite_client,
+            &driver,
+            &EventLog::disabled(),
+            MaintenanceTrigger::DoorSwitch,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(!sd.maintenance_active());
+        assert_eq!(sd.maintenance_session_id(), None);
+        assert_eq!(sqlite_client.read_maintenance_sessions(10).unwrap(), "[]");
+    }
+}
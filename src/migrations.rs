@@ -0,0 +1,467 @@
+use crate::error::AtmosError;
+use crate::sqlite_client::{parse_timestamp, unix_millis};
+use log::{info, warn};
+use rusqlite::{Connection, Result, Row};
+
+type Migration = fn(&Connection) -> Result<(), AtmosError>;
+
+// Schema changes, applied in order. A database at `PRAGMA user_version` N has had the
+// first N migrations applied. Never edit a released migration, append a new one instead.
+//
+// Databases created before migrations existed report version 0 but may already hold some
+// of the early tables and columns, so the migrations that mirror those changes have to
+// tolerate finding them.
+const MIGRATIONS: &[Migration] = &[
+    create_atmosphere_data,
+    create_maintenance_sessions,
+    add_derived_metrics,
+    add_atmospheric_quality_index,
+    create_batches,
+    add_load_cell_columns,
+    create_recipes,
+    create_readings,
+    create_actuator_events,
+    add_sortable_timestamps,
+    create_rollups,
+    create_controller_state,
+    add_weigh_in_timestamps,
+];
+
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+// Adds a column unless a pre-migrations version of the program already did.
+fn ensure_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), AtmosError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row: &Row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn create_atmosphere_data(conn: &Connection) -> Result<(), AtmosError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS atmosphere_data (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            average_temperature REAL NOT NULL,
+            average_humidity REAL NOT NULL,
+            fridge_status TEXT NOT NULL,
+            dehumidifier_status TEXT NOT NULL,
+            humidifier_status TEXT NOT NULL,
+            ventilator_status TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_maintenance_sessions(conn: &Connection) -> Result<(), AtmosError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS maintenance_sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at TEXT NOT NULL,
+            ended_at TEXT,
+            trigger TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_derived_metrics(conn: &Connection) -> Result<(), AtmosError> {
+    ensure_column(conn, "atmosphere_data", "dew_point", "REAL")?;
+    ensure_column(conn, "atmosphere_data", "absolute_humidity", "REAL")?;
+    ensure_column(conn, "atmosphere_data", "vapour_pressure_deficit", "REAL")?;
+    Ok(())
+}
+
+fn add_atmospheric_quality_index(conn: &Connection) -> Result<(), AtmosError> {
+    ensure_column(conn, "atmosphere_data", "atmospheric_quality_index", "REAL")
+}
+
+fn create_batches(conn: &Connection) -> Result<(), AtmosError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS batches (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            start_weight REAL NOT NULL,
+            target_loss_percent REAL NOT NULL,
+            program TEXT,
+            started_at TEXT NOT NULL,
+            finished_at TEXT
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS weigh_ins (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            batch_id INTEGER NOT NULL REFERENCES batches(id),
+            timestamp TEXT NOT NULL,
+            weight REAL NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_load_cell_columns(conn: &Connection) -> Result<(), AtmosError> {
+    ensure_column(conn, "atmosphere_data", "hanging_weight", "REAL")?;
+    ensure_column(
+        conn,
+        "weigh_ins",
+        "source",
+        "TEXT NOT NULL DEFAULT 'manual'",
+    )?;
+    Ok(())
+}
+
+fn create_recipes(conn: &Connection) -> Result<(), AtmosError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS recipes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            definition TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    ensure_column(
+        conn,
+        "batches",
+        "recipe_id",
+        "INTEGER REFERENCES recipes(id)",
+    )?;
+    Ok(())
+}
+
+// Per-sensor values and every actuator state, keyed to the averaged row they belong to
+fn create_readings(conn: &Connection) -> Result<(), AtmosError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS sensor_readings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            atmosphere_data_id INTEGER NOT NULL REFERENCES atmosphere_data(id),
+            sensor_id INTEGER NOT NULL,
+            metric TEXT NOT NULL,
+            value REAL NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS sensor_readings_atmosphere_data_id
+            ON sensor_readings(atmosphere_data_id);
+        CREATE TABLE IF NOT EXISTS actuator_states (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            atmosphere_data_id INTEGER NOT NULL REFERENCES atmosphere_data(id),
+            actuator TEXT NOT NULL,
+            status TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS actuator_states_atmosphere_data_id
+            ON actuator_states(atmosphere_data_id);",
+    )?;
+    Ok(())
+}
+
+fn create_actuator_events(conn: &Connection) -> Result<(), AtmosError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS actuator_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            actuator TEXT NOT NULL,
+            old_status TEXT NOT NULL,
+            new_status TEXT NOT NULL,
+            source TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            readings TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS actuator_events_actuator ON actuator_events(actuator);",
+    )?;
+    Ok(())
+}
+
+// `timestamp` holds `OffsetDateTime::to_string()` text, which doesn't sort
+// chronologically. Range queries use an indexed Unix time in milliseconds instead,
+// backfilled from the text for existing rows.
+fn add_timestamp_ms(conn: &Connection, table: &str) -> Result<(), AtmosError> {
+    ensure_column(conn, table, "timestamp_ms", "INTEGER")?;
+
+    let rows: Vec<(i64, String)> = conn
+        .prepare(&format!(
+            "SELECT id, timestamp FROM {} WHERE timestamp_ms IS NULL",
+            table
+        ))?
+        .query_map([], |row: &Row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut update = conn.prepare(&format!(
+        "UPDATE {} SET timestamp_ms = ?1 WHERE id = ?2",
+        table
+    ))?;
+    for (id, timestamp) in rows {
+        match parse_timestamp(&timestamp) {
+            Ok(timestamp) => {
+                update.execute([unix_millis(timestamp), id])?;
+            }
+            Err(e) => warn!("Leaving {} row {} out of time queries: {}", table, id, e),
+        }
+    }
+
+    conn.execute(
+        &format!(
+            "CREATE INDEX IF NOT EXISTS {0}_timestamp_ms ON {0}(timestamp_ms)",
+            table
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_sortable_timestamps(conn: &Connection) -> Result<(), AtmosError> {
+    for table in ["atmosphere_data", "actuator_events"] {
+        add_timestamp_ms(conn, table)?;
+    }
+    Ok(())
+}
+
+// Aggregates that replace raw history once it's older than the retention period
+fn create_rollups(conn: &Connection) -> Result<(), AtmosError> {
+    for table in ["atmosphere_hourly", "atmosphere_daily"] {
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    bucket_start_ms INTEGER PRIMARY KEY,
+                    bucket_start TEXT NOT NULL,
+                    samples INTEGER NOT NULL,
+                    min_temperature REAL NOT NULL,
+                    max_temperature REAL NOT NULL,
+                    avg_temperature REAL NOT NULL,
+                    min_humidity REAL NOT NULL,
+                    max_humidity REAL NOT NULL,
+                    avg_humidity REAL NOT NULL,
+                    avg_vapour_pressure_deficit REAL,
+                    avg_atmospheric_quality_index REAL,
+                    fridge_duty_cycle REAL NOT NULL,
+                    humidifier_duty_cycle REAL NOT NULL,
+                    dehumidifier_duty_cycle REAL NOT NULL,
+                    ventilator_duty_cycle REAL NOT NULL,
+                    heater_duty_cycle REAL
+                )",
+                table
+            ),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+// A single row holding the controller state snapshot restored on boot
+fn create_controller_state(conn: &Connection) -> Result<(), AtmosError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS controller_state (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            saved_at TEXT NOT NULL,
+            state TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// Weigh-ins can be backdated, so they need a sortable timestamp of their own to be
+// filtered and ordered in SQL
+fn add_weigh_in_timestamps(conn: &Connection) -> Result<(), AtmosError> {
+    add_timestamp_ms(conn, "weigh_ins")
+}
+
+pub fn schema_version(conn: &Connection) -> Result<u32, AtmosError> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+// Brings the schema up to `target` (at most `SCHEMA_VERSION`), one transaction per
+// migration so an interrupted upgrade resumes where it stopped.
+pub fn migrate_to(conn: &mut Connection, target: u32) -> Result<(), AtmosError> {
+    let current = schema_version(conn)?;
+    if current > SCHEMA_VERSION {
+        return Err(AtmosError::MigrationError(format!(
+            "Database schema version {} is newer than the {} this program supports",
+            current, SCHEMA_VERSION
+        )));
+    }
+    if current >= target {
+        return Ok(());
+    }
+
+    info!(
+        "Migrating database schema from version {} to {}",
+        current, target
+    );
+    for version in current..target {
+        let tx = conn.transaction()?;
+        MIGRATIONS[version as usize](&tx).map_err(|e| {
+            AtmosError::MigrationError(format!(
+                "Migration to version {} failed: {}",
+                version + 1,
+                e
+            ))
+        })?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+pub fn run_migrations(conn: &mut Connection) -> Result<(), AtmosError> {
+    migrate_to(conn, SCHEMA_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The schema of every released version, dumped when it was released and never
+    // regenerated, so editing an old migration shows up as a mismatch. Version 0 is the
+    // schema written before migrations existed. Add the dump of a new version here when
+    // appending a migration.
+    const SCHEMA_FIXTURES: &[&str] = &[
+        include_str!("../fixtures/schema/v00.sql"),
+        include_str!("../fixtures/schema/v01.sql"),
+        include_str!("../fixtures/schema/v02.sql"),
+        include_str!("../fixtures/schema/v03.sql"),
+        include_str!("../fixtures/schema/v04.sql"),
+        include_str!("../fixtures/schema/v05.sql"),
+        include_str!("../fixtures/schema/v06.sql"),
+        include_str!("../fixtures/schema/v07.sql"),
+        include_str!("../fixtures/schema/v08.sql"),
+        include_str!("../fixtures/schema/v09.sql"),
+        include_str!("../fixtures/schema/v10.sql"),
+        include_str!("../fixtures/schema/v11.sql"),
+        include_str!("../fixtures/schema/v12.sql"),
+        include_str!("../fixtures/schema/v13.sql"),
+    ];
+
+    const SAMPLE_READING: &str = "
+        INSERT INTO atmosphere_data (
+            timestamp, average_temperature, average_humidity,
+            fridge_status, dehumidifier_status, humidifier_status, ventilator_status
+        ) VALUES ('2024-05-01 0:00:00.0 +00:00:00', 12.5, 75.0, 'On', 'Off', 'Off', 'Off');
+    ";
+
+    fn fixture_database(version: usize) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA_FIXTURES[version]).unwrap();
+        conn.execute_batch(SAMPLE_READING).unwrap();
+        conn
+    }
+
+    // Every table with its column names, to compare schemas regardless of how they were built
+    fn schema(conn: &Connection) -> Vec<(String, Vec<String>)> {
+        let mut stmt = conn
+            .prepare(
+                "SELECT name FROM sqlite_master
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            )
+            .unwrap();
+        let tables: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        tables
+            .into_iter()
+            .map(|table| {
+                let mut stmt = conn
+                    .prepare(&format!("PRAGMA table_info({})", table))
+                    .unwrap();
+                let mut columns: Vec<String> = stmt
+                    .query_map([], |row| row.get(1))
+                    .unwrap()
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                columns.sort();
+                (table, columns)
+            })
+            .collect()
+    }
+
+    fn fresh_schema() -> Vec<(String, Vec<String>)> {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_migrations(&mut conn).unwrap();
+        schema(&conn)
+    }
+
+    #[test]
+    fn test_every_version_has_a_fixture() {
+        assert_eq!(SCHEMA_FIXTURES.len(), SCHEMA_VERSION as usize + 1);
+        for version in 1..=SCHEMA_VERSION {
+            let mut conn = Connection::open_in_memory().unwrap();
+            migrate_to(&mut conn, version).unwrap();
+            let fixture = fixture_database(version as usize);
+            assert_eq!(schema_version(&fixture).unwrap(), version);
+            assert_eq!(
+                schema(&conn),
+                schema(&fixture),
+                "migration to {} changed after its release",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn test_upgrade_legacy_database() {
+        let mut conn = fixture_database(0);
+
+        run_migrations(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert_eq!(schema(&conn), fresh_schema());
+        let temperature: f32 = conn
+            .query_row(
+                "SELECT average_temperature FROM atmosphere_data",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(temperature, 12.5);
+
+        // 2024-05-01T00:00:00Z
+        let timestamp_ms: i64 = conn
+            .query_row("SELECT timestamp_ms FROM atmosphere_data", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(timestamp_ms, 1_714_521_600_000);
+    }
+
+    #[test]
+    fn test_upgrade_from_each_version() {
+        for version in 1..SCHEMA_FIXTURES.len() {
+            let mut conn = fixture_database(version);
+
+            run_migrations(&mut conn).unwrap();
+
+            assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+            assert_eq!(schema(&conn), fresh_schema(), "upgrading from {}", version);
+            let rows: i64 = conn
+                .query_row("SELECT COUNT(*) FROM atmosphere_data", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(rows, 1);
+        }
+    }
+
+    #[test]
+    fn test_refuses_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(run_migrations(&mut conn).is_err());
+    }
+}
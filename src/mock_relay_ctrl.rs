@@ -1,30 +1,34 @@
 use crate::error::AtmosError;
-use crate::relay_ctrl::RelayStatus;
-use lazy_static::lazy_static;
+use crate::relay_ctrl::{Actuator, RelayDriver, RelayStatus};
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-lazy_static! {
-    static ref MOCK_RELAY_STATES: Mutex<HashMap<u8, AtomicBool>> = Mutex::new(HashMap::new());
+// Relay states kept in memory instead of on the GPIO pins. Clones share the states, so a
+// test keeps one to check what the code under test switched through another.
+#[derive(Clone, Default)]
+pub struct MockRelayDriver {
+    states: Arc<Mutex<HashMap<u8, RelayStatus>>>,
 }
 
-pub async fn mock_change_relay_status(pin: u8, status: RelayStatus) -> Result<(), AtmosError> {
-    let mut states = MOCK_RELAY_STATES.lock().unwrap();
-    let state = states.entry(pin).or_insert_with(|| AtomicBool::new(false));
-    state.store(status == RelayStatus::On, Ordering::SeqCst);
-    Ok(())
+impl MockRelayDriver {
+    pub fn status(&self, pin: u8) -> RelayStatus {
+        self.states
+            .lock()
+            .unwrap()
+            .get(&pin)
+            .copied()
+            .unwrap_or(RelayStatus::Off)
+    }
 }
 
-pub fn get_mock_relay_status(pin: u8) -> RelayStatus {
-    let states = MOCK_RELAY_STATES.lock().unwrap();
-    if let Some(state) = states.get(&pin) {
-        if state.load(Ordering::SeqCst) {
-            RelayStatus::On
-        } else {
-            RelayStatus::Off
-        }
-    } else {
-        RelayStatus::Off
+impl RelayDriver for MockRelayDriver {
+    fn change_relay_status(
+        &self,
+        _actuator: Actuator,
+        pin: u8,
+        status: RelayStatus,
+    ) -> Result<(), AtmosError> {
+        self.states.lock().unwrap().insert(pin, status);
+        Ok(())
     }
 }
@@ -1,9 +1,20 @@
-use crate::sqlite_client::SqliteClient;
+use crate::clock::Clock;
+use crate::config::HumidityControlTarget;
+use crate::event_bus::BusEvent;
+use crate::events::{ActuatorEvent, EventLog, EventSource};
+use crate::maintenance::finish_maintenance_if_settled;
+use crate::psychrometrics::DerivedMetrics;
+use crate::quality_index::{evaluate_components, quality_index, QualityIndexInput};
+use crate::shared_data::AtmosphereState;
+use crate::sqlite_client::{AtmosphereRow, SensorReading, SqliteClient};
+use crate::write_buffer::BufferedWrite;
 use crate::Arc;
 use crate::{
-    error::AtmosError, relay_ctrl::change_relay_status, AccessSharedData, RelayStatus, Settings,
+    error::AtmosError,
+    relay_ctrl::{Actuator, RelayDriver},
+    AccessSharedData, RelayStatus, Settings,
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use time::OffsetDateTime;
 use tokio::task::JoinHandle;
 use tokio::time::{interval, Duration};
@@ -11,33 +22,54 @@ pub async fn monitor_atmosphere(
     sd: AccessSharedData,
     settings: Settings,
     sqlite_client: Arc<SqliteClient>,
+    driver: Arc<dyn RelayDriver>,
+    clock: Arc<dyn Clock>,
     mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
 ) -> Result<(), AtmosError> {
     let mut interval = interval(Duration::from_secs(settings.polling_interval.duration));
+    let events = EventLog::new(sqlite_client.clone(), sd.events.clone());
 
     loop {
         tokio::select! {
             _ = interval.tick() => {
                 interval.tick().await;
 
+        let now = clock.now();
         update_average_values(&sd);
-        update_atmosphere_quality_index(&sd, &settings);
-
-        if sd.polling_iterations() > 4 {
-            let now = OffsetDateTime::now_utc();
-
-            let handlers: Vec<JoinHandle<Result<(), AtmosError>>> = vec![
-                tokio::spawn(handle_fridge(sd.clone(), now, settings.clone())),
-                tokio::spawn(handle_dehumidifier(sd.clone(), now, settings.clone())),
-                tokio::spawn(handle_humidifier(sd.clone(), now, settings.clone())),
-                tokio::spawn(handle_ventilator(sd.clone(), now, settings.clone())),
-                tokio::spawn(insert_atmosphere_data(sd.clone(), sqlite_client.clone())),
-            ];
-
-            for handler in handlers {
-                if let Err(e) = handler.await.expect("Task panicked") {
-                    handle_device_error(&e).await;
-                }
+        update_derived_metrics(&sd);
+        update_atmosphere_quality_index(&sd, &settings, now);
+
+        let in_maintenance = match finish_maintenance_if_settled(
+            &sd,
+            &settings,
+            &sqlite_client,
+            &driver,
+            &events,
+            now,
+        )
+        .await
+        {
+            Ok(in_maintenance) => in_maintenance,
+            Err(e) => {
+                handle_device_error(&sd, &e).await;
+                true
+            }
+        };
+
+        if in_maintenance {
+            info!("Maintenance mode active, automatic control paused");
+            if let Err(e) = insert_atmosphere_data(sd.clone(), sqlite_client.clone(), now).await {
+                handle_device_error(&sd, &e).await;
+            }
+        } else if sd.polling_iterations() > 4 {
+            let insert_handler =
+                tokio::spawn(insert_atmosphere_data(sd.clone(), sqlite_client.clone(), now));
+
+            for e in run_control_handlers(&sd, &settings, &driver, &clock, &events, now).await {
+                handle_device_error(&sd, &e).await;
+            }
+            if let Err(e) = insert_handler.await.expect("Task panicked") {
+                handle_device_error(&sd, &e).await;
             }
         } else {
             debug!(
@@ -55,7 +87,65 @@ pub async fn monitor_atmosphere(
     }
 }
 
-async fn handle_device_error(e: &AtmosError) {
+// Runs one round of the actuator handlers against `driver` and collects the errors they
+// report. Shared by the live loop and the dry-run shadow controller.
+pub async fn run_control_handlers(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    driver: &Arc<dyn RelayDriver>,
+    clock: &Arc<dyn Clock>,
+    events: &EventLog,
+    now: OffsetDateTime,
+) -> Vec<AtmosError> {
+    let handlers: Vec<JoinHandle<Result<(), AtmosError>>> = vec![
+        tokio::spawn(handle_fridge(
+            sd.clone(),
+            now,
+            settings.clone(),
+            driver.clone(),
+            events.clone(),
+        )),
+        tokio::spawn(handle_dehumidifier(
+            sd.clone(),
+            now,
+            settings.clone(),
+            driver.clone(),
+            events.clone(),
+        )),
+        tokio::spawn(handle_humidifier(
+            sd.clone(),
+            now,
+            settings.clone(),
+            driver.clone(),
+            clock.clone(),
+            events.clone(),
+        )),
+        tokio::spawn(handle_ventilator(
+            sd.clone(),
+            now,
+            settings.clone(),
+            driver.clone(),
+            clock.clone(),
+            events.clone(),
+        )),
+    ];
+
+    let mut errors = Vec::new();
+    for handler in handlers {
+        if let Err(e) = handler.await.expect("Task panicked") {
+            errors.push(e);
+        }
+    }
+    // An override ends once automatic control switched the actuator
+    sd.update(|state| {
+        let mut overrides = std::mem::take(&mut state.manual_overrides);
+        overrides.retain(|actuator, status| state.actuator_status(*actuator) == *status);
+        state.manual_overrides = overrides;
+    });
+    errors
+}
+
+async fn handle_device_error(sd: &AccessSharedData, e: &AtmosError) {
     let (alert_type, details) = match e {
         AtmosError::FridgeError(details) => ("Fridge Error", details),
         AtmosError::DehumidifierError(details) => ("Dehumidifier Error", details),
@@ -66,6 +156,27 @@ async fn handle_device_error(e: &AtmosError) {
         _ => ("Unexpected Error", &format!("{:?}", e)),
     };
 
+    raise_alert(sd, alert_type, details);
+}
+
+// Reports a condition that needs the operator's attention. Alerts are only logged while
+// maintenance is active, since the chamber is expected to be out of range then.
+pub fn raise_alert(sd: &AccessSharedData, alert_type: &str, details: &str) {
+    let suppressed = sd.maintenance_active();
+    sd.publish(BusEvent::AlertRaised {
+        timestamp: OffsetDateTime::now_utc().to_string(),
+        alert_type: alert_type.to_string(),
+        details: details.to_string(),
+        suppressed,
+    });
+    if suppressed {
+        warn!(
+            "{}: {}. Alert suppressed during maintenance.",
+            alert_type, details
+        );
+        return;
+    }
+
     error!(
         "{}: {}. Attempting to continue operation.",
         alert_type, details
@@ -78,6 +189,8 @@ async fn handle_fridge(
     sd: AccessSharedData,
     now: OffsetDateTime,
     settings: Settings,
+    driver: Arc<dyn RelayDriver>,
+    events: EventLog,
 ) -> Result<(), AtmosError> {
     let average_temp = sd.average_temp();
     if settings.temperature.high_range().contains(&average_temp) {
@@ -87,52 +200,176 @@ async fn handle_fridge(
             && settings.temperature.high_range().contains(&average_temp)
         {
             info!("fridge_control() -> activating fridge");
-            change_relay_status(settings.relay_pins.fridge, RelayStatus::On)?;
-            sd.set_fridge_status(RelayStatus::On);
-            sd.set_fridge_turn_on_datetime(now);
+            let Some(_relays) = sd.lock_for_control().await else {
+                return Ok(());
+            };
+            driver.change_relay_status(
+                Actuator::Fridge,
+                settings.relay_pins.fridge,
+                RelayStatus::On,
+            )?;
+            events.record(ActuatorEvent::new(
+                &sd,
+                now,
+                Actuator::Fridge,
+                sd.fridge_status(),
+                RelayStatus::On,
+                EventSource::Auto,
+                format!("temperature {:.1} in high range", average_temp),
+            ));
+            sd.update(|state| state.record_switch(Actuator::Fridge, RelayStatus::On, now));
         } else {
             info!("fridge_control() -> activation prevented due to cooldown period");
         }
-    } else if settings.temperature.ideal_range().contains(&average_temp)
-        || settings.temperature.low_range().contains(&average_temp)
+    } else if (settings.temperature.ideal_range().contains(&average_temp)
+        || settings.temperature.low_range().contains(&average_temp))
+        && sd.fridge_status() == RelayStatus::On
     {
-        if sd.fridge_status() == RelayStatus::On {
-            info!("fridge_control() -> deactivating fridge");
-            change_relay_status(settings.relay_pins.fridge, RelayStatus::Off)?;
-            sd.set_fridge_status(RelayStatus::Off);
-            sd.set_fridge_turn_off_datetime(now);
-        }
+        info!("fridge_control() -> deactivating fridge");
+        let Some(_relays) = sd.lock_for_control().await else {
+            return Ok(());
+        };
+        driver.change_relay_status(
+            Actuator::Fridge,
+            settings.relay_pins.fridge,
+            RelayStatus::Off,
+        )?;
+        events.record(ActuatorEvent::new(
+            &sd,
+            now,
+            Actuator::Fridge,
+            RelayStatus::On,
+            RelayStatus::Off,
+            EventSource::Auto,
+            format!("temperature {:.1} out of high range", average_temp),
+        ));
+        sd.update(|state| state.record_switch(Actuator::Fridge, RelayStatus::Off, now));
     }
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HumidityDemand {
+    Humidify,
+    Hold,
+    Dehumidify,
+}
+
+// Decides whether moisture should be added or removed, either from the average relative
+// humidity or from the average VPD depending on `humidity.control_target`.
+pub fn humidity_demand(sd: &AccessSharedData, settings: &Settings) -> HumidityDemand {
+    match settings.humidity.control_target {
+        HumidityControlTarget::RelativeHumidity => {
+            let average_humidity = sd.average_humidity();
+            if settings.humidity.low_range().contains(&average_humidity) {
+                HumidityDemand::Humidify
+            } else if settings.humidity.high_range().contains(&average_humidity) {
+                HumidityDemand::Dehumidify
+            } else {
+                HumidityDemand::Hold
+            }
+        }
+        HumidityControlTarget::Vpd => {
+            let average_vpd = sd.average_derived_metrics().vpd;
+            if settings.vpd.high_range().contains(&average_vpd) {
+                HumidityDemand::Humidify
+            } else if settings.vpd.low_range().contains(&average_vpd) {
+                HumidityDemand::Dehumidify
+            } else {
+                HumidityDemand::Hold
+            }
+        }
+    }
+}
+
+// Explains a humidity decision in terms of the configured control target,
+// e.g. "humidity 86.2 in high range"
+fn humidity_reason(sd: &AccessSharedData, settings: &Settings) -> String {
+    let (metric, value, ranges) = match settings.humidity.control_target {
+        HumidityControlTarget::RelativeHumidity => (
+            "humidity",
+            sd.average_humidity(),
+            [
+                ("low", settings.humidity.low_range()),
+                ("ideal", settings.humidity.ideal_range()),
+                ("high", settings.humidity.high_range()),
+            ],
+        ),
+        HumidityControlTarget::Vpd => (
+            "vpd",
+            sd.average_derived_metrics().vpd,
+            [
+                ("low", settings.vpd.low_range()),
+                ("ideal", settings.vpd.ideal_range()),
+                ("high", settings.vpd.high_range()),
+            ],
+        ),
+    };
+    let range = ranges
+        .iter()
+        .find(|(_, range)| range.contains(&value))
+        .map_or("no configured", |(name, _)| *name);
+    format!("{} {:.2} in {} range", metric, value, range)
+}
+
 async fn handle_dehumidifier(
     sd: AccessSharedData,
     now: OffsetDateTime,
     settings: Settings,
+    driver: Arc<dyn RelayDriver>,
+    events: EventLog,
 ) -> Result<(), AtmosError> {
-    let average_humidity = sd.average_humidity();
+    let humidity_demand = humidity_demand(&sd, &settings);
     let time_since_last_activation = now - sd.dehumidifier_turn_off_datetime();
     if time_since_last_activation
         >= Duration::from_secs(settings.humidity.dehumidifier_cooldown_duration)
-        && settings.humidity.high_range().contains(&average_humidity)
+        && humidity_demand == HumidityDemand::Dehumidify
     {
         info!("dehumidifier_control() -> activating dehumidifier");
-        change_relay_status(settings.relay_pins.dehumidifier, RelayStatus::On)?;
-        sd.set_dehumidifier_status(RelayStatus::On);
-        sd.set_dehumidifier_turn_on_datetime(now);
+        let Some(_relays) = sd.lock_for_control().await else {
+            return Ok(());
+        };
+        driver.change_relay_status(
+            Actuator::Dehumidifier,
+            settings.relay_pins.dehumidifier,
+            RelayStatus::On,
+        )?;
+        events.record(ActuatorEvent::new(
+            &sd,
+            now,
+            Actuator::Dehumidifier,
+            sd.dehumidifier_status(),
+            RelayStatus::On,
+            EventSource::Auto,
+            humidity_reason(&sd, &settings),
+        ));
+        sd.update(|state| state.record_switch(Actuator::Dehumidifier, RelayStatus::On, now));
     } else {
         info!(
             "dehumidifier_control() -> activation prevented due to cooldown period or humidity level"
         );
     }
-    if !settings.humidity.high_range().contains(&average_humidity) {
-        if sd.dehumidifier_status() == RelayStatus::On {
-            info!("dehumidifier_control() -> deactivating dehumidifier");
-            change_relay_status(settings.relay_pins.dehumidifier, RelayStatus::Off)?;
-            sd.set_dehumidifier_status(RelayStatus::Off);
-            sd.set_dehumidifier_turn_off_datetime(now);
-        }
+    if humidity_demand != HumidityDemand::Dehumidify && sd.dehumidifier_status() == RelayStatus::On
+    {
+        info!("dehumidifier_control() -> deactivating dehumidifier");
+        let Some(_relays) = sd.lock_for_control().await else {
+            return Ok(());
+        };
+        driver.change_relay_status(
+            Actuator::Dehumidifier,
+            settings.relay_pins.dehumidifier,
+            RelayStatus::Off,
+        )?;
+        events.record(ActuatorEvent::new(
+            &sd,
+            now,
+            Actuator::Dehumidifier,
+            RelayStatus::On,
+            RelayStatus::Off,
+            EventSource::Auto,
+            humidity_reason(&sd, &settings),
+        ));
+        sd.update(|state| state.record_switch(Actuator::Dehumidifier, RelayStatus::Off, now));
     }
     Ok(())
 }
@@ -141,9 +378,11 @@ async fn handle_humidifier(
     sd: AccessSharedData,
     now: OffsetDateTime,
     settings: Settings,
+    driver: Arc<dyn RelayDriver>,
+    clock: Arc<dyn Clock>,
+    events: EventLog,
 ) -> Result<(), AtmosError> {
-    let average_humidity = sd.average_humidity();
-    if settings.humidity.low_range().contains(&average_humidity) {
+    if humidity_demand(&sd, &settings) == HumidityDemand::Humidify {
         let time_since_last_activation = now - sd.humidifier_turn_off_datetime();
         if time_since_last_activation
             >= Duration::from_secs(settings.humidity.humidifier_cooldown_duration)
@@ -152,13 +391,53 @@ async fn handle_humidifier(
                 "humidifier_control() -> activating humidifier for {} second(s)",
                 settings.humidity.humidifier_activation_duration
             );
-            change_relay_status(settings.relay_pins.humidifier, RelayStatus::On)?;
-            tokio::time::sleep(Duration::from_secs(
-                settings.humidity.humidifier_activation_duration,
-            ))
-            .await;
-            change_relay_status(settings.relay_pins.humidifier, RelayStatus::Off)?;
-            sd.set_humidifier_turn_off_datetime(now);
+            {
+                let Some(_relays) = sd.lock_for_control().await else {
+                    return Ok(());
+                };
+                driver.change_relay_status(
+                    Actuator::Humidifier,
+                    settings.relay_pins.humidifier,
+                    RelayStatus::On,
+                )?;
+                events.record(ActuatorEvent::new(
+                    &sd,
+                    now,
+                    Actuator::Humidifier,
+                    RelayStatus::Off,
+                    RelayStatus::On,
+                    EventSource::Auto,
+                    humidity_reason(&sd, &settings),
+                ));
+                sd.update(|state| state.record_switch(Actuator::Humidifier, RelayStatus::On, now));
+            }
+            clock
+                .sleep(Duration::from_secs(
+                    settings.humidity.humidifier_activation_duration,
+                ))
+                .await;
+            // Maintenance mode switched it off already if it started during the pulse
+            let Some(_relays) = sd.lock_for_control().await else {
+                return Ok(());
+            };
+            driver.change_relay_status(
+                Actuator::Humidifier,
+                settings.relay_pins.humidifier,
+                RelayStatus::Off,
+            )?;
+            events.record(ActuatorEvent::new(
+                &sd,
+                clock.now(),
+                Actuator::Humidifier,
+                RelayStatus::On,
+                RelayStatus::Off,
+                EventSource::Auto,
+                format!(
+                    "activation of {} s finished",
+                    settings.humidity.humidifier_activation_duration
+                ),
+            ));
+            sd.update(|state| state.record_switch(Actuator::Humidifier, RelayStatus::Off, now));
         } else {
             info!("humidifier_control() -> activation prevented due to cooldown period");
         }
@@ -170,6 +449,9 @@ async fn handle_ventilator(
     sd: AccessSharedData,
     now: OffsetDateTime,
     settings: Settings,
+    driver: Arc<dyn RelayDriver>,
+    clock: Arc<dyn Clock>,
+    events: EventLog,
 ) -> Result<(), AtmosError> {
     if sd.ventilator_status() == RelayStatus::Off {
         let time_since_last_activation = now - sd.ventilator_turn_off_datetime();
@@ -178,16 +460,51 @@ async fn handle_ventilator(
                 "ventilator_control() -> activating ventilator for {} second(s)",
                 settings.ventilation.duration
             );
-            change_relay_status(settings.relay_pins.ventilator_or_heater, RelayStatus::On)?;
-            sd.set_ventilator_status(RelayStatus::On);
-            sd.set_ventilator_turn_on_datetime(now);
+            {
+                let Some(_relays) = sd.lock_for_control().await else {
+                    return Ok(());
+                };
+                driver.change_relay_status(
+                    Actuator::Ventilator,
+                    settings.relay_pins.ventilator_or_heater,
+                    RelayStatus::On,
+                )?;
+                events.record(ActuatorEvent::new(
+                    &sd,
+                    now,
+                    Actuator::Ventilator,
+                    RelayStatus::Off,
+                    RelayStatus::On,
+                    EventSource::Auto,
+                    format!("interval of {} s elapsed", settings.ventilation.interval),
+                ));
+                sd.update(|state| state.record_switch(Actuator::Ventilator, RelayStatus::On, now));
+            }
 
-            tokio::time::sleep(Duration::from_secs(settings.ventilation.duration)).await;
+            clock
+                .sleep(Duration::from_secs(settings.ventilation.duration))
+                .await;
 
+            // Maintenance mode owns the ventilator if it started in the meantime
+            let Some(_relays) = sd.lock_for_control().await else {
+                return Ok(());
+            };
             info!("ventilator_control() -> deactivating ventilator");
-            change_relay_status(settings.relay_pins.ventilator_or_heater, RelayStatus::Off)?;
-            sd.set_ventilator_status(RelayStatus::Off);
-            sd.set_ventilator_turn_off_datetime(now);
+            driver.change_relay_status(
+                Actuator::Ventilator,
+                settings.relay_pins.ventilator_or_heater,
+                RelayStatus::Off,
+            )?;
+            events.record(ActuatorEvent::new(
+                &sd,
+                clock.now(),
+                Actuator::Ventilator,
+                RelayStatus::On,
+                RelayStatus::Off,
+                EventSource::Auto,
+                format!("ran for {} s", settings.ventilation.duration),
+            ));
+            sd.update(|state| state.record_switch(Actuator::Ventilator, RelayStatus::Off, now));
         } else {
             info!("ventilator_control() -> activation prevented due to interval period");
         }
@@ -195,71 +512,123 @@ async fn handle_ventilator(
     Ok(())
 }
 
-fn update_average_values(sd: &AccessSharedData) {
-    sd.set_average_temp((sd.temp_one() + sd.temp_two()) / 2.0);
-    sd.set_average_humidity((sd.humidity_one() + sd.humidity_two()) / 2.0);
+pub fn update_average_values(sd: &AccessSharedData) {
+    sd.update(|state| {
+        state.average_temp = (state.temp_1 + state.temp_2) / 2.0;
+        state.average_humidity = (state.humidity_1 + state.humidity_2) / 2.0;
+    });
+}
+
+pub fn update_derived_metrics(sd: &AccessSharedData) {
+    sd.update(|state| {
+        state.derived_metrics_1 = DerivedMetrics::from_reading(state.temp_1, state.humidity_1);
+        state.derived_metrics_2 = DerivedMetrics::from_reading(state.temp_2, state.humidity_2);
+        state.average_derived_metrics =
+            DerivedMetrics::average(&state.derived_metrics_1, &state.derived_metrics_2);
+    });
 }
 
-fn update_atmosphere_quality_index(sd: &AccessSharedData, settings: &Settings) {
-    let temp_in_range = settings
-        .temperature
-        .ideal_range()
-        .contains(&sd.average_temp());
-    let humidity_in_range = settings
-        .humidity
-        .ideal_range()
-        .contains(&sd.average_humidity());
-
-    sd.set_atmosphere_quality_index(if temp_in_range && humidity_in_range {
-        100.0
-    } else {
-        0.0
+fn update_atmosphere_quality_index(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    now: OffsetDateTime,
+) {
+    sd.update(|state| {
+        let input = QualityIndexInput {
+            temp_1: state.temp_1,
+            humidity_1: state.humidity_1,
+            temp_2: state.temp_2,
+            humidity_2: state.humidity_2,
+            average_temp: state.average_temp,
+            average_humidity: state.average_humidity,
+            last_reading_time: state.last_reading_time,
+        };
+        let components = evaluate_components(&input, &mut state.quality_index_state, settings, now);
+        state.quality_index_components = components;
+        state.atmospheric_quality_index = quality_index(&components, &settings.quality_index);
     });
 }
 
 fn log_atmosphere_data(sd: &AccessSharedData) {
+    let state = sd.snapshot();
     info!(
         "Current atmosphere - Temp: {:.2}°C, Humidity: {:.2}%, Quality Index: {:.2}",
-        sd.average_temp(),
-        sd.average_humidity(),
-        sd.atmosphere_quality_index()
+        state.average_temp, state.average_humidity, state.atmospheric_quality_index
+    );
+    info!(
+        "Derived - Dew point: {:.2}°C, Absolute humidity: {:.2}g/m³, VPD: {:.3}kPa",
+        state.average_derived_metrics.dew_point,
+        state.average_derived_metrics.absolute_humidity,
+        state.average_derived_metrics.vpd
     );
     info!(
         "Detailed readings - Temp1: {:.2}°C, Humidity1: {:.2}%, Temp2: {:.2}°C, Humidity2: {:.2}%",
-        sd.temp_one(),
-        sd.humidity_one(),
-        sd.temp_two(),
-        sd.humidity_two()
+        state.temp_1, state.humidity_1, state.temp_2, state.humidity_2
     );
 }
 
 async fn insert_atmosphere_data(
     sd: AccessSharedData,
     sqlite_client: Arc<SqliteClient>,
+    now: OffsetDateTime,
 ) -> Result<(), AtmosError> {
-    let now = OffsetDateTime::now_utc();
-    sqlite_client.insert_atmosphere_data(
-        now,
-        sd.average_temp(),
-        sd.average_humidity(),
-        sd.fridge_status(),
-        sd.dehumidifier_status(),
-        sd.humidifier_status(),
-        sd.ventilator_status(),
-    )?;
-    Ok(())
+    // One snapshot taken before moving to the blocking pool, so the row matches the tick
+    // that produced it
+    let state = sd.snapshot();
+    let row = AtmosphereRow {
+        timestamp: now,
+        average_temperature: state.average_temp,
+        average_humidity: state.average_humidity,
+        derived_metrics: state.average_derived_metrics,
+        atmospheric_quality_index: state.atmospheric_quality_index,
+        hanging_weight: state.hanging_weight,
+        fridge_status: state.fridge_status,
+        dehumidifier_status: state.dehumidifier_status,
+        humidifier_status: state.humidifier_status,
+        ventilator_status: state.ventilator_status,
+        heater_status: state.heater_status,
+        sensor_readings: sensor_readings(&state),
+    };
+    sqlite_client
+        .call(move |db| db.buffer_write(BufferedWrite::Atmosphere(row)))
+        .await
+}
+
+fn sensor_readings(state: &AtmosphereState) -> Vec<SensorReading> {
+    [
+        (1, state.temp_1, state.humidity_1, state.derived_metrics_1),
+        (2, state.temp_2, state.humidity_2, state.derived_metrics_2),
+    ]
+    .into_iter()
+    .flat_map(|(sensor_id, temperature, humidity, derived)| {
+        [
+            ("temperature", temperature),
+            ("humidity", humidity),
+            ("dew_point", derived.dew_point),
+            ("absolute_humidity", derived.absolute_humidity),
+            ("vapour_pressure_deficit", derived.vpd),
+        ]
+        .map(|(metric, value)| SensorReading {
+            sensor_id,
+            metric,
+            value,
+        })
+    })
+    .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::mock_relay_ctrl::get_mock_relay_status;
-    use crate::{config::Settings, shared_data::SharedData};
-    use std::sync::{Arc, Mutex};
+    use crate::clock::SystemClock;
+    use crate::config::test_settings;
+    use crate::maintenance::{start_maintenance, MaintenanceTrigger};
+    use crate::mock_relay_ctrl::MockRelayDriver;
+    use std::sync::Arc;
     use time::macros::offset;
 
     fn create_test_shared_data() -> AccessSharedData {
-        let test_data = SharedData::new(
+        let test_data = AtmosphereState::new(
             0,
             13.0,
             80.0,
@@ -286,70 +655,87 @@ mod tests {
             OffsetDateTime::UNIX_EPOCH.to_offset(offset!(+1)),
         );
 
-        let shared_data = AccessSharedData {
-            sd: Arc::new(Mutex::new(test_data)),
-        };
-        shared_data
+        AccessSharedData::new(test_data)
     }
 
     #[tokio::test]
     async fn test_handle_fridge() {
         let sd = create_test_shared_data();
-        let mut settings = Settings::new().unwrap();
-        settings.temperature.high_range().start = 25.0;
-        settings.temperature.high_range().end = 30.0;
-        settings.temperature.ideal_range().start = 20.0;
-        settings.temperature.ideal_range().end = 25.0;
-        settings.temperature.low_range().start = 15.0;
-        settings.temperature.low_range().end = 20.0;
+        let driver = MockRelayDriver::default();
+        let mut settings = test_settings();
+        settings.temperature.high_range_start = 25.0;
+        settings.temperature.high_range_end = 30.0;
+        settings.temperature.ideal_range_start = 20.0;
+        settings.temperature.ideal_range_end = 25.0;
+        settings.temperature.low_range_start = 15.0;
+        settings.temperature.low_range_end = 20.0;
         settings.relay_pins.fridge = 1;
 
         // Test when temperature is in high range
-        sd.set_average_temp(26.0);
-        handle_fridge(sd.clone(), OffsetDateTime::now_utc(), settings.clone())
-            .await
-            .unwrap();
-        assert_eq!(
-            get_mock_relay_status(settings.relay_pins.fridge),
-            RelayStatus::On
-        );
+        sd.update(|state| state.average_temp = 26.0);
+        handle_fridge(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            EventLog::disabled(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(driver.status(settings.relay_pins.fridge), RelayStatus::On);
 
         // Test when temperature is in ideal range
-        sd.set_average_temp(22.0);
-        handle_fridge(sd.clone(), OffsetDateTime::now_utc(), settings.clone())
-            .await
-            .unwrap();
-        assert_eq!(
-            get_mock_relay_status(settings.relay_pins.fridge),
-            RelayStatus::Off
-        );
+        sd.update(|state| state.average_temp = 22.0);
+        handle_fridge(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            EventLog::disabled(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(driver.status(settings.relay_pins.fridge), RelayStatus::Off);
     }
 
     #[tokio::test]
     async fn test_handle_dehumidifier() {
         let sd = create_test_shared_data();
-        let mut settings = Settings::new().unwrap();
-        settings.humidity.high_range().start = 60.0;
-        settings.humidity.high_range().end = 100.0;
+        let driver = MockRelayDriver::default();
+        let mut settings = test_settings();
+        settings.humidity.high_range_start = 60.0;
+        settings.humidity.high_range_end = 100.0;
         settings.relay_pins.dehumidifier = 2;
 
         // Test when humidity is in high range
-        sd.set_average_humidity(70.0);
-        handle_dehumidifier(sd.clone(), OffsetDateTime::now_utc(), settings.clone())
-            .await
-            .unwrap();
+        sd.update(|state| state.average_humidity = 70.0);
+        handle_dehumidifier(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            EventLog::disabled(),
+        )
+        .await
+        .unwrap();
         assert_eq!(
-            get_mock_relay_status(settings.relay_pins.dehumidifier),
+            driver.status(settings.relay_pins.dehumidifier),
             RelayStatus::On
         );
 
         // Test when humidity is not in high range
-        sd.set_average_humidity(50.0);
-        handle_dehumidifier(sd.clone(), OffsetDateTime::now_utc(), settings.clone())
-            .await
-            .unwrap();
+        sd.update(|state| state.average_humidity = 50.0);
+        handle_dehumidifier(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            EventLog::disabled(),
+        )
+        .await
+        .unwrap();
         assert_eq!(
-            get_mock_relay_status(settings.relay_pins.dehumidifier),
+            driver.status(settings.relay_pins.dehumidifier),
             RelayStatus::Off
         );
     }
@@ -357,41 +743,126 @@ mod tests {
     #[tokio::test]
     async fn test_handle_humidifier() {
         let sd = create_test_shared_data();
-        let mut settings = Settings::new().unwrap();
-        settings.humidity.low_range().start = 0.0;
-        settings.humidity.low_range().end = 40.0;
+        let driver = MockRelayDriver::default();
+        let mut settings = test_settings();
+        settings.humidity.low_range_start = 0.0;
+        settings.humidity.low_range_end = 40.0;
         settings.humidity.humidifier_cooldown_duration = 0;
         settings.humidity.humidifier_activation_duration = 1;
         settings.relay_pins.humidifier = 3;
 
         // Test when humidity is in low range
-        sd.set_average_humidity(30.0);
-        handle_humidifier(sd.clone(), OffsetDateTime::now_utc(), settings.clone())
-            .await
-            .unwrap();
+        sd.update(|state| state.average_humidity = 30.0);
+        handle_humidifier(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            Arc::new(SystemClock),
+            EventLog::disabled(),
+        )
+        .await
+        .unwrap();
         assert_eq!(
-            get_mock_relay_status(settings.relay_pins.humidifier),
+            driver.status(settings.relay_pins.humidifier),
             RelayStatus::Off
         );
 
         // Test when humidity is not in low range
-        sd.set_average_humidity(50.0);
-        handle_humidifier(sd.clone(), OffsetDateTime::now_utc(), settings.clone())
-            .await
-            .unwrap();
+        sd.update(|state| state.average_humidity = 50.0);
+        handle_humidifier(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            Arc::new(SystemClock),
+            EventLog::disabled(),
+        )
+        .await
+        .unwrap();
         assert_eq!(
-            get_mock_relay_status(settings.relay_pins.humidifier),
+            driver.status(settings.relay_pins.humidifier),
             RelayStatus::Off
         );
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_humidifier_shows_on_during_its_pulse() {
+        let sd = create_test_shared_data();
+        let driver = MockRelayDriver::default();
+        let mut settings = test_settings();
+        settings.humidity.low_range_start = 0.0;
+        settings.humidity.low_range_end = 40.0;
+        settings.humidity.humidifier_cooldown_duration = 0;
+        settings.humidity.humidifier_activation_duration = 10;
+        sd.update(|state| state.average_humidity = 30.0);
+
+        let now = OffsetDateTime::now_utc();
+        let pulse = tokio::spawn(handle_humidifier(
+            sd.clone(),
+            now,
+            settings.clone(),
+            Arc::new(driver.clone()),
+            Arc::new(SystemClock),
+            EventLog::disabled(),
+        ));
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(sd.humidifier_status(), RelayStatus::On);
+        assert_eq!(sd.humidifier_turn_on_datetime(), now);
+
+        pulse.await.unwrap().unwrap();
+        assert_eq!(sd.humidifier_status(), RelayStatus::Off);
+        assert_eq!(sd.humidifier_turn_off_datetime(), now);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_maintenance_started_during_ventilation_keeps_the_fan() {
+        let sd = create_test_shared_data();
+        let driver = MockRelayDriver::default();
+        let mut settings = test_settings();
+        settings.ventilation.interval = 0;
+        settings.ventilation.duration = 60;
+        settings.maintenance.run_fan = true;
+        let pin = settings.relay_pins.ventilator_or_heater;
+
+        let cycle = tokio::spawn(handle_ventilator(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            Arc::new(SystemClock),
+            EventLog::disabled(),
+        ));
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(driver.status(pin), RelayStatus::On);
+
+        let maintenance_driver: Arc<dyn RelayDriver> = Arc::new(driver.clone());
+        start_maintenance(
+            &sd,
+            &settings,
+            &Arc::new(SqliteClient::new(":memory:").unwrap()),
+            &maintenance_driver,
+            &EventLog::disabled(),
+            MaintenanceTrigger::Api,
+        )
+        .await
+        .unwrap();
+        cycle.await.unwrap().unwrap();
+
+        // The cycle ended without switching off the fan maintenance mode runs
+        assert_eq!(driver.status(pin), RelayStatus::On);
+        assert_eq!(sd.ventilator_status(), RelayStatus::On);
+    }
+
     #[test]
     fn test_update_average_values() {
         let sd = create_test_shared_data();
-        sd.set_temp_one(20.0);
-        sd.set_temp_two(22.0);
-        sd.set_humidity_one(50.0);
-        sd.set_humidity_two(52.0);
+        sd.update(|state| {
+            state.temp_1 = 20.0;
+            state.temp_2 = 22.0;
+            state.humidity_1 = 50.0;
+            state.humidity_2 = 52.0;
+        });
 
         update_average_values(&sd);
 
@@ -402,47 +873,99 @@ mod tests {
     #[test]
     fn test_update_atmosphere_quality_index() {
         let sd = create_test_shared_data();
-        let settings = Settings::new().unwrap();
-        settings.temperature.ideal_range().start = 20.0;
-        settings.temperature.ideal_range().end = 25.0;
-        settings.humidity.ideal_range().start = 40.0;
-        settings.humidity.ideal_range().end = 60.0;
+        let mut settings = test_settings();
+        settings.temperature.ideal_range_start = 20.0;
+        settings.temperature.ideal_range_end = 25.0;
+        settings.humidity.ideal_range_start = 40.0;
+        settings.humidity.ideal_range_end = 60.0;
+
+        let now = OffsetDateTime::now_utc();
+        sd.update(|state| state.last_reading_time = now);
 
         // Test when both temperature and humidity are in ideal range
-        sd.set_average_temp(22.0);
-        sd.set_average_humidity(50.0);
-        update_atmosphere_quality_index(&sd, &settings);
+        sd.update(|state| {
+            state.average_temp = 22.0;
+            state.average_humidity = 50.0;
+        });
+        update_atmosphere_quality_index(&sd, &settings, now);
         assert_eq!(sd.atmosphere_quality_index(), 100.0);
 
-        // Test when either temperature or humidity is out of ideal range
-        sd.set_average_temp(26.0);
-        update_atmosphere_quality_index(&sd, &settings);
-        assert_eq!(sd.atmosphere_quality_index(), 0.0);
+        // Test that leaving the ideal range lowers the index without zeroing it
+        sd.update(|state| state.average_temp = 26.0);
+        update_atmosphere_quality_index(&sd, &settings, now + Duration::from_secs(600));
+        assert!(sd.atmosphere_quality_index() > 0.0);
+        assert!(sd.atmosphere_quality_index() < 100.0);
+    }
+
+    #[test]
+    fn test_humidity_demand_follows_vpd() {
+        let sd = create_test_shared_data();
+        let mut settings = test_settings();
+        settings.humidity.control_target = HumidityControlTarget::Vpd;
+
+        // 13 °C at 90 %RH leaves a VPD of about 0.15 kPa, below the ideal band
+        sd.update(|state| {
+            state.temp_1 = 13.0;
+            state.temp_2 = 13.0;
+            state.humidity_1 = 90.0;
+            state.humidity_2 = 90.0;
+        });
+        update_derived_metrics(&sd);
+        assert_eq!(humidity_demand(&sd, &settings), HumidityDemand::Dehumidify);
+
+        sd.update(|state| {
+            state.humidity_1 = 50.0;
+            state.humidity_2 = 50.0;
+        });
+        update_derived_metrics(&sd);
+        assert_eq!(humidity_demand(&sd, &settings), HumidityDemand::Humidify);
+
+        sd.update(|state| {
+            state.humidity_1 = 70.0;
+            state.humidity_2 = 70.0;
+        });
+        update_derived_metrics(&sd);
+        assert_eq!(humidity_demand(&sd, &settings), HumidityDemand::Hold);
     }
 
     #[tokio::test]
     async fn test_handle_ventilator() {
         let sd = create_test_shared_data();
-        let mut settings = Settings::new().unwrap();
+        let driver = MockRelayDriver::default();
+        let mut settings = test_settings();
         settings.ventilation.interval = 0;
         settings.ventilation.duration = 1;
         settings.relay_pins.ventilator_or_heater = 4;
 
         // Test ventilator activation
-        handle_ventilator(sd.clone(), OffsetDateTime::now_utc(), settings.clone())
-            .await
-            .unwrap();
+        handle_ventilator(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            Arc::new(SystemClock),
+            EventLog::disabled(),
+        )
+        .await
+        .unwrap();
         assert_eq!(
-            get_mock_relay_status(settings.relay_pins.ventilator_or_heater),
+            driver.status(settings.relay_pins.ventilator_or_heater),
             RelayStatus::Off
         );
         assert_eq!(sd.ventilator_status(), RelayStatus::Off);
 
         // Test ventilator not activating due to being already on
-        sd.set_ventilator_status(RelayStatus::On);
-        handle_ventilator(sd.clone(), OffsetDateTime::now_utc(), settings.clone())
-            .await
-            .unwrap();
+        sd.update(|state| state.ventilator_status = RelayStatus::On);
+        handle_ventilator(
+            sd.clone(),
+            OffsetDateTime::now_utc(),
+            settings.clone(),
+            Arc::new(driver.clone()),
+            Arc::new(SystemClock),
+            EventLog::disabled(),
+        )
+        .await
+        .unwrap();
         assert_eq!(sd.ventilator_status(), RelayStatus::On);
     }
 }
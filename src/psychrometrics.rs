@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+// Magnus formula coefficients over water (Alduchov & Eskridge, 1996)
+const MAGNUS_A: f32 = 0.61094;
+const MAGNUS_B: f32 = 17.625;
+const MAGNUS_C: f32 = 243.04;
+/// Specific gas constant of water vapour (J/(kg·K))
+const WATER_VAPOUR_GAS_CONSTANT: f32 = 461.5;
+
+/// Saturation vapour pressure (kPa) at `temperature` (°C)
+pub fn saturation_vapour_pressure(temperature: f32) -> f32 {
+    MAGNUS_A * (MAGNUS_B * temperature / (temperature + MAGNUS_C)).exp()
+}
+
+/// Actual vapour pressure (kPa) at `temperature` (°C) and `humidity` (%RH)
+pub fn vapour_pressure(temperature: f32, humidity: f32) -> f32 {
+    saturation_vapour_pressure(temperature) * humidity.clamp(0.0, 100.0) / 100.0
+}
+
+/// Temperature (°C) at which the air would start condensing on a surface
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    // ln(0) is undefined, bone dry air is treated as 0.1 %RH
+    let gamma = (humidity.clamp(0.1, 100.0) / 100.0).ln()
+        + MAGNUS_B * temperature / (MAGNUS_C + temperature);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+/// Mass of water vapour per volume of air (g/m³)
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let vapour_pressure_pa = vapour_pressure(temperature, humidity) * 1000.0;
+    vapour_pressure_pa / (WATER_VAPOUR_GAS_CONSTANT * (temperature + 273.15)) * 1000.0
+}
+
+/// Vapour pressure deficit (kPa): how much more moisture the air could take up
+pub fn vapour_pressure_deficit(temperature: f32, humidity: f32) -> f32 {
+    saturation_vapour_pressure(temperature) - vapour_pressure(temperature, humidity)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DerivedMetrics {
+    /// Dew point (in Celsius)
+    pub dew_point: f32,
+    /// Absolute humidity (in g/m³)
+    pub absolute_humidity: f32,
+    /// Vapour pressure deficit (in kPa)
+    pub vpd: f32,
+}
+
+impl DerivedMetrics {
+    pub fn from_reading(temperature: f32, humidity: f32) -> Self {
+        DerivedMetrics {
+            dew_point: dew_point(temperature, humidity),
+            absolute_humidity: absolute_humidity(temperature, humidity),
+            vpd: vapour_pressure_deficit(temperature, humidity),
+        }
+    }
+
+    pub fn average(a: &DerivedMetrics, b: &DerivedMetrics) -> Self {
+        DerivedMetrics {
+            dew_point: (a.dew_point + b.dew_point) / 2.0,
+            absolute_humidity: (a.absolute_humidity + b.absolute_humidity) / 2.0,
+            vpd: (a.vpd + b.vpd) / 2.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dew_point() {
+        assert!((dew_point(20.0, 100.0) - 20.0).abs() < 0.01);
+        assert!((dew_point(13.0, 80.0) - 9.63).abs() < 0.05);
+        assert!(dew_point(13.0, 0.0).is_finite());
+    }
+
+    #[test]
+    fn test_absolute_humidity() {
+        // 20 °C saturated air holds about 17.3 g/m³
+        assert!((absolute_humidity(20.0, 100.0) - 17.3).abs() < 0.1);
+        assert_eq!(absolute_humidity(20.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_vapour_pressure_deficit() {
+        assert!((saturation_vapour_pressure(20.0) - 2.338).abs() < 0.01);
+        assert_eq!(vapour_pressure_deficit(13.0, 100.0), 0.0);
+        assert!((vapour_pressure_deficit(13.0, 80.0) - 0.299).abs() < 0.005);
+    }
+}
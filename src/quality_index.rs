@@ -0,0 +1,257 @@
+use crate::config::{QualityIndexSettings, Settings};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use time::{Duration, OffsetDateTime};
+
+// What the index needs to remember between ticks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QualityIndexState {
+    /// Since when the averages have been outside the ideal ranges
+    pub out_of_range_since: Option<OffsetDateTime>,
+    /// Time and averages the rate of change is measured against, at least `rate_window`
+    /// old once it is used
+    pub rate_reference: Option<(OffsetDateTime, f32, f32)>,
+    /// Takes over as the reference once it is `rate_window` old itself
+    pub next_rate_reference: Option<(OffsetDateTime, f32, f32)>,
+}
+
+// Each component scores from 0 (bad) to 1 (good)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct QualityIndexComponents {
+    pub distance: f32,
+    pub time_out_of_range: f32,
+    pub rate_of_change: f32,
+    pub sensor_health: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QualityIndexInput {
+    pub temp_1: f32,
+    pub humidity_1: f32,
+    pub temp_2: f32,
+    pub humidity_2: f32,
+    pub average_temp: f32,
+    pub average_humidity: f32,
+    pub last_reading_time: OffsetDateTime,
+}
+
+fn distance_outside(range: &Range<f32>, value: f32) -> f32 {
+    if value < range.start {
+        range.start - value
+    } else if value > range.end {
+        value - range.end
+    } else {
+        0.0
+    }
+}
+
+// 1 at zero, falling linearly to 0 at `limit`
+fn linear_score(value: f32, limit: f32) -> f32 {
+    if limit <= 0.0 {
+        return if value > 0.0 { 0.0 } else { 1.0 };
+    }
+    (1.0 - value / limit).clamp(0.0, 1.0)
+}
+
+pub fn evaluate_components(
+    input: &QualityIndexInput,
+    state: &mut QualityIndexState,
+    settings: &Settings,
+    now: OffsetDateTime,
+) -> QualityIndexComponents {
+    let qi = &settings.quality_index;
+
+    let temp_distance = distance_outside(&settings.temperature.ideal_range(), input.average_temp);
+    let humidity_distance =
+        distance_outside(&settings.humidity.ideal_range(), input.average_humidity);
+    let distance = (linear_score(temp_distance, qi.temperature_distance_limit)
+        + linear_score(humidity_distance, qi.humidity_distance_limit))
+        / 2.0;
+
+    let time_out_of_range = if temp_distance > 0.0 || humidity_distance > 0.0 {
+        let since = *state.out_of_range_since.get_or_insert(now);
+        linear_score(
+            (now - since).as_seconds_f32(),
+            qi.out_of_range_duration_limit as f32,
+        )
+    } else {
+        state.out_of_range_since = None;
+        1.0
+    };
+
+    // Measured against a sample between one and two windows old, the first window scores 1
+    let sample = (now, input.average_temp, input.average_humidity);
+    let window = Duration::seconds(qi.rate_window as i64);
+    let (reference_time, reference_temp, reference_humidity) =
+        *state.rate_reference.get_or_insert(sample);
+    let rate_of_change = if now > reference_time && now - reference_time >= window {
+        let hours = (now - reference_time).as_seconds_f32() / 3600.0;
+        let temp_rate = (input.average_temp - reference_temp).abs() / hours;
+        let humidity_rate = (input.average_humidity - reference_humidity).abs() / hours;
+        (linear_score(temp_rate, qi.temperature_rate_limit)
+            + linear_score(humidity_rate, qi.humidity_rate_limit))
+            / 2.0
+    } else {
+        1.0
+    };
+    let next_reference = *state.next_rate_reference.get_or_insert(sample);
+    if now - next_reference.0 >= window {
+        state.rate_reference = Some(next_reference);
+        state.next_rate_reference = Some(sample);
+    }
+
+    let sensor_health = sensor_health(input, qi, now);
+
+    QualityIndexComponents {
+        distance,
+        time_out_of_range,
+        rate_of_change,
+        sensor_health,
+    }
+}
+
+fn sensor_health(input: &QualityIndexInput, qi: &QualityIndexSettings, now: OffsetDateTime) -> f32 {
+    let readings = [
+        input.temp_1,
+        input.humidity_1,
+        input.temp_2,
+        input.humidity_2,
+    ];
+    if readings.iter().any(|reading| !reading.is_finite())
+        || !(0.0..=100.0).contains(&input.humidity_1)
+        || !(0.0..=100.0).contains(&input.humidity_2)
+    {
+        return 0.0;
+    }
+    if (now - input.last_reading_time).as_seconds_f32() > qi.stale_reading_after as f32 {
+        return 0.0;
+    }
+    (linear_score(
+        (input.temp_1 - input.temp_2).abs(),
+        qi.temperature_disagreement_limit,
+    ) + linear_score(
+        (input.humidity_1 - input.humidity_2).abs(),
+        qi.humidity_disagreement_limit,
+    )) / 2.0
+}
+
+// Weighted average of the components, scaled to 0..100
+pub fn quality_index(components: &QualityIndexComponents, qi: &QualityIndexSettings) -> f32 {
+    let weights = [
+        (qi.distance_weight, components.distance),
+        (qi.time_out_of_range_weight, components.time_out_of_range),
+        (qi.rate_of_change_weight, components.rate_of_change),
+        (qi.sensor_health_weight, components.sensor_health),
+    ];
+    let total_weight: f32 = weights.iter().map(|(weight, _)| weight.max(0.0)).sum();
+    if total_weight == 0.0 {
+        return 0.0;
+    }
+    let score: f32 = weights
+        .iter()
+        .map(|(weight, component)| weight.max(0.0) * component)
+        .sum();
+    100.0 * score / total_weight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_settings;
+    use time::Duration;
+
+    fn input(average_temp: f32, average_humidity: f32, now: OffsetDateTime) -> QualityIndexInput {
+        QualityIndexInput {
+            temp_1: average_temp,
+            humidity_1: average_humidity,
+            temp_2: average_temp,
+            humidity_2: average_humidity,
+            average_temp,
+            average_humidity,
+            last_reading_time: now,
+        }
+    }
+
+    #[test]
+    fn test_quality_index_is_graded() {
+        let settings = test_settings();
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(1);
+
+        let mut state = QualityIndexState::default();
+        let ideal = evaluate_components(&input(12.5, 70.0, now), &mut state, &settings, now);
+        assert_eq!(quality_index(&ideal, &settings.quality_index), 100.0);
+
+        let mut state = QualityIndexState::default();
+        let slightly_warm =
+            evaluate_components(&input(14.5, 70.0, now), &mut state, &settings, now);
+        let mut state = QualityIndexState::default();
+        let very_warm = evaluate_components(&input(18.0, 70.0, now), &mut state, &settings, now);
+        let slightly_warm = quality_index(&slightly_warm, &settings.quality_index);
+        let very_warm = quality_index(&very_warm, &settings.quality_index);
+        assert!(slightly_warm < 100.0 && slightly_warm > very_warm && very_warm > 0.0);
+    }
+
+    #[test]
+    fn test_time_out_of_range_and_rate_of_change() {
+        let settings = test_settings();
+        let start = OffsetDateTime::UNIX_EPOCH + Duration::days(1);
+        let mut state = QualityIndexState::default();
+
+        evaluate_components(&input(15.0, 70.0, start), &mut state, &settings, start);
+        let later =
+            start + Duration::seconds(settings.quality_index.out_of_range_duration_limit as i64);
+        let components =
+            evaluate_components(&input(15.0, 70.0, later), &mut state, &settings, later);
+        assert_eq!(components.time_out_of_range, 0.0);
+        assert_eq!(components.rate_of_change, 1.0);
+
+        let jump = later + Duration::minutes(10);
+        let components = evaluate_components(&input(12.0, 70.0, jump), &mut state, &settings, jump);
+        assert_eq!(components.time_out_of_range, 1.0);
+        assert!(components.rate_of_change < 1.0);
+    }
+
+    #[test]
+    fn test_rate_of_change_is_measured_over_the_window() {
+        let settings = test_settings();
+        let start = OffsetDateTime::UNIX_EPOCH + Duration::days(1);
+        let tick = Duration::seconds(120);
+
+        // Noise between two ticks would be 9 °C/h, over the window it averages out
+        let mut state = QualityIndexState::default();
+        for i in 0..60 {
+            let now = start + tick * i;
+            let temp = if i % 2 == 0 { 12.5 } else { 12.8 };
+            let components =
+                evaluate_components(&input(temp, 70.0, now), &mut state, &settings, now);
+            assert!(components.rate_of_change > 0.9, "tick {}", i);
+        }
+
+        // A steady temperature drift at the limit zeroes its half once a window has passed
+        let mut state = QualityIndexState::default();
+        let drift_per_tick = settings.quality_index.temperature_rate_limit / 30.0;
+        let mut components = QualityIndexComponents::default();
+        for i in 0..60 {
+            let now = start + tick * i;
+            let temp = 10.0 + drift_per_tick * i as f32;
+            components = evaluate_components(&input(temp, 70.0, now), &mut state, &settings, now);
+        }
+        assert!((components.rate_of_change - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_sensor_health() {
+        let settings = test_settings();
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(1);
+        let mut state = QualityIndexState::default();
+
+        let mut disagreeing = input(12.5, 70.0, now);
+        disagreeing.temp_2 = 12.5 + settings.quality_index.temperature_disagreement_limit;
+        let components = evaluate_components(&disagreeing, &mut state, &settings, now);
+        assert_eq!(components.sensor_health, 0.5);
+
+        let stale = input(12.5, 70.0, now - Duration::hours(1));
+        let components = evaluate_components(&stale, &mut state, &settings, now);
+        assert_eq!(components.sensor_health, 0.0);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::error::AtmosError;
+use crate::event_bus::BusEvent;
 use crate::shared_data::AccessSharedData;
 use log::info;
 use serde_json::Value;
@@ -54,12 +55,32 @@ pub async fn read_atmosphere_from_sensors(sd: &AccessSharedData) -> Result<(), A
 
     //println!("t1:{}, h1:{}, t2:{}, h2:{}", t1, h1, t2, h2);
 
-    sd.increment_polling_iterations();
-    sd.set_temp_one(t1);
-    sd.set_humidity_one(h1);
-    sd.set_temp_two(t2);
-    sd.set_humidity_two(h2);
-    sd.set_last_reading_datetime(now);
+    record_sensor_readings(sd, t1, h1, t2, h2, now);
 
     Ok(())
 }
+
+pub fn record_sensor_readings(
+    sd: &AccessSharedData,
+    t1: f32,
+    h1: f32,
+    t2: f32,
+    h2: f32,
+    now: OffsetDateTime,
+) {
+    sd.update(|state| {
+        state.polling_iterations += 1;
+        state.temp_1 = t1;
+        state.humidity_1 = h1;
+        state.temp_2 = t2;
+        state.humidity_2 = h2;
+        state.last_reading_time = now;
+    });
+    sd.publish(BusEvent::ReadingReceived {
+        timestamp: now.to_string(),
+        temperature_1: t1,
+        humidity_1: h1,
+        temperature_2: t2,
+        humidity_2: h2,
+    });
+}
@@ -0,0 +1,201 @@
+use crate::error::AtmosError;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// A named curing program as it is stored, imported and exported. Unknown fields are
+// rejected so a typo in a shared file doesn't silently fall back to a default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recipe {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Weight loss at which batches of this recipe are done (in percent)
+    pub target_loss_percent: f32,
+    /// Stages run one after another
+    pub stages: Vec<RecipeStage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipeStage {
+    pub name: String,
+    pub duration_days: f32,
+    /// Ideal temperature range (in Celsius)
+    pub temperature_start: f32,
+    pub temperature_end: f32,
+    /// Ideal humidity range (in percentage)
+    pub humidity_start: f32,
+    pub humidity_end: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipeFormat {
+    Json,
+    Toml,
+}
+
+impl FromStr for RecipeFormat {
+    type Err = AtmosError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(RecipeFormat::Json),
+            "toml" => Ok(RecipeFormat::Toml),
+            _ => Err(AtmosError::InvalidInput(format!(
+                "Unknown recipe format {}, expected json or toml",
+                s
+            ))),
+        }
+    }
+}
+
+impl RecipeFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            RecipeFormat::Json => "application/json",
+            RecipeFormat::Toml => "application/toml",
+        }
+    }
+}
+
+impl Recipe {
+    pub fn parse(content: &str, format: RecipeFormat) -> Result<Self, AtmosError> {
+        let recipe: Recipe = match format {
+            RecipeFormat::Json => serde_json::from_str(content)?,
+            RecipeFormat::Toml => toml::from_str(content)
+                .map_err(|e| AtmosError::InvalidInput(format!("Invalid recipe: {}", e)))?,
+        };
+        recipe.validate()?;
+        Ok(recipe)
+    }
+
+    pub fn export(&self, format: RecipeFormat) -> Result<String, AtmosError> {
+        match format {
+            RecipeFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            RecipeFormat::Toml => toml::to_string(self)
+                .map_err(|e| AtmosError::InvalidInput(format!("Can't export recipe: {}", e))),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), AtmosError> {
+        let invalid = |reason: String| Err(AtmosError::InvalidInput(reason));
+        if self.name.trim().is_empty() {
+            return invalid("Recipe name must not be empty".to_string());
+        }
+        if !self.target_loss_percent.is_finite()
+            || self.target_loss_percent <= 0.0
+            || self.target_loss_percent >= 100.0
+        {
+            return invalid("Target loss must be between 0 and 100 %".to_string());
+        }
+        if self.stages.is_empty() {
+            return invalid(format!("Recipe {} has no stages", self.name));
+        }
+        for stage in &self.stages {
+            if stage.name.trim().is_empty() {
+                return invalid("Stage name must not be empty".to_string());
+            }
+            if !stage.duration_days.is_finite() || stage.duration_days <= 0.0 {
+                return invalid(format!("Stage {} must last longer than 0 days", stage.name));
+            }
+            if !(-10.0..=40.0).contains(&stage.temperature_start)
+                || !(-10.0..=40.0).contains(&stage.temperature_end)
+                || stage.temperature_start >= stage.temperature_end
+            {
+                return invalid(format!(
+                    "Stage {} needs a temperature range within -10 to 40 °C",
+                    stage.name
+                ));
+            }
+            if !(0.0..=100.0).contains(&stage.humidity_start)
+                || !(0.0..=100.0).contains(&stage.humidity_end)
+                || stage.humidity_start >= stage.humidity_end
+            {
+                return invalid(format!(
+                    "Stage {} needs a humidity range within 0 to 100 %",
+                    stage.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Index of the stage running `elapsed_days` after the start, the last stage once the
+    // program has run its course.
+    pub fn stage_at(&self, elapsed_days: f32) -> Option<usize> {
+        let mut end = 0.0;
+        for (index, stage) in self.stages.iter().enumerate() {
+            end += stage.duration_days;
+            if elapsed_days < end {
+                return Some(index);
+            }
+        }
+        self.stages.len().checked_sub(1)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredRecipe {
+    pub id: i64,
+    pub created_at: String,
+    pub updated_at: String,
+    #[serde(flatten)]
+    pub recipe: Recipe,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COPPA: &str = r#"
+        name = "coppa"
+        target_loss_percent = 33.0
+
+        [[stages]]
+        name = "fermentation"
+        duration_days = 2.0
+        temperature_start = 18.0
+        temperature_end = 22.0
+        humidity_start = 85.0
+        humidity_end = 92.0
+
+        [[stages]]
+        name = "drying"
+        duration_days = 60.0
+        temperature_start = 11.0
+        temperature_end = 14.0
+        humidity_start = 70.0
+        humidity_end = 80.0
+    "#;
+
+    #[test]
+    fn test_recipe_round_trips_through_toml_and_json() {
+        let recipe = Recipe::parse(COPPA, RecipeFormat::Toml).unwrap();
+        assert_eq!(recipe.stages.len(), 2);
+        assert_eq!(recipe.stages[1].name, "drying");
+
+        for format in [RecipeFormat::Toml, RecipeFormat::Json] {
+            let exported = recipe.export(format).unwrap();
+            assert_eq!(Recipe::parse(&exported, format).unwrap(), recipe);
+        }
+    }
+
+    #[test]
+    fn test_recipe_validation() {
+        let typo = COPPA.replace("duration_days = 2.0", "duration_day = 2.0");
+        assert!(Recipe::parse(&typo, RecipeFormat::Toml).is_err());
+
+        let inverted = COPPA.replace("humidity_start = 70.0", "humidity_start = 90.0");
+        assert!(Recipe::parse(&inverted, RecipeFormat::Toml).is_err());
+    }
+
+    #[test]
+    fn test_stage_at() {
+        let recipe = Recipe::parse(COPPA, RecipeFormat::Toml).unwrap();
+        assert_eq!(recipe.stage_at(0.0), Some(0));
+        assert_eq!(recipe.stage_at(1.9), Some(0));
+        assert_eq!(recipe.stage_at(2.0), Some(1));
+        assert_eq!(recipe.stage_at(90.0), Some(1));
+    }
+}
@@ -1,7 +1,9 @@
+use crate::config::RelayPinSettings;
 use crate::error::AtmosError;
-use log::{debug, error, info};
+use log::{debug, info};
 use rppal::gpio::{Gpio, Level};
 use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum RelayStatus {
@@ -18,6 +20,96 @@ impl fmt::Display for RelayStatus {
     }
 }
 
+impl FromStr for RelayStatus {
+    type Err = AtmosError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "On" => Ok(RelayStatus::On),
+            "Off" => Ok(RelayStatus::Off),
+            _ => Err(AtmosError::InvalidInput(format!(
+                "Unknown relay status: {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Actuator {
+    Fridge,
+    Humidifier,
+    Dehumidifier,
+    Ventilator,
+}
+
+impl Actuator {
+    pub const ALL: [Actuator; 4] = [
+        Actuator::Fridge,
+        Actuator::Humidifier,
+        Actuator::Dehumidifier,
+        Actuator::Ventilator,
+    ];
+
+    pub fn pin(&self, relay_pins: &RelayPinSettings) -> u8 {
+        match self {
+            Actuator::Fridge => relay_pins.fridge,
+            Actuator::Humidifier => relay_pins.humidifier,
+            Actuator::Dehumidifier => relay_pins.dehumidifier,
+            Actuator::Ventilator => relay_pins.ventilator_or_heater,
+        }
+    }
+}
+
+impl fmt::Display for Actuator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Actuator::Fridge => write!(f, "fridge"),
+            Actuator::Humidifier => write!(f, "humidifier"),
+            Actuator::Dehumidifier => write!(f, "dehumidifier"),
+            Actuator::Ventilator => write!(f, "ventilator"),
+        }
+    }
+}
+
+impl FromStr for Actuator {
+    type Err = AtmosError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Actuator::ALL
+            .into_iter()
+            .find(|actuator| actuator.to_string() == s)
+            .ok_or_else(|| AtmosError::InvalidInput(format!("Unknown actuator: {}", s)))
+    }
+}
+
+// Where the control loop sends its relay decisions. The live controller drives the GPIO
+// pins, dry-run and tests substitute drivers that only record what would have happened.
+pub trait RelayDriver: Send + Sync {
+    fn change_relay_status(
+        &self,
+        actuator: Actuator,
+        pin: u8,
+        status: RelayStatus,
+    ) -> Result<(), AtmosError>;
+}
+
+pub struct GpioRelayDriver;
+
+impl RelayDriver for GpioRelayDriver {
+    fn change_relay_status(
+        &self,
+        _actuator: Actuator,
+        pin: u8,
+        status: RelayStatus,
+    ) -> Result<(), AtmosError> {
+        change_relay_status(pin, status)
+    }
+}
+
 pub fn change_relay_status(pin: u8, status: RelayStatus) -> Result<(), AtmosError> {
     info!(
         "Attempting to change relay status for pin {} to {:?}",
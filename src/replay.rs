@@ -0,0 +1,327 @@
+use crate::clock::{Clock, ReplayClock};
+use crate::config::Settings;
+use crate::error::AtmosError;
+use crate::events::EventLog;
+use crate::initialization::initialize_shared_data;
+use crate::monitor_atmosphere::{
+    run_control_handlers, update_average_values, update_derived_metrics,
+};
+use crate::read_atmosphere::record_sensor_readings;
+use crate::relay_ctrl::{Actuator, RelayDriver, RelayStatus};
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::{parse_timestamp, AtmosphereRecord, SqliteClient};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayDecision {
+    pub timestamp: String,
+    pub actuator: Actuator,
+    pub status: RelayStatus,
+}
+
+// A stretch of records during which the replayed controller kept a relay in a different
+// state than the one recorded.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayDifference {
+    pub actuator: Actuator,
+    pub from: String,
+    pub to: String,
+    pub records: usize,
+    pub replayed: RelayStatus,
+    pub recorded: RelayStatus,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActuatorSummary {
+    pub actuator: Actuator,
+    pub decisions: usize,
+    pub mismatched_records: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayReport {
+    pub records: usize,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub summary: Vec<ActuatorSummary>,
+    pub decisions: Vec<ReplayDecision>,
+    pub differences: Vec<ReplayDifference>,
+}
+
+// Records relay commands as a timeline of state changes. The handlers re-assert a relay
+// on every tick it is needed, so repeated commands to the same state are dropped.
+struct ReplayDriver {
+    clock: Arc<ReplayClock>,
+    decisions: Mutex<Vec<ReplayDecision>>,
+}
+
+impl RelayDriver for ReplayDriver {
+    fn change_relay_status(
+        &self,
+        actuator: Actuator,
+        _pin: u8,
+        status: RelayStatus,
+    ) -> Result<(), AtmosError> {
+        let mut decisions = self.decisions.lock().unwrap();
+        let previous = decisions
+            .iter()
+            .rev()
+            .find(|decision| decision.actuator == actuator)
+            .map_or(RelayStatus::Off, |decision| decision.status);
+        if previous == status {
+            return Ok(());
+        }
+        decisions.push(ReplayDecision {
+            timestamp: self.clock.now().to_string(),
+            actuator,
+            status,
+        });
+        Ok(())
+    }
+}
+
+fn recorded_status(record: &AtmosphereRecord, actuator: Actuator) -> RelayStatus {
+    match actuator {
+        Actuator::Fridge => record.fridge_status,
+        Actuator::Humidifier => record.humidifier_status,
+        Actuator::Dehumidifier => record.dehumidifier_status,
+        Actuator::Ventilator => record.ventilator_status,
+    }
+}
+
+// Feeds recorded averages through the control handlers with `settings`, one record per
+// control tick, and compares the resulting relay states with the recorded ones.
+pub async fn replay_records(records: &[AtmosphereRecord], settings: &Settings) -> ReplayReport {
+    let sd = AccessSharedData::new(initialize_shared_data());
+    let replay_clock = Arc::new(ReplayClock::new(
+        records
+            .first()
+            .map_or(OffsetDateTime::UNIX_EPOCH, |record| record.timestamp),
+    ));
+    let replay_driver = Arc::new(ReplayDriver {
+        clock: replay_clock.clone(),
+        decisions: Mutex::new(Vec::new()),
+    });
+    let clock: Arc<dyn Clock> = replay_clock.clone();
+    let driver: Arc<dyn RelayDriver> = replay_driver.clone();
+    let events = EventLog::disabled();
+
+    let mut differences = Vec::new();
+    let mut open_differences: HashMap<Actuator, (OffsetDateTime, ReplayDifference)> =
+        HashMap::new();
+    let mut mismatched_records: HashMap<Actuator, usize> = HashMap::new();
+
+    for record in records {
+        replay_clock.set(record.timestamp);
+        record_sensor_readings(
+            &sd,
+            record.average_temperature,
+            record.average_humidity,
+            record.average_temperature,
+            record.average_humidity,
+            record.timestamp,
+        );
+        update_average_values(&sd);
+        update_derived_metrics(&sd);
+
+        if sd.polling_iterations() > 4 {
+            for e in run_control_handlers(&sd, settings, &driver, &clock, &events, record.timestamp)
+                .await
+            {
+                warn!(
+                    "replay() -> controller error at {}: {}",
+                    record.timestamp, e
+                );
+            }
+        }
+
+        let timestamp = record.timestamp.to_string();
+        for actuator in Actuator::ALL {
+            let replayed = sd.actuator_status(actuator);
+            let recorded = recorded_status(record, actuator);
+            if replayed == recorded {
+                if let Some(difference) = open_differences.remove(&actuator) {
+                    differences.push(difference);
+                }
+                continue;
+            }
+
+            *mismatched_records.entry(actuator).or_insert(0) += 1;
+            let (_, difference) = open_differences.entry(actuator).or_insert_with(|| {
+                (
+                    record.timestamp,
+                    ReplayDifference {
+                        actuator,
+                        from: timestamp.clone(),
+                        to: timestamp.clone(),
+                        records: 0,
+                        replayed,
+                        recorded,
+                    },
+                )
+            });
+            difference.to = timestamp.clone();
+            difference.records += 1;
+        }
+    }
+    differences.extend(open_differences.into_values());
+    differences.sort_by_key(|(start, difference)| (*start, difference.actuator));
+    let differences = differences
+        .into_iter()
+        .map(|(_, difference)| difference)
+        .collect();
+
+    let decisions = replay_driver.decisions.lock().unwrap().clone();
+    let summary = Actuator::ALL
+        .iter()
+        .map(|actuator| ActuatorSummary {
+            actuator: *actuator,
+            decisions: decisions
+                .iter()
+                .filter(|decision| decision.actuator == *actuator)
+                .count(),
+            mismatched_records: *mismatched_records.get(actuator).unwrap_or(&0),
+        })
+        .collect();
+
+    ReplayReport {
+        records: records.len(),
+        from: records.first().map(|record| record.timestamp.to_string()),
+        to: records.last().map(|record| record.timestamp.to_string()),
+        summary,
+        decisions,
+        differences,
+    }
+}
+
+// Reads a CSV export with a header row using the `atmosphere_data` column names.
+pub fn read_csv_records(
+    path: &str,
+    from: OffsetDateTime,
+    to: OffsetDateTime,
+) -> Result<Vec<AtmosphereRecord>, AtmosError> {
+    let content = fs::read_to_string(path)?;
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<&str> = lines
+        .next()
+        .ok_or_else(|| AtmosError::InvalidInput(format!("{} is empty", path)))?
+        .split(',')
+        .map(str::trim)
+        .collect();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| *column == name)
+            .ok_or_else(|| AtmosError::InvalidInput(format!("Missing CSV column {}", name)))
+    };
+    let columns = [
+        column("timestamp")?,
+        column("average_temperature")?,
+        column("average_humidity")?,
+        column("fridge_status")?,
+        column("dehumidifier_status")?,
+        column("humidifier_status")?,
+        column("ventilator_status")?,
+    ];
+
+    let mut records = Vec::new();
+    for (line_number, line) in lines.enumerate() {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let field = |index: usize| {
+            fields.get(columns[index]).copied().ok_or_else(|| {
+                AtmosError::InvalidInput(format!("Too few fields on CSV line {}", line_number + 2))
+            })
+        };
+        let number = |index: usize| -> Result<f32, AtmosError> {
+            field(index)?.parse().map_err(|_| {
+                AtmosError::InvalidInput(format!("Invalid number on CSV line {}", line_number + 2))
+            })
+        };
+
+        let timestamp = parse_timestamp(field(0)?)?;
+        if timestamp < from || timestamp > to {
+            continue;
+        }
+        records.push(AtmosphereRecord {
+            timestamp,
+            average_temperature: number(1)?,
+            average_humidity: number(2)?,
+            fridge_status: field(3)?.parse()?,
+            dehumidifier_status: field(4)?.parse()?,
+            humidifier_status: field(5)?.parse()?,
+            ventilator_status: field(6)?.parse()?,
+        });
+    }
+    records.sort_by_key(|record| record.timestamp);
+    Ok(records)
+}
+
+// `atmos replay [--from <time>] [--to <time>] [--csv <file>] [--config <name>] [--db <file>]`
+pub async fn replay_command(options: &HashMap<String, String>) -> Result<(), AtmosError> {
+    let settings = Settings::from_file(options.get("config").map_or("config", String::as_str))?;
+    let from = match options.get("from") {
+        Some(from) => parse_timestamp(from)?,
+        None => OffsetDateTime::UNIX_EPOCH,
+    };
+    let to = match options.get("to") {
+        Some(to) => parse_timestamp(to)?,
+        None => OffsetDateTime::now_utc(),
+    };
+
+    let records = match options.get("csv") {
+        Some(path) => read_csv_records(path, from, to)?,
+        None => {
+            let db_name = options.get("db").unwrap_or(&settings.sqlite.db_name);
+            SqliteClient::new(db_name)?.read_atmosphere_records(from, to)?
+        }
+    };
+    info!("Replaying {} records", records.len());
+
+    let report = replay_records(&records, &settings).await;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_settings;
+    use time::Duration;
+
+    fn record(minutes: i64, temperature: f32, fridge_status: RelayStatus) -> AtmosphereRecord {
+        AtmosphereRecord {
+            timestamp: OffsetDateTime::UNIX_EPOCH + Duration::minutes(minutes),
+            average_temperature: temperature,
+            average_humidity: 70.0,
+            fridge_status,
+            dehumidifier_status: RelayStatus::Off,
+            humidifier_status: RelayStatus::Off,
+            ventilator_status: RelayStatus::Off,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_reports_fridge_differences() {
+        let settings = test_settings();
+        // Recorded controller never ran the fridge although the chamber got too warm
+        let records: Vec<AtmosphereRecord> = (0..10)
+            .map(|i| record(i * 2, if i < 6 { 12.0 } else { 16.0 }, RelayStatus::Off))
+            .collect();
+
+        let report = replay_records(&records, &settings).await;
+
+        assert_eq!(report.records, 10);
+        assert_eq!(report.decisions.len(), 1);
+        assert_eq!(report.decisions[0].actuator, Actuator::Fridge);
+        assert_eq!(report.decisions[0].status, RelayStatus::On);
+        assert_eq!(report.differences.len(), 1);
+        assert_eq!(report.differences[0].actuator, Actuator::Fridge);
+        assert_eq!(report.differences[0].records, 4);
+        assert_eq!(report.differences[0].replayed, RelayStatus::On);
+    }
+}
@@ -1,15 +1,23 @@
 use crate::config::Settings;
 use crate::error::AtmosError;
+use crate::load_cell::LoadCell;
 use crate::read_atmosphere;
 use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::SqliteClient;
 use log::{error, info};
+use std::sync::Arc;
+use time::OffsetDateTime;
 use tokio::time::{sleep, Duration};
 
 pub async fn request_atmosphere(
     sd: AccessSharedData,
     settings: Settings,
+    sqlite_client: Arc<SqliteClient>,
+    load_cell: Arc<LoadCell>,
     mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
 ) -> Result<(), AtmosError> {
+    let mut last_weigh_in: Option<OffsetDateTime> = None;
+
     loop {
         match read_atmosphere::read_atmosphere_from_sensors(&sd).await {
             Ok(_) => {
@@ -21,6 +29,20 @@ pub async fn request_atmosphere(
             }
         }
 
+        if load_cell.enabled() {
+            if let Err(e) = read_hanging_weight(
+                &sd,
+                &settings,
+                &sqlite_client,
+                &load_cell,
+                &mut last_weigh_in,
+            )
+            .await
+            {
+                error!("Error reading load cell: {}", e);
+            }
+        }
+
         tokio::select! {
             _ = sleep(Duration::from_secs(settings.sensor_read_cooldown.duration)) => {
                 // Continue to next iteration
@@ -32,3 +54,49 @@ pub async fn request_atmosphere(
         }
     }
 }
+
+// Reads the hanging weight and, every `weigh_in_interval`, logs it as a weigh-in of the
+// batch currently in the chamber so its weight-loss progress follows along.
+async fn read_hanging_weight(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    sqlite_client: &Arc<SqliteClient>,
+    load_cell: &Arc<LoadCell>,
+    last_weigh_in: &mut Option<OffsetDateTime>,
+) -> Result<(), AtmosError> {
+    // Bit-banging the HX711 busy-waits, keep it off the async workers
+    let reader = load_cell.clone();
+    let weight = match tokio::task::spawn_blocking(move || reader.read_weight()).await? {
+        Ok(weight) => weight,
+        Err(e) => {
+            sd.update(|state| state.hanging_weight = None);
+            return Err(e);
+        }
+    };
+    sd.update(|state| state.hanging_weight = Some(weight));
+
+    // Product is being handled with the door open, the weight means nothing
+    if sd.maintenance_active() {
+        return Ok(());
+    }
+    let now = OffsetDateTime::now_utc();
+    let due = last_weigh_in
+        .is_none_or(|last| now - last >= Duration::from_secs(settings.load_cell.weigh_in_interval));
+    if !due {
+        return Ok(());
+    }
+    let batch = sqlite_client
+        .call(move |db| {
+            let batch = db.read_active_batch()?;
+            if let Some(batch) = &batch {
+                db.insert_weigh_in(batch.id, now, weight, "load_cell")?;
+            }
+            Ok(batch)
+        })
+        .await?;
+    if let Some(batch) = batch {
+        info!("Logged {:.0} g for batch {}", weight, batch.name);
+    }
+    *last_weigh_in = Some(now);
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use crate::clock::Clock;
+use crate::config::{RetentionSettings, Settings};
+use crate::error::AtmosError;
+use crate::sqlite_client::SqliteClient;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use tokio::time::interval;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub raw_rows_rolled_up: usize,
+    pub hours_rolled_up: usize,
+}
+
+// Truncates to a multiple of `period` since the Unix epoch, so only whole hours and days
+// are rolled up.
+fn align(timestamp: OffsetDateTime, period: Duration) -> OffsetDateTime {
+    let period = period.whole_seconds();
+    OffsetDateTime::from_unix_timestamp(timestamp.unix_timestamp().div_euclid(period) * period)
+        .unwrap_or(timestamp)
+}
+
+pub fn apply_retention(
+    sqlite_client: &SqliteClient,
+    retention: &RetentionSettings,
+    now: OffsetDateTime,
+) -> Result<RetentionReport, AtmosError> {
+    let raw_cutoff = align(
+        now - Duration::days(retention.raw_days as i64),
+        Duration::HOUR,
+    );
+    let hourly_cutoff = align(
+        raw_cutoff - Duration::days(retention.hourly_days as i64),
+        Duration::DAY,
+    );
+
+    let report = RetentionReport {
+        raw_rows_rolled_up: sqlite_client.roll_up_raw_history(raw_cutoff)?,
+        hours_rolled_up: sqlite_client.roll_up_hourly_history(hourly_cutoff)?,
+    };
+    if report != RetentionReport::default() {
+        sqlite_client.incremental_vacuum()?;
+    }
+    Ok(report)
+}
+
+// Rolls up and deletes history past the retention periods every `retention.interval`
+// seconds.
+pub async fn monitor_retention(
+    settings: Settings,
+    sqlite_client: Arc<SqliteClient>,
+    clock: Arc<dyn Clock>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    let retention = settings.retention;
+    if !retention.enabled {
+        info!("Data retention disabled, keeping all history");
+        let _ = shutdown_rx.recv().await;
+        return Ok(());
+    }
+    match sqlite_client
+        .call(|db| db.incremental_vacuum_enabled())
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => warn!(
+            "Incremental auto-vacuum is off, rolled up history won't shrink the database \
+             file until `atmos vacuum` is run"
+        ),
+        Err(e) => error!("Failed to read the auto-vacuum mode: {}", e),
+    }
+
+    let mut interval = interval(std::time::Duration::from_secs(retention.interval));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let (retention, now) = (retention.clone(), clock.now());
+                let result = sqlite_client
+                    .call(move |db| apply_retention(db, &retention, now))
+                    .await;
+                match result {
+                    Ok(report) if report != RetentionReport::default() => info!(
+                        "retention() -> rolled up {} raw rows and {} hourly aggregates",
+                        report.raw_rows_rolled_up, report.hours_rolled_up
+                    ),
+                    Ok(_) => {}
+                    Err(e) => error!("Failed to apply data retention: {}", e),
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Received shutdown signal");
+                break Ok(());
+            }
+        }
+    }
+}
+
+// `atmos vacuum [--config <name>] [--db <file>]`, switches the database to incremental
+// auto-vacuum so the retention can return freed pages. Run it once with the controller
+// stopped and as much free disk space as the database file takes.
+pub fn vacuum_command(options: &HashMap<String, String>) -> Result<(), AtmosError> {
+    let db_name = match options.get("db") {
+        Some(db_name) => db_name.clone(),
+        None => {
+            Settings::from_file(options.get("config").map_or("config", String::as_str))?
+                .sqlite
+                .db_name
+        }
+    };
+    let sqlite_client = SqliteClient::new(&db_name)?;
+    if sqlite_client.enable_incremental_vacuum()? {
+        println!("Switched {} to incremental auto-vacuum", db_name);
+    } else {
+        println!("{} already uses incremental auto-vacuum", db_name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psychrometrics::DerivedMetrics;
+    use crate::relay_ctrl::RelayStatus;
+    use crate::sqlite_client::{AtmosphereRow, HistoryQuery};
+
+    fn insert(sqlite_client: &SqliteClient, timestamp: OffsetDateTime, temperature: f32) {
+        sqlite_client
+            .insert_atmosphere_row(&AtmosphereRow {
+                timestamp,
+                average_temperature: temperature,
+                average_humidity: 80.0,
+                derived_metrics: DerivedMetrics::default(),
+                atmospheric_quality_index: 90.0,
+                hanging_weight: None,
+                fridge_status: RelayStatus::On,
+                dehumidifier_status: RelayStatus::Off,
+                humidifier_status: RelayStatus::Off,
+                ventilator_status: RelayStatus::Off,
+                heater_status: RelayStatus::Off,
+                sensor_readings: Vec::new(),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_old_history_is_rolled_up() {
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        let retention = RetentionSettings {
+            enabled: true,
+            raw_days: 2,
+            hourly_days: 1,
+            interval: 3600,
+        };
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(10) + Duration::minutes(30);
+
+        // Six readings an hour for the last five days
+        let start = now - Duration::days(5);
+        for i in 0..5 * 24 * 6 {
+            insert(
+                &sqlite_client,
+                start + Duration::minutes(10 * i),
+                (i % 6) as f32,
+            );
+        }
+
+        let report = apply_retention(&sqlite_client, &retention, now).unwrap();
+        // Raw rows before day 8 00:00 are rolled up, hours before day 7 00:00 roll up further
+        assert_eq!(report.raw_rows_rolled_up, 3 * 24 * 6 - 3);
+        assert_eq!(report.hours_rolled_up, 2 * 24);
+
+        let (_, remaining) = sqlite_client
+            .read_atmosphere_data(&HistoryQuery {
+                from: start,
+                to: now,
+                limit: 1,
+                offset: 0,
+                ascending: true,
+            })
+            .unwrap();
+        assert_eq!(remaining, 5 * 24 * 6 - report.raw_rows_rolled_up);
+
+        // Nothing left to do on the next run
+        assert_eq!(
+            apply_retention(&sqlite_client, &retention, now).unwrap(),
+            RetentionReport::default()
+        );
+
+        // Buckets over the whole range still count every reading
+        let buckets = sqlite_client
+            .read_atmosphere_buckets(start, now, Duration::days(1))
+            .unwrap();
+        assert_eq!(
+            buckets.iter().map(|bucket| bucket.samples).sum::<u32>(),
+            5 * 24 * 6
+        );
+        let weighted: f32 = buckets
+            .iter()
+            .map(|bucket| bucket.temperature.avg * bucket.samples as f32)
+            .sum();
+        assert!((weighted / (5 * 24 * 6) as f32 - 2.5).abs() < 1e-3);
+        for bucket in &buckets {
+            assert_eq!(bucket.temperature.min, 0.0);
+            assert_eq!(bucket.temperature.max, 5.0);
+            assert_eq!(bucket.duty_cycle.fridge, 1.0);
+        }
+
+        // Points stand for the two rolled up days, the hours of day 7, then the raw readings
+        let points = sqlite_client.read_atmosphere_points(start, now).unwrap();
+        assert_eq!(points.len(), 2 + 24 + remaining);
+        assert!(points
+            .windows(2)
+            .all(|pair| pair[0].timestamp < pair[1].timestamp));
+        assert!(points
+            .iter()
+            .all(|point| (point.average_temperature - 2.5).abs() <= 2.5));
+    }
+
+    #[test]
+    fn test_export_includes_rolled_up_history() {
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        let retention = RetentionSettings {
+            enabled: true,
+            raw_days: 1,
+            hourly_days: 1,
+            interval: 3600,
+        };
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(10);
+        let start = now - Duration::days(3);
+        for i in 0..3 * 24 * 6 {
+            insert(&sqlite_client, start + Duration::minutes(10 * i), 12.0);
+        }
+        apply_retention(&sqlite_client, &retention, now).unwrap();
+
+        let mut output = String::new();
+        crate::export::export(
+            &sqlite_client,
+            crate::export::ExportDataset::Readings,
+            crate::export::ExportFormat::Csv,
+            start,
+            now,
+            |chunk| {
+                output.push_str(&chunk);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let rows: Vec<&str> = output.lines().skip(1).collect();
+        // One day, then 24 hours, then the raw readings of the last day
+        assert_eq!(rows.len(), 1 + 24 + 24 * 6);
+        assert_eq!(
+            rows[0],
+            "1970-01-08T00:00:00Z,12.0,80.0,On,Off,Off,Off,Off,,,0.0,90.0,,,,,"
+        );
+        assert!(rows[1].starts_with("1970-01-09T00:00:00Z,12.0,80.0,On,Off,Off,Off,Off,"));
+        assert!(rows[25].starts_with("1970-01-10 0:00:00.0 +00:00:00,12.0,80.0,On,"));
+    }
+}
@@ -0,0 +1,244 @@
+use crate::clock::Clock;
+use crate::config::{RiskSettings, Settings};
+use crate::error::AtmosError;
+use crate::monitor_atmosphere::raise_alert;
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::{AtmosphereRecord, SqliteClient};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::time::{interval, Duration};
+
+// Risk indicators score from 0 (no risk) to 1 (act now)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RiskAssessment {
+    /// Surface mold risk
+    pub mold_risk: f32,
+    /// Hours in the window spent above the mold humidity and temperature thresholds
+    pub hours_above_mold_threshold: f32,
+    /// Case-hardening risk from drying too fast
+    pub drying_risk: f32,
+    /// Humidity trend over the gradient window (%RH per hour, negative when drying)
+    pub humidity_gradient: f32,
+    /// When the assessment was last computed
+    pub evaluated_at: Option<String>,
+}
+
+// Time spent at or above `mold_humidity_threshold` %RH while at or above
+// `mold_temperature_threshold` °C. Gaps between records longer than `max_record_gap`
+// (sensor or service outages) only count up to that gap.
+fn hours_above_mold_threshold(records: &[AtmosphereRecord], risk: &RiskSettings) -> f32 {
+    let max_gap = risk.max_record_gap as f32;
+    let seconds: f32 = records
+        .windows(2)
+        .filter(|pair| {
+            pair[0].average_humidity >= risk.mold_humidity_threshold
+                && pair[0].average_temperature >= risk.mold_temperature_threshold
+        })
+        .map(|pair| {
+            (pair[1].timestamp - pair[0].timestamp)
+                .as_seconds_f32()
+                .clamp(0.0, max_gap)
+        })
+        .sum();
+    seconds / 3600.0
+}
+
+// Least-squares slope of the average humidity over time, in %RH per hour
+fn humidity_gradient(records: &[AtmosphereRecord]) -> f32 {
+    let Some(first) = records.first() else {
+        return 0.0;
+    };
+    let points: Vec<(f32, f32)> = records
+        .iter()
+        .map(|record| {
+            (
+                (record.timestamp - first.timestamp).as_seconds_f32() / 3600.0,
+                record.average_humidity,
+            )
+        })
+        .collect();
+    let n = points.len() as f32;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f32>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f32>() / n;
+    let covariance: f32 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f32 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if variance == 0.0 {
+        return 0.0;
+    }
+    covariance / variance
+}
+
+fn records_since(records: &[AtmosphereRecord], start: OffsetDateTime) -> &[AtmosphereRecord] {
+    let offset = records
+        .iter()
+        .position(|record| record.timestamp >= start)
+        .unwrap_or(records.len());
+    &records[offset..]
+}
+
+// `records` must be sorted by timestamp. Mold risk looks at the last `mold_window` hours,
+// drying risk at the last `gradient_window` hours.
+pub fn assess_risk(
+    records: &[AtmosphereRecord],
+    risk: &RiskSettings,
+    now: OffsetDateTime,
+) -> RiskAssessment {
+    let mold_start = now - Duration::from_secs(risk.mold_window * 3600);
+    let gradient_start = now - Duration::from_secs(risk.gradient_window * 3600);
+    let hours_above = hours_above_mold_threshold(records_since(records, mold_start), risk);
+    let gradient = humidity_gradient(records_since(records, gradient_start));
+
+    RiskAssessment {
+        mold_risk: (hours_above / risk.mold_hours_limit).clamp(0.0, 1.0),
+        hours_above_mold_threshold: hours_above,
+        drying_risk: (-gradient / risk.humidity_drop_limit).clamp(0.0, 1.0),
+        humidity_gradient: gradient,
+        evaluated_at: Some(now.to_string()),
+    }
+}
+
+fn raise_risk_alerts(
+    sd: &AccessSharedData,
+    previous: &RiskAssessment,
+    current: &RiskAssessment,
+    risk: &RiskSettings,
+) {
+    if current.mold_risk >= risk.alert_threshold && previous.mold_risk < risk.alert_threshold {
+        raise_alert(
+            sd,
+            "Mold Risk",
+            &format!(
+                "{:.1} h above {} %RH at {} °C or warmer in the last {} h",
+                current.hours_above_mold_threshold,
+                risk.mold_humidity_threshold,
+                risk.mold_temperature_threshold,
+                risk.mold_window
+            ),
+        );
+    }
+    if current.drying_risk >= risk.alert_threshold && previous.drying_risk < risk.alert_threshold {
+        raise_alert(
+            sd,
+            "Case Hardening Risk",
+            &format!(
+                "Humidity falling {:.1} %RH/h over the last {} h",
+                -current.humidity_gradient, risk.gradient_window
+            ),
+        );
+    }
+}
+
+pub fn update_risk_assessment(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    sqlite_client: &SqliteClient,
+    now: OffsetDateTime,
+) -> Result<(), AtmosError> {
+    let risk = &settings.risk;
+    let window = risk.mold_window.max(risk.gradient_window);
+    let mut records =
+        sqlite_client.read_atmosphere_records(now - Duration::from_secs(window * 3600), now)?;
+    records.sort_by_key(|record| record.timestamp);
+    let assessment = assess_risk(&records, risk, now);
+
+    raise_risk_alerts(sd, &sd.risk_assessment(), &assessment, risk);
+    sd.update(|state| state.risk_assessment = assessment);
+    Ok(())
+}
+
+// Re-evaluates the risk indicators from the stored history every `evaluation_interval`
+// seconds.
+pub async fn monitor_risk(
+    sd: AccessSharedData,
+    settings: Settings,
+    sqlite_client: Arc<SqliteClient>,
+    clock: Arc<dyn Clock>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    let mut interval = interval(Duration::from_secs(settings.risk.evaluation_interval));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let (sd, settings, now) = (sd.clone(), settings.clone(), clock.now());
+                let result = sqlite_client
+                    .call(move |db| update_risk_assessment(&sd, &settings, db, now))
+                    .await;
+                if let Err(e) = result {
+                    error!("Failed to evaluate mold and case-hardening risk: {}", e);
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Received shutdown signal");
+                break Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_settings;
+    use crate::relay_ctrl::RelayStatus;
+
+    fn records(
+        now: OffsetDateTime,
+        hours: i64,
+        reading: impl Fn(i64) -> (f32, f32),
+    ) -> Vec<AtmosphereRecord> {
+        (0..=hours * 6)
+            .map(|i| {
+                let (temperature, humidity) = reading(i);
+                AtmosphereRecord {
+                    timestamp: now - time::Duration::minutes((hours * 6 - i) * 10),
+                    average_temperature: temperature,
+                    average_humidity: humidity,
+                    fridge_status: RelayStatus::Off,
+                    dehumidifier_status: RelayStatus::Off,
+                    humidifier_status: RelayStatus::Off,
+                    ventilator_status: RelayStatus::Off,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_mold_risk_counts_hours_above_threshold() {
+        let risk = test_settings().risk;
+        let now = OffsetDateTime::UNIX_EPOCH + time::Duration::days(2);
+
+        // Humid and warm for the first half of the window only
+        let humid = records(
+            now,
+            24,
+            |i| if i < 72 { (14.0, 90.0) } else { (14.0, 75.0) },
+        );
+        let assessment = assess_risk(&humid, &risk, now);
+        assert!((assessment.hours_above_mold_threshold - 12.0).abs() < 0.01);
+        assert!((assessment.mold_risk - 12.0 / risk.mold_hours_limit).abs() < 0.01);
+
+        // Just as humid but too cold for mold to grow
+        let cold = records(now, 24, |_| (2.0, 90.0));
+        assert_eq!(assess_risk(&cold, &risk, now).mold_risk, 0.0);
+    }
+
+    #[test]
+    fn test_drying_risk_follows_humidity_gradient() {
+        let risk = test_settings().risk;
+        let now = OffsetDateTime::UNIX_EPOCH + time::Duration::days(2);
+
+        let steady = records(now, 24, |_| (12.0, 75.0));
+        assert_eq!(assess_risk(&steady, &risk, now).drying_risk, 0.0);
+
+        // Losing 1 %RH every 10 minutes is 6 %RH per hour
+        let drying = records(now, 6, |i| (12.0, 80.0 - i as f32));
+        let assessment = assess_risk(&drying, &risk, now);
+        assert!((assessment.humidity_gradient + 6.0).abs() < 0.01);
+        assert_eq!(assessment.drying_risk, 1.0);
+    }
+}
@@ -0,0 +1,31 @@
+use crate::backup::create_backup;
+use crate::config::Settings;
+use crate::sqlite_client::SqliteClient;
+use crate::Arc;
+use actix_web::{post, web, HttpResponse};
+use serde_json::json;
+use time::OffsetDateTime;
+
+// Takes a backup right away, with the same directory and rotation as scheduled ones
+#[post("/api/admin/backup")]
+pub async fn post_backup(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    settings: web::Data<Settings>,
+) -> HttpResponse {
+    let sqlite_client = sqlite_client.get_ref().clone();
+    let backup = settings.backup.clone();
+    let result =
+        web::block(move || create_backup(&sqlite_client, &backup, OffsetDateTime::now_utc())).await;
+
+    match result {
+        Ok(Ok(path)) => {
+            let size = std::fs::metadata(&path).map(|metadata| metadata.len()).ok();
+            HttpResponse::Created().json(json!({
+                "path": path.display().to_string(),
+                "size_bytes": size,
+            }))
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
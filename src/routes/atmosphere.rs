@@ -1,19 +1,34 @@
+use crate::error::AtmosError;
+use crate::history::{lttb, parse_bucket};
+use crate::quality_index::QualityIndexComponents;
+use crate::risk_model::RiskAssessment;
+use crate::shared_data::AtmosphereState;
+use crate::sqlite_client::{parse_timestamp, AtmospherePoint, HistoryQuery};
 use crate::Arc;
 use crate::{relay_ctrl::RelayStatus, sqlite_client::SqliteClient, AccessSharedData};
 use actix_web::{get, http::header::ContentType, web, web::Query, HttpResponse};
-use std::collections::HashMap;
+use serde_json::json;
+use time::{Duration, OffsetDateTime};
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct AvgAtmosphereData {
     temperature: f32,
     humidity: f32,
+    dew_point: f32,
+    absolute_humidity: f32,
+    vpd: f32,
 }
 
 #[get("/atmosphere")]
 pub async fn get_atmosphere(sd: web::Data<AccessSharedData>) -> HttpResponse {
+    let state = sd.snapshot();
+    let derived_metrics = state.average_derived_metrics;
     let values = AvgAtmosphereData {
-        temperature: sd.average_temp(),
-        humidity: sd.average_humidity(),
+        temperature: state.average_temp,
+        humidity: state.average_humidity,
+        dew_point: derived_metrics.dew_point,
+        absolute_humidity: derived_metrics.absolute_humidity,
+        vpd: derived_metrics.vpd,
     };
     let values = serde_json::to_string(&values).unwrap();
 
@@ -31,6 +46,18 @@ pub struct FullData {
     average_temp: f32,
     average_humidity: f32,
     atmospheric_quality_index: f32,
+    quality_index_components: QualityIndexComponents,
+    risk: RiskAssessment,
+    dew_point_1: f32,
+    absolute_humidity_1: f32,
+    vpd_1: f32,
+    dew_point_2: f32,
+    absolute_humidity_2: f32,
+    vpd_2: f32,
+    average_dew_point: f32,
+    average_absolute_humidity: f32,
+    average_vpd: f32,
+    hanging_weight: Option<f32>,
     fridge_status: RelayStatus,
     humidifier_status: RelayStatus,
     dehumidifier_status: RelayStatus,
@@ -46,30 +73,52 @@ pub struct FullData {
     heater_turn_off_datetime: String,
 }
 
+impl From<&AtmosphereState> for FullData {
+    fn from(state: &AtmosphereState) -> Self {
+        let derived_metrics_one = state.derived_metrics_1;
+        let derived_metrics_two = state.derived_metrics_2;
+        let average_derived_metrics = state.average_derived_metrics;
+        FullData {
+            temp_1: state.temp_1,
+            humidity_1: state.humidity_1,
+            temp_2: state.temp_2,
+            humidity_2: state.humidity_2,
+            average_temp: state.average_temp,
+            average_humidity: state.average_humidity,
+            atmospheric_quality_index: state.atmospheric_quality_index,
+            quality_index_components: state.quality_index_components,
+            risk: state.risk_assessment.clone(),
+            dew_point_1: derived_metrics_one.dew_point,
+            absolute_humidity_1: derived_metrics_one.absolute_humidity,
+            vpd_1: derived_metrics_one.vpd,
+            dew_point_2: derived_metrics_two.dew_point,
+            absolute_humidity_2: derived_metrics_two.absolute_humidity,
+            vpd_2: derived_metrics_two.vpd,
+            average_dew_point: average_derived_metrics.dew_point,
+            average_absolute_humidity: average_derived_metrics.absolute_humidity,
+            average_vpd: average_derived_metrics.vpd,
+            hanging_weight: state.hanging_weight,
+            fridge_status: state.fridge_status,
+            humidifier_status: state.humidifier_status,
+            dehumidifier_status: state.dehumidifier_status,
+            heater_status: state.heater_status,
+            last_reading_time: state.last_reading_time.to_string(),
+            fridge_turn_on_datetime: state.fridge_turn_on_datetime.to_string(),
+            fridge_turn_off_datetime: state.fridge_turn_off_datetime.to_string(),
+            humidifier_turn_on_datetime: state.humidifier_turn_on_datetime.to_string(),
+            humidifier_turn_off_datetime: state.humidifier_turn_off_datetime.to_string(),
+            dehumidifier_turn_on_datetime: state.dehumidifier_turn_on_datetime.to_string(),
+            dehumidifier_turn_off_datetime: state.dehumidifier_turn_off_datetime.to_string(),
+            heater_turn_on_datetime: state.heater_turn_on_datetime.to_string(),
+            heater_turn_off_datetime: state.heater_turn_off_datetime.to_string(),
+        }
+    }
+}
+
 #[get("/api/atmosphere/full")]
 pub async fn get_full_atmospheric_data(sd: web::Data<AccessSharedData>) -> HttpResponse {
-    let values = FullData {
-        temp_1: sd.temp_one(),
-        humidity_1: sd.humidity_one(),
-        temp_2: sd.temp_two(),
-        humidity_2: sd.humidity_two(),
-        average_temp: sd.average_temp(),
-        average_humidity: sd.average_humidity(),
-        atmospheric_quality_index: sd.atmosphere_quality_index(),
-        fridge_status: sd.fridge_status(),
-        humidifier_status: sd.humidifier_status(),
-        dehumidifier_status: sd.dehumidifier_status(),
-        heater_status: sd.heater_status(),
-        last_reading_time: sd.last_reading_datetime().to_string(),
-        fridge_turn_on_datetime: sd.fridge_turn_on_datetime().to_string(),
-        fridge_turn_off_datetime: sd.fridge_turn_off_datetime().to_string(),
-        humidifier_turn_on_datetime: sd.humidifier_turn_on_datetime().to_string(),
-        humidifier_turn_off_datetime: sd.humidifier_turn_off_datetime().to_string(),
-        dehumidifier_turn_on_datetime: sd.dehumidifier_turn_on_datetime().to_string(),
-        dehumidifier_turn_off_datetime: sd.dehumidifier_turn_off_datetime().to_string(),
-        heater_turn_on_datetime: sd.heater_turn_on_datetime().to_string(),
-        heater_turn_off_datetime: sd.heater_turn_off_datetime().to_string(),
-    };
+    // One snapshot, so every value comes from the same tick
+    let values = FullData::from(&sd.snapshot());
     let values = serde_json::to_string(&values).unwrap();
     println!("Sending JSON response: {}", values);
     HttpResponse::Ok()
@@ -77,24 +126,162 @@ pub async fn get_full_atmospheric_data(sd: web::Data<AccessSharedData>) -> HttpR
         .body(values)
 }
 
+// Largest page the history endpoint returns, a month of 10 minute readings
+const MAX_HISTORY_PAGE: usize = 5000;
+
+#[derive(serde::Deserialize)]
+pub struct HistoryParams {
+    /// ISO-8601 bounds, inclusive. `to` defaults to now, `from` to `range` before `to`.
+    from: Option<String>,
+    to: Option<String>,
+    /// Today, Week or Month, used when `from` isn't given
+    range: Option<String>,
+    /// Page size, without it the whole range is returned (up to `MAX_HISTORY_PAGE`)
+    limit: Option<usize>,
+    offset: Option<usize>,
+    /// asc or desc (default)
+    order: Option<String>,
+    /// Aggregate into buckets of this size, e.g. 15m
+    bucket: Option<String>,
+    /// Reduce each series to this many points for charting
+    downsample: Option<usize>,
+}
+
+pub fn history_query(params: &HistoryParams) -> Result<HistoryQuery, AtmosError> {
+    let to = match &params.to {
+        Some(to) => parse_timestamp(to)?,
+        None => OffsetDateTime::now_utc(),
+    };
+    let from = match &params.from {
+        Some(from) => parse_timestamp(from)?,
+        None => match params.range.as_deref().unwrap_or("Today") {
+            "Week" => to - Duration::days(7),
+            "Month" => to - Duration::days(30),
+            _ => to - Duration::days(1),
+        },
+    };
+    if from > to {
+        return Err(AtmosError::InvalidInput(
+            "from must not be after to".to_string(),
+        ));
+    }
+    let ascending = match params.order.as_deref() {
+        None | Some("desc") => false,
+        Some("asc") => true,
+        Some(order) => {
+            return Err(AtmosError::InvalidInput(format!(
+                "Unknown order {}, expected asc or desc",
+                order
+            )))
+        }
+    };
+    Ok(HistoryQuery {
+        from,
+        to,
+        limit: params
+            .limit
+            .unwrap_or(MAX_HISTORY_PAGE)
+            .min(MAX_HISTORY_PAGE),
+        offset: params.offset.unwrap_or(0),
+        ascending,
+    })
+}
+
+// One page of the readings between `from` and `to`. The number of readings in the whole
+// range is returned in the X-Total-Count header, page through it with `offset`.
 #[get("/api/atmosphere/history")]
 pub async fn get_atmosphere_history(
     sqlite_client: web::Data<Arc<SqliteClient>>,
-    query: Query<HashMap<String, String>>,
+    params: Query<HistoryParams>,
 ) -> HttpResponse {
-    let default_range = "Today".to_string();
-    let range = query.get("range").unwrap_or(&default_range);
-    let limit = match range.as_str() {
-        "Today" => 24 * 6,
-        "Week" => 168 * 6,
-        "Month" => 720 * 6,
-        _ => 24 * 6,
+    let query = match history_query(&params) {
+        Ok(query) => query,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
     };
+    match (&params.bucket, params.downsample) {
+        (Some(_), Some(_)) => {
+            return HttpResponse::BadRequest().body("Use either bucket or downsample, not both")
+        }
+        (Some(bucket), None) => {
+            return bucketed_history(sqlite_client.get_ref(), query.from, query.to, bucket).await
+        }
+        (None, Some(threshold)) => {
+            return downsampled_history(sqlite_client.get_ref(), query, threshold).await
+        }
+        (None, None) => {}
+    }
 
-    match sqlite_client.read_atmosphere_data(limit) {
-        Ok(json_data) => HttpResponse::Ok()
+    match sqlite_client
+        .call(move |db| db.read_atmosphere_data(&query))
+        .await
+    {
+        Ok((json_data, total)) => HttpResponse::Ok()
             .content_type(ContentType::json())
+            .insert_header(("X-Total-Count", total.to_string()))
             .body(json_data),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
+
+// Min/max/avg and duty cycles per bucket, oldest first
+pub async fn bucketed_history(
+    sqlite_client: &Arc<SqliteClient>,
+    from: OffsetDateTime,
+    to: OffsetDateTime,
+    bucket: &str,
+) -> HttpResponse {
+    let bucket = match parse_bucket(bucket) {
+        Ok(bucket) => bucket,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    if (to - from) / bucket > MAX_HISTORY_PAGE as f64 {
+        return HttpResponse::BadRequest().body(format!(
+            "More than {} buckets, use a larger bucket or a shorter range",
+            MAX_HISTORY_PAGE
+        ));
+    }
+
+    match sqlite_client
+        .call(move |db| db.read_atmosphere_buckets(from, to, bucket))
+        .await
+    {
+        Ok(buckets) => HttpResponse::Ok().json(buckets),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// Temperature and humidity each reduced to `threshold` points with LTTB, oldest first.
+// Rolled up periods count as one point per hour or day.
+async fn downsampled_history(
+    sqlite_client: &Arc<SqliteClient>,
+    query: HistoryQuery,
+    threshold: usize,
+) -> HttpResponse {
+    let points = match sqlite_client
+        .call(move |db| db.read_atmosphere_points(query.from, query.to))
+        .await
+    {
+        Ok(points) => points,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let series = |value: fn(&AtmospherePoint) -> f32| {
+        let coordinates: Vec<(f64, f64)> = points
+            .iter()
+            .map(|point| (point.timestamp.unix_timestamp() as f64, value(point) as f64))
+            .collect();
+        lttb(&coordinates, threshold)
+            .into_iter()
+            .map(|index| {
+                json!({
+                    "timestamp": points[index].timestamp.to_string(),
+                    "value": value(&points[index]),
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    HttpResponse::Ok().json(json!({
+        "average_temperature": series(|point| point.average_temperature),
+        "average_humidity": series(|point| point.average_humidity),
+    }))
+}
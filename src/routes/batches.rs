@@ -0,0 +1,186 @@
+use crate::batches::{batch_progress, Batch, BatchProgress, NewBatch, WeighIn};
+use crate::routes::atmosphere::bucketed_history;
+use crate::sqlite_client::{parse_timestamp, SqliteClient};
+use crate::Arc;
+use actix_web::{get, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+#[derive(Serialize)]
+struct BatchDetails {
+    batch: Batch,
+    progress: Option<BatchProgress>,
+    weigh_ins: Vec<WeighIn>,
+    /// Atmosphere history recorded while the batch was hanging
+    atmosphere_history: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewWeighIn {
+    weight: f32,
+    /// When the batch was weighed, now if unset
+    timestamp: Option<String>,
+}
+
+async fn batch_details(sqlite_client: &Arc<SqliteClient>, batch: Batch) -> HttpResponse {
+    let batch_id = batch.id;
+    let weigh_ins = match sqlite_client
+        .call(move |db| db.read_weigh_ins(batch_id))
+        .await
+    {
+        Ok(weigh_ins) => weigh_ins,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let progress = batch_progress(&batch, &weigh_ins).ok();
+    HttpResponse::Ok().json(BatchDetails {
+        atmosphere_history: format!("/api/batches/{}/atmosphere", batch.id),
+        batch,
+        progress,
+        weigh_ins,
+    })
+}
+
+#[get("/api/batches")]
+pub async fn get_batches(sqlite_client: web::Data<Arc<SqliteClient>>) -> HttpResponse {
+    match sqlite_client.call(|db| db.read_batches()).await {
+        Ok(batches) => HttpResponse::Ok().json(batches),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/api/batches")]
+pub async fn create_batch(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    new_batch: web::Json<NewBatch>,
+) -> HttpResponse {
+    if let Err(e) = new_batch.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    let new_batch = new_batch.into_inner();
+    let batch = sqlite_client
+        .call(move |db| {
+            let id = db.create_batch(
+                new_batch.name.trim(),
+                new_batch.start_weight,
+                new_batch.target_loss_percent,
+                new_batch.program.as_deref(),
+                None,
+                OffsetDateTime::now_utc(),
+            )?;
+            db.read_batch(id)
+        })
+        .await;
+    match batch {
+        Ok(Some(batch)) => batch_details(&sqlite_client, batch).await,
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/api/batches/{id}")]
+pub async fn get_batch(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+) -> HttpResponse {
+    let id = *id;
+    match sqlite_client.call(move |db| db.read_batch(id)).await {
+        Ok(Some(batch)) => batch_details(&sqlite_client, batch).await,
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/api/batches/{id}/weigh_ins")]
+pub async fn log_weigh_in(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+    weigh_in: web::Json<NewWeighIn>,
+) -> HttpResponse {
+    let id = *id;
+    let batch = match sqlite_client.call(move |db| db.read_batch(id)).await {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if batch.finished_at.is_some() {
+        return HttpResponse::Conflict().body("Batch is already finished");
+    }
+    if !weigh_in.weight.is_finite() || weigh_in.weight <= 0.0 {
+        return HttpResponse::BadRequest().body("Weight must be positive");
+    }
+    let timestamp = match &weigh_in.timestamp {
+        Some(timestamp) => match parse_timestamp(timestamp) {
+            Ok(timestamp) => timestamp,
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        },
+        None => OffsetDateTime::now_utc(),
+    };
+    match parse_timestamp(&batch.started_at) {
+        Ok(started_at) if timestamp < started_at => {
+            return HttpResponse::BadRequest().body("Weigh-in is older than the batch")
+        }
+        Ok(_) => {}
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let (batch_id, weight) = (batch.id, weigh_in.weight);
+    match sqlite_client
+        .call(move |db| db.insert_weigh_in(batch_id, timestamp, weight, "manual"))
+        .await
+    {
+        Ok(_) => batch_details(&sqlite_client, batch).await,
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/api/batches/{id}/finish")]
+pub async fn finish_batch(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+) -> HttpResponse {
+    let id = *id;
+    let batch = sqlite_client
+        .call(move |db| {
+            db.finish_batch(id, OffsetDateTime::now_utc())?;
+            db.read_batch(id)
+        })
+        .await;
+    match batch {
+        Ok(Some(batch)) => batch_details(&sqlite_client, batch).await,
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BatchAtmosphereParams {
+    /// Bucket size, e.g. 15m, 1h by default
+    bucket: Option<String>,
+}
+
+// The atmosphere while the batch was hanging, aggregated like the bucketed history
+#[get("/api/batches/{id}/atmosphere")]
+pub async fn get_batch_atmosphere(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+    params: web::Query<BatchAtmosphereParams>,
+) -> HttpResponse {
+    let id = *id;
+    let batch = match sqlite_client.call(move |db| db.read_batch(id)).await {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let from = parse_timestamp(&batch.started_at);
+    let to = match &batch.finished_at {
+        Some(finished_at) => parse_timestamp(finished_at),
+        None => Ok(OffsetDateTime::now_utc()),
+    };
+    match (from, to) {
+        (Ok(from), Ok(to)) => {
+            let bucket = params.bucket.as_deref().unwrap_or("1h");
+            bucketed_history(sqlite_client.get_ref(), from, to, bucket).await
+        }
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,24 @@
+use crate::config::Settings;
+use crate::dry_run::{DryRunAction, RecordingRelayDriver};
+use crate::Arc;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct DryRunActions {
+    enabled: bool,
+    settings_file: Option<String>,
+    actions: Vec<DryRunAction>,
+}
+
+#[get("/api/dry_run/actions")]
+pub async fn get_dry_run_actions(
+    settings: web::Data<Settings>,
+    driver: web::Data<Arc<RecordingRelayDriver>>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(DryRunActions {
+        enabled: settings.dry_run.enabled,
+        settings_file: settings.dry_run.settings_file.clone(),
+        actions: driver.actions(),
+    })
+}
@@ -0,0 +1,28 @@
+use crate::events::EventFilter;
+use crate::sqlite_client::{parse_timestamp, SqliteClient};
+use crate::Arc;
+use actix_web::{get, web, web::Query, HttpResponse};
+
+// Relay transitions, newest first. Filters: `actuator`, `source`, `from`, `to` and
+// `limit` (100 by default).
+#[get("/api/events")]
+pub async fn get_events(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    filter: Query<EventFilter>,
+) -> HttpResponse {
+    let from = filter.from.as_deref().map(parse_timestamp).transpose();
+    let to = filter.to.as_deref().map(parse_timestamp).transpose();
+    let (from, to) = match (from, to) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    let filter = filter.into_inner();
+    match sqlite_client
+        .call(move |db| db.read_actuator_events(&filter, from, to))
+        .await
+    {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
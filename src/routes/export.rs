@@ -0,0 +1,70 @@
+use crate::error::AtmosError;
+use crate::export::{export, ExportDataset, ExportFormat};
+use crate::sqlite_client::{parse_timestamp, SqliteClient};
+use crate::Arc;
+use actix_web::{get, web, web::Query, HttpResponse};
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub format: Option<String>,
+}
+
+// Streams `readings`, `events` or `batches` between `from` (all history by default) and
+// `to` (now) as a chunked CSV or NDJSON download.
+#[get("/api/export/{dataset}")]
+pub async fn get_export(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    dataset: web::Path<String>,
+    params: Query<ExportParams>,
+) -> HttpResponse {
+    let name = dataset.into_inner();
+    let parsed = (|| {
+        let dataset: ExportDataset = name.parse()?;
+        let format: ExportFormat = params.format.as_deref().unwrap_or("csv").parse()?;
+        let from = match &params.from {
+            Some(from) => parse_timestamp(from)?,
+            None => OffsetDateTime::UNIX_EPOCH,
+        };
+        let to = match &params.to {
+            Some(to) => parse_timestamp(to)?,
+            None => OffsetDateTime::now_utc(),
+        };
+        Ok::<_, AtmosError>((dataset, format, from, to))
+    })();
+    let (dataset, format, from, to) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    // Pages are read on a blocking thread and handed over through a small channel, so a
+    // slow client holds back the reads instead of buffering the whole export
+    let (tx, rx) = tokio::sync::mpsc::channel::<String>(4);
+    let filename = format!("{}.{}", name, format.extension());
+    let sqlite_client = sqlite_client.get_ref().clone();
+    tokio::task::spawn_blocking(move || {
+        let result = export(&sqlite_client, dataset, format, from, to, |chunk| {
+            tx.blocking_send(chunk)
+                .map_err(|_| AtmosError::HttpError("Export client disconnected".to_string()))
+        });
+        if let Err(e) = result {
+            log::error!("Failed to export {:?}: {}", dataset, e);
+        }
+    });
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|chunk| (Ok::<_, actix_web::Error>(web::Bytes::from(chunk)), rx))
+    });
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .streaming(body)
+}
@@ -0,0 +1,85 @@
+use crate::load_cell::{LoadCell, LoadCellCalibration};
+use crate::shared_data::AccessSharedData;
+use crate::Arc;
+use actix_web::{get, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+struct LoadCellStatus {
+    enabled: bool,
+    hanging_weight: Option<f32>,
+    calibration: Option<LoadCellCalibration>,
+    response: String,
+}
+
+#[derive(Deserialize)]
+pub struct Calibration {
+    /// Reference weight hanging on the load cell (in grams)
+    known_weight: f32,
+}
+
+fn load_cell_status(
+    sd: &AccessSharedData,
+    load_cell: &LoadCell,
+    response: String,
+) -> LoadCellStatus {
+    LoadCellStatus {
+        enabled: load_cell.enabled(),
+        hanging_weight: sd.hanging_weight(),
+        calibration: load_cell.calibration(),
+        response,
+    }
+}
+
+#[get("/api/load_cell")]
+pub async fn get_load_cell(
+    sd: web::Data<AccessSharedData>,
+    load_cell: web::Data<Arc<LoadCell>>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(load_cell_status(&sd, &load_cell, String::new()))
+}
+
+// Tare and calibration only last until restart, the reported values go into config.toml.
+#[post("/api/load_cell/tare")]
+pub async fn tare_load_cell(
+    sd: web::Data<AccessSharedData>,
+    load_cell: web::Data<Arc<LoadCell>>,
+) -> HttpResponse {
+    let reader = load_cell.get_ref().clone();
+    match web::block(move || reader.tare()).await {
+        Ok(Ok(_)) => HttpResponse::Ok().json(load_cell_status(
+            &sd,
+            &load_cell,
+            "Load cell tared".to_string(),
+        )),
+        Ok(Err(e)) => HttpResponse::BadRequest().json(load_cell_status(
+            &sd,
+            &load_cell,
+            format!("Error taring load cell: {}", e),
+        )),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/api/load_cell/calibrate")]
+pub async fn calibrate_load_cell(
+    sd: web::Data<AccessSharedData>,
+    load_cell: web::Data<Arc<LoadCell>>,
+    calibration: web::Json<Calibration>,
+) -> HttpResponse {
+    let reader = load_cell.get_ref().clone();
+    let known_weight = calibration.known_weight;
+    match web::block(move || reader.calibrate(known_weight)).await {
+        Ok(Ok(_)) => HttpResponse::Ok().json(load_cell_status(
+            &sd,
+            &load_cell,
+            "Load cell calibrated".to_string(),
+        )),
+        Ok(Err(e)) => HttpResponse::BadRequest().json(load_cell_status(
+            &sd,
+            &load_cell,
+            format!("Error calibrating load cell: {}", e),
+        )),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,105 @@
+use crate::config::Settings;
+use crate::events::EventLog;
+use crate::maintenance::{
+    maintenance_resume_datetime, release_maintenance, start_maintenance, MaintenanceTrigger,
+};
+use crate::relay_ctrl::RelayDriver;
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::SqliteClient;
+use crate::Arc;
+use actix_web::{get, http::header::ContentType, post, web, HttpResponse};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+#[derive(Serialize)]
+struct MaintenanceStatus {
+    maintenance_active: bool,
+    door_open: bool,
+    started_at: Option<String>,
+    resume_at: Option<String>,
+    response: String,
+}
+
+fn maintenance_status(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    response: String,
+) -> MaintenanceStatus {
+    let active = sd.maintenance_active();
+    MaintenanceStatus {
+        maintenance_active: active,
+        door_open: sd.door_open(),
+        started_at: active.then(|| sd.maintenance_start_datetime().to_string()),
+        resume_at: maintenance_resume_datetime(sd, settings).map(|dt| dt.to_string()),
+        response,
+    }
+}
+
+#[get("/api/maintenance")]
+pub async fn get_maintenance_status(
+    sd: web::Data<AccessSharedData>,
+    settings: web::Data<Settings>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(maintenance_status(&sd, &settings, String::new()))
+}
+
+#[post("/api/maintenance/start")]
+pub async fn start_maintenance_mode(
+    sd: web::Data<AccessSharedData>,
+    settings: web::Data<Settings>,
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    driver: web::Data<Arc<dyn RelayDriver>>,
+) -> HttpResponse {
+    let events = EventLog::new(sqlite_client.get_ref().clone(), sd.events.clone());
+    let response = match start_maintenance(
+        &sd,
+        &settings,
+        &sqlite_client,
+        &driver,
+        &events,
+        MaintenanceTrigger::Api,
+    )
+    .await
+    {
+        Ok(_) => "Maintenance mode active".to_string(),
+        Err(e) => format!("Error entering maintenance mode: {}", e),
+    };
+
+    HttpResponse::Ok().json(maintenance_status(&sd, &settings, response))
+}
+
+#[post("/api/maintenance/stop")]
+pub async fn stop_maintenance_mode(
+    sd: web::Data<AccessSharedData>,
+    settings: web::Data<Settings>,
+) -> HttpResponse {
+    if sd.door_open() {
+        return HttpResponse::Conflict().json(maintenance_status(
+            &sd,
+            &settings,
+            "Door is still open".to_string(),
+        ));
+    }
+
+    release_maintenance(&sd, OffsetDateTime::now_utc());
+    let response = if sd.maintenance_active() {
+        "Automatic control resumes after the settle delay".to_string()
+    } else {
+        "Maintenance mode is not active".to_string()
+    };
+
+    HttpResponse::Ok().json(maintenance_status(&sd, &settings, response))
+}
+
+#[get("/api/maintenance/sessions")]
+pub async fn get_maintenance_sessions(sqlite_client: web::Data<Arc<SqliteClient>>) -> HttpResponse {
+    match sqlite_client
+        .call(|db| db.read_maintenance_sessions(100))
+        .await
+    {
+        Ok(json_data) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(json_data),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
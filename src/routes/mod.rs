@@ -1,6 +1,16 @@
+pub mod admin;
 pub mod atmosphere;
+pub mod batches;
+pub mod dry_run;
+pub mod events;
+pub mod export;
 pub mod heartbeat;
+pub mod load_cell;
+pub mod maintenance;
+pub mod recipes;
 pub mod relay_control;
 pub mod relay_status;
+pub mod stream;
+pub mod v2;
 
 pub use atmosphere::get_full_atmospheric_data;
@@ -0,0 +1,212 @@
+use crate::batches::NewBatch;
+use crate::error::AtmosError;
+use crate::recipes::{Recipe, RecipeFormat};
+use crate::sqlite_client::SqliteClient;
+use crate::Arc;
+use actix_web::{delete, get, post, put, web, web::Query, HttpResponse};
+use serde::Deserialize;
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+#[derive(Deserialize)]
+pub struct NewRecipeBatch {
+    /// Batch name, the recipe name if unset
+    name: Option<String>,
+    start_weight: f32,
+}
+
+fn recipe_format(query: &HashMap<String, String>) -> Result<RecipeFormat, AtmosError> {
+    query
+        .get("format")
+        .map_or(Ok(RecipeFormat::Json), |format| format.parse())
+}
+
+// Duplicate recipe names violate the UNIQUE constraint, which is the client's fault
+fn storage_error(e: AtmosError) -> HttpResponse {
+    if e.is_constraint_violation() {
+        HttpResponse::Conflict().body("A recipe with that name already exists")
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+async fn create_recipe(sqlite_client: &Arc<SqliteClient>, recipe: Recipe) -> HttpResponse {
+    if let Err(e) = recipe.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let stored = sqlite_client
+        .call(move |db| {
+            let id = db.insert_recipe(&recipe, OffsetDateTime::now_utc())?;
+            db.read_recipe(id)
+        })
+        .await;
+    match stored {
+        Ok(Some(recipe)) => HttpResponse::Created().json(recipe),
+        Ok(None) => HttpResponse::InternalServerError().finish(),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[get("/api/recipes")]
+pub async fn get_recipes(sqlite_client: web::Data<Arc<SqliteClient>>) -> HttpResponse {
+    match sqlite_client.call(|db| db.read_recipes()).await {
+        Ok(recipes) => HttpResponse::Ok().json(recipes),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/api/recipes")]
+pub async fn post_recipe(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    recipe: web::Json<Recipe>,
+) -> HttpResponse {
+    create_recipe(&sqlite_client, recipe.into_inner()).await
+}
+
+// Takes a recipe file as the raw request body, `?format=toml` or `?format=json` (default).
+#[post("/api/recipes/import")]
+pub async fn import_recipe(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    query: Query<HashMap<String, String>>,
+    body: String,
+) -> HttpResponse {
+    let recipe = recipe_format(&query).and_then(|format| Recipe::parse(&body, format));
+    match recipe {
+        Ok(recipe) => create_recipe(&sqlite_client, recipe).await,
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[get("/api/recipes/{id}")]
+pub async fn get_recipe(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+) -> HttpResponse {
+    let id = *id;
+    match sqlite_client.call(move |db| db.read_recipe(id)).await {
+        Ok(Some(recipe)) => HttpResponse::Ok().json(recipe),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[put("/api/recipes/{id}")]
+pub async fn put_recipe(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+    recipe: web::Json<Recipe>,
+) -> HttpResponse {
+    if let Err(e) = recipe.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let (id, recipe) = (*id, recipe.into_inner());
+    let stored = sqlite_client
+        .call(move |db| {
+            if !db.update_recipe(id, &recipe, OffsetDateTime::now_utc())? {
+                return Ok(None);
+            }
+            db.read_recipe(id)
+        })
+        .await;
+    match stored {
+        Ok(Some(recipe)) => HttpResponse::Ok().json(recipe),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[delete("/api/recipes/{id}")]
+pub async fn delete_recipe(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+) -> HttpResponse {
+    let id = *id;
+    match sqlite_client.call(move |db| db.delete_recipe(id)).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// Downloads the recipe as a file other chambers can import, `?format=toml` or `?format=json`.
+#[get("/api/recipes/{id}/export")]
+pub async fn export_recipe(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+    query: Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let format = match recipe_format(&query) {
+        Ok(format) => format,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let id = *id;
+    let stored = match sqlite_client.call(move |db| db.read_recipe(id)).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let extension = match format {
+        RecipeFormat::Json => "json",
+        RecipeFormat::Toml => "toml",
+    };
+
+    match stored.recipe.export(format) {
+        Ok(content) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .insert_header((
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    stored.recipe.name.replace('"', ""),
+                    extension
+                ),
+            ))
+            .body(content),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/api/recipes/{id}/batches")]
+pub async fn start_batch_from_recipe(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+    new_batch: web::Json<NewRecipeBatch>,
+) -> HttpResponse {
+    let id = *id;
+    let stored = match sqlite_client.call(move |db| db.read_recipe(id)).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let batch = NewBatch {
+        name: new_batch
+            .name
+            .clone()
+            .unwrap_or_else(|| stored.recipe.name.clone()),
+        start_weight: new_batch.start_weight,
+        target_loss_percent: stored.recipe.target_loss_percent,
+        program: Some(stored.recipe.name.clone()),
+    };
+    if let Err(e) = batch.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    let recipe_id = stored.id;
+    let batch = sqlite_client
+        .call(move |db| {
+            let batch_id = db.create_batch(
+                batch.name.trim(),
+                batch.start_weight,
+                batch.target_loss_percent,
+                batch.program.as_deref(),
+                Some(recipe_id),
+                OffsetDateTime::now_utc(),
+            )?;
+            db.read_batch(batch_id)
+        })
+        .await;
+    match batch {
+        Ok(Some(batch)) => HttpResponse::Created().json(batch),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
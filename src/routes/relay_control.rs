@@ -1,6 +1,10 @@
 use crate::config::Settings;
-use crate::relay_ctrl::{change_relay_status, RelayStatus};
+use crate::error::AtmosError;
+use crate::events::{ActuatorEvent, EventLog, EventSource};
+use crate::relay_ctrl::{change_relay_status, Actuator, RelayStatus};
 use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::SqliteClient;
+use crate::Arc;
 use actix_web::{post, web, HttpResponse};
 use serde::Serialize;
 use time::OffsetDateTime;
@@ -14,152 +18,124 @@ struct RelayResponse {
     response: String,
 }
 
-#[post("/change_fridge_status")]
-pub async fn change_fridge_status(
-    sd: web::Data<AccessSharedData>,
-    settings: web::Data<Settings>,
+// Switches the relay and records the transition as a manual event. Shared data is only
+// updated once the relay actually switched, the override is kept until automatic control
+// switches the actuator and survives a restart.
+pub fn switch_actuator(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    sqlite_client: &Arc<SqliteClient>,
+    actuator: Actuator,
+    new_status: RelayStatus,
+    reason: &str,
+) -> Result<(), AtmosError> {
+    let prev_status = sd.actuator_status(actuator);
+    change_relay_status(actuator.pin(&settings.relay_pins), new_status)?;
+
+    let now = OffsetDateTime::now_utc();
+    EventLog::new(sqlite_client.clone(), sd.events.clone()).record(ActuatorEvent::new(
+        sd,
+        now,
+        actuator,
+        prev_status,
+        new_status,
+        EventSource::Manual,
+        reason,
+    ));
+    sd.update(|state| {
+        state.record_switch(actuator, new_status, now);
+        state.manual_overrides.insert(actuator, new_status);
+    });
+    Ok(())
+}
+
+fn toggle_actuator(
+    sd: &AccessSharedData,
+    settings: &Settings,
+    sqlite_client: &Arc<SqliteClient>,
+    actuator: Actuator,
+    label: &str,
 ) -> HttpResponse {
-    let prev_status = sd.fridge_status();
+    let prev_status = sd.actuator_status(actuator);
     let new_status = if prev_status == RelayStatus::On {
         RelayStatus::Off
     } else {
         RelayStatus::On
     };
 
-    let response = match change_relay_status(settings.relay_pins.fridge, new_status) {
-        Ok(_) => {
-            let now = OffsetDateTime::now_utc();
-            sd.set_fridge_status(new_status);
-            if new_status == RelayStatus::On {
-                sd.set_fridge_turn_on_datetime(now);
-            } else {
-                sd.set_fridge_turn_off_datetime(now);
-            }
-            format!("Fridge turned {:?}", new_status)
-        }
-        Err(e) => format!("Error changing fridge status: {}", e),
+    let response = match switch_actuator(
+        sd,
+        settings,
+        sqlite_client,
+        actuator,
+        new_status,
+        "toggled via API",
+    ) {
+        Ok(_) => format!("{} turned {:?}", label, new_status),
+        Err(e) => format!("Error changing {} status: {}", actuator, e),
     };
 
     let relay_response = RelayResponse {
         previous_status: prev_status,
-        new_status: sd.fridge_status(),
-        last_turn_on: sd.fridge_turn_on_datetime().to_string(),
-        last_turn_off: sd.fridge_turn_off_datetime().to_string(),
+        new_status: sd.actuator_status(actuator),
+        last_turn_on: sd.actuator_turn_on_datetime(actuator).to_string(),
+        last_turn_off: sd.actuator_turn_off_datetime(actuator).to_string(),
         response,
     };
 
     HttpResponse::Ok().json(relay_response)
 }
 
+#[post("/change_fridge_status")]
+pub async fn change_fridge_status(
+    sd: web::Data<AccessSharedData>,
+    settings: web::Data<Settings>,
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+) -> HttpResponse {
+    toggle_actuator(&sd, &settings, &sqlite_client, Actuator::Fridge, "Fridge")
+}
+
 #[post("/change_humidifier_status")]
 pub async fn change_humidifier_status(
     sd: web::Data<AccessSharedData>,
     settings: web::Data<Settings>,
+    sqlite_client: web::Data<Arc<SqliteClient>>,
 ) -> HttpResponse {
-    let prev_status = sd.humidifier_status();
-    let new_status = if prev_status == RelayStatus::On {
-        RelayStatus::Off
-    } else {
-        RelayStatus::On
-    };
-
-    let response = match change_relay_status(settings.relay_pins.humidifier, new_status) {
-        Ok(_) => {
-            let now = OffsetDateTime::now_utc();
-            sd.set_humidifier_status(new_status);
-            if new_status == RelayStatus::On {
-                sd.set_humidifier_turn_on_datetime(now);
-            } else {
-                sd.set_humidifier_turn_off_datetime(now);
-            }
-            format!("Humidifier turned {:?}", new_status)
-        }
-        Err(e) => format!("Error changing humidifier status: {}", e),
-    };
-
-    let relay_response = RelayResponse {
-        previous_status: prev_status,
-        new_status: sd.humidifier_status(),
-        last_turn_on: sd.humidifier_turn_on_datetime().to_string(),
-        last_turn_off: sd.humidifier_turn_off_datetime().to_string(),
-        response,
-    };
-
-    HttpResponse::Ok().json(relay_response)
+    toggle_actuator(
+        &sd,
+        &settings,
+        &sqlite_client,
+        Actuator::Humidifier,
+        "Humidifier",
+    )
 }
 
 #[post("/change_dehumidifier_status")]
 pub async fn change_dehumidifier_status(
     sd: web::Data<AccessSharedData>,
     settings: web::Data<Settings>,
+    sqlite_client: web::Data<Arc<SqliteClient>>,
 ) -> HttpResponse {
-    let prev_status = sd.dehumidifier_status();
-    let new_status = if prev_status == RelayStatus::On {
-        RelayStatus::Off
-    } else {
-        RelayStatus::On
-    };
-
-    let response = match change_relay_status(settings.relay_pins.dehumidifier, new_status) {
-        Ok(_) => {
-            let now = OffsetDateTime::now_utc();
-            sd.set_dehumidifier_status(new_status);
-            if new_status == RelayStatus::On {
-                sd.set_dehumidifier_turn_on_datetime(now);
-            } else {
-                sd.set_dehumidifier_turn_off_datetime(now);
-            }
-            format!("Dehumidifier turned {:?}", new_status)
-        }
-        Err(e) => format!("Error changing dehumidifier status: {}", e),
-    };
-
-    let relay_response = RelayResponse {
-        previous_status: prev_status,
-        new_status: sd.dehumidifier_status(),
-        last_turn_on: sd.dehumidifier_turn_on_datetime().to_string(),
-        last_turn_off: sd.dehumidifier_turn_off_datetime().to_string(),
-        response,
-    };
-
-    HttpResponse::Ok().json(relay_response)
+    toggle_actuator(
+        &sd,
+        &settings,
+        &sqlite_client,
+        Actuator::Dehumidifier,
+        "Dehumidifier",
+    )
 }
 
 #[post("/change_ventilator_status")]
 pub async fn change_ventilator_status(
     sd: web::Data<AccessSharedData>,
     settings: web::Data<Settings>,
+    sqlite_client: web::Data<Arc<SqliteClient>>,
 ) -> HttpResponse {
-    let prev_status = sd.ventilator_status();
-    let new_status = if prev_status == RelayStatus::On {
-        RelayStatus::Off
-    } else {
-        RelayStatus::On
-    };
-
-    let response = match change_relay_status(settings.relay_pins.ventilator_or_heater, new_status) {
-        Ok(_) => {
-            let now = OffsetDateTime::now_utc();
-            sd.set_ventilator_status(new_status);
-            if new_status == RelayStatus::On {
-                sd.set_ventilator_turn_on_datetime(now);
-            } else {
-                sd.set_ventilator_turn_off_datetime(now);
-            }
-            format!("Ventilator turned {:?}", new_status)
-        }
-        Err(e) => format!("Error changing ventilator status: {}", e),
-    };
-
-    let relay_response = RelayResponse {
-        previous_status: prev_status,
-        new_status: sd.ventilator_status(),
-        last_turn_on: sd.ventilator_turn_on_datetime().to_string(),
-        last_turn_off: sd.ventilator_turn_off_datetime().to_string(),
-        response,
-    };
-
-    HttpResponse::Ok().json(relay_response)
+    toggle_actuator(
+        &sd,
+        &settings,
+        &sqlite_client,
+        Actuator::Ventilator,
+        "Ventilator",
+    )
 }
-
-// Similar functions for humidifier, dehumidifier, and ventilator
@@ -0,0 +1,145 @@
+use crate::event_bus::BusEvent;
+use crate::routes::atmosphere::FullData;
+use crate::AccessSharedData;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, broadcast::error::RecvError, mpsc};
+use tokio::time::{interval_at, Duration, Instant};
+
+// Often enough to keep proxies from closing an idle connection
+const HEARTBEAT_INTERVAL: u64 = 15;
+
+// One Server-Sent Events frame
+fn sse_frame(event: &str, data: &impl Serialize) -> web::Bytes {
+    let data = serde_json::to_string(data).unwrap_or_else(|_| "null".to_string());
+    web::Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+// Ends once the client disconnects and a send fails
+async fn forward_events(
+    sd: AccessSharedData,
+    mut events: broadcast::Receiver<BusEvent>,
+    tx: mpsc::Sender<web::Bytes>,
+) {
+    let period = Duration::from_secs(HEARTBEAT_INTERVAL);
+    // The snapshot just went out, the first heartbeat is due a full period later
+    let mut heartbeat = interval_at(Instant::now() + period, period);
+    let mut frame = sse_frame("snapshot", &FullData::from(&sd.snapshot()));
+    loop {
+        if tx.send(frame).await.is_err() {
+            break;
+        }
+        frame = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => sse_frame(event.name(), &event),
+                Err(RecvError::Lagged(_)) => {
+                    sse_frame("snapshot", &FullData::from(&sd.snapshot()))
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = heartbeat.tick() => sse_frame(
+                "heartbeat",
+                &serde_json::json!({ "timestamp": OffsetDateTime::now_utc().to_string() }),
+            ),
+        };
+    }
+}
+
+// Server-Sent Events: a `snapshot` frame shaped like /api/atmosphere/full on connect, then
+// every event published on the bus as it happens (`reading_received`, `actuator_changed`,
+// `alert_raised`, `stage_advanced`) and a `heartbeat` frame every 15 seconds. A client
+// that falls behind the bus gets a fresh snapshot instead of the events it missed.
+#[get("/api/stream")]
+pub async fn get_stream(sd: web::Data<AccessSharedData>) -> HttpResponse {
+    // Subscribed before the snapshot is taken, so nothing falls between the two
+    let events = sd.events.subscribe();
+    let (tx, rx) = mpsc::channel::<web::Bytes>(16);
+    tokio::spawn(forward_events(sd.get_ref().clone(), events, tx));
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|frame| (Ok::<_, actix_web::Error>(frame), rx))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::initialize_shared_data;
+
+    fn reading(temperature: f32) -> BusEvent {
+        BusEvent::ReadingReceived {
+            timestamp: OffsetDateTime::UNIX_EPOCH.to_string(),
+            temperature_1: temperature,
+            humidity_1: 80.0,
+            temperature_2: temperature,
+            humidity_2: 80.0,
+        }
+    }
+
+    // Subscribes the way `get_stream` does and returns the frames the client would read
+    fn connect(sd: &AccessSharedData) -> mpsc::Receiver<web::Bytes> {
+        let events = sd.events.subscribe();
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(forward_events(sd.clone(), events, tx));
+        rx
+    }
+
+    fn event_name(frame: &web::Bytes) -> String {
+        let frame = std::str::from_utf8(frame).unwrap();
+        frame.lines().next().unwrap().replace("event: ", "")
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_then_bus_events() {
+        let sd = AccessSharedData::new(initialize_shared_data());
+        sd.update(|state| state.average_temp = 12.5);
+        let mut rx = connect(&sd);
+
+        let snapshot = rx.recv().await.unwrap();
+        assert_eq!(event_name(&snapshot), "snapshot");
+        let data = std::str::from_utf8(&snapshot).unwrap();
+        assert!(data.contains("12.5"), "{}", data);
+
+        sd.publish(reading(13.0));
+        let frame = rx.recv().await.unwrap();
+        assert_eq!(event_name(&frame), "reading_received");
+        assert!(std::str::from_utf8(&frame)
+            .unwrap()
+            .contains(r#""temperature_1":13.0"#));
+    }
+
+    #[tokio::test]
+    async fn test_lagging_client_gets_a_fresh_snapshot() {
+        let sd = AccessSharedData::new(initialize_shared_data());
+        let events = sd.events.subscribe();
+        // More than the bus keeps, before the client read any of them
+        for i in 0..300 {
+            sd.publish(reading(i as f32));
+        }
+        let (tx, mut rx) = mpsc::channel(16);
+        tokio::spawn(forward_events(sd.clone(), events, tx));
+
+        assert_eq!(event_name(&rx.recv().await.unwrap()), "snapshot");
+        assert_eq!(event_name(&rx.recv().await.unwrap()), "snapshot");
+        // Then carries on with the oldest event still buffered
+        assert_eq!(event_name(&rx.recv().await.unwrap()), "reading_received");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_first_heartbeat_comes_a_full_interval_after_the_snapshot() {
+        let sd = AccessSharedData::new(initialize_shared_data());
+        let connected = Instant::now();
+        let mut rx = connect(&sd);
+
+        assert_eq!(event_name(&rx.recv().await.unwrap()), "snapshot");
+        assert_eq!(event_name(&rx.recv().await.unwrap()), "heartbeat");
+        assert!(connected.elapsed() >= Duration::from_secs(HEARTBEAT_INTERVAL));
+    }
+}
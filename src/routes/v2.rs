@@ -0,0 +1,390 @@
+use crate::config::Settings;
+use crate::error::AtmosError;
+use crate::events::{EventFilter, StoredActuatorEvent};
+use crate::psychrometrics::DerivedMetrics;
+use crate::recipes::{Recipe, StoredRecipe};
+use crate::relay_ctrl::{Actuator, RelayStatus};
+use crate::risk_model::RiskAssessment;
+use crate::routes::atmosphere::{history_query, HistoryParams};
+use crate::routes::relay_control::switch_actuator;
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::{parse_timestamp, SqliteClient};
+use crate::Arc;
+use actix_web::{
+    delete, get, http::header::ContentType, post, put, web, web::Query, HttpResponse,
+    ResponseError, Scope,
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+// Version 2 of the HTTP API: one resource per noun under /api/v2 and every failure as
+// `{"error": {"code": ..., "message": ...}}`, see `AtmosError::error_response`.
+//
+//   GET    /api/v2/readings/latest
+//   GET    /api/v2/readings                 ?from&to&range&limit&offset&order
+//   GET    /api/v2/actuators
+//   GET    /api/v2/actuators/{name}
+//   PUT    /api/v2/actuators/{name}         {"status": "On" | "Off"}
+//   GET    /api/v2/settings
+//   GET    /api/v2/events                   ?actuator&source&from&to&limit
+//   GET    /api/v2/programs
+//   POST   /api/v2/programs
+//   GET    /api/v2/programs/{id}
+//   PUT    /api/v2/programs/{id}
+//   DELETE /api/v2/programs/{id}
+pub fn scope() -> Scope {
+    web::scope("/api/v2")
+        .app_data(
+            web::JsonConfig::default()
+                .error_handler(|e, _| AtmosError::InvalidInput(e.to_string()).into()),
+        )
+        .app_data(
+            web::QueryConfig::default()
+                .error_handler(|e, _| AtmosError::InvalidInput(e.to_string()).into()),
+        )
+        .app_data(
+            web::PathConfig::default()
+                .error_handler(|e, _| AtmosError::InvalidInput(e.to_string()).into()),
+        )
+        .service(get_latest_reading)
+        .service(get_readings)
+        .service(get_actuators)
+        .service(get_actuator)
+        .service(put_actuator)
+        .service(get_settings)
+        .service(get_events)
+        .service(get_programs)
+        .service(post_program)
+        .service(get_program)
+        .service(put_program)
+        .service(delete_program)
+        .default_service(web::to(|| async {
+            AtmosError::NotFound("No such resource".to_string()).error_response()
+        }))
+}
+
+// The v2 route replacing a legacy path, sent with the `Deprecation` header on responses
+// from the old routes. Paths without a v2 counterpart are not deprecated.
+pub fn successor(path: &str) -> Option<String> {
+    let successor = match path {
+        "/atmosphere" | "/api/atmosphere/full" => "/api/v2/readings/latest".to_string(),
+        "/api/atmosphere/history" => "/api/v2/readings".to_string(),
+        "/get_all_statuses" => "/api/v2/actuators".to_string(),
+        "/api/events" => "/api/v2/events".to_string(),
+        "/api/recipes" => "/api/v2/programs".to_string(),
+        _ => {
+            if let Some(actuator) = path
+                .strip_prefix("/get_")
+                .or_else(|| path.strip_prefix("/change_"))
+                .and_then(|rest| rest.strip_suffix("_status"))
+            {
+                format!("/api/v2/actuators/{}", actuator.parse::<Actuator>().ok()?)
+            } else {
+                let id = path.strip_prefix("/api/recipes/")?;
+                id.parse::<i64>().ok()?;
+                format!("/api/v2/programs/{}", id)
+            }
+        }
+    };
+    Some(successor)
+}
+
+#[derive(Serialize)]
+pub struct SensorReading {
+    id: u8,
+    temperature: f32,
+    humidity: f32,
+    #[serde(flatten)]
+    derived_metrics: DerivedMetrics,
+}
+
+#[derive(Serialize)]
+pub struct Reading {
+    timestamp: String,
+    temperature: f32,
+    humidity: f32,
+    #[serde(flatten)]
+    derived_metrics: DerivedMetrics,
+    quality_index: f32,
+    risk: RiskAssessment,
+    hanging_weight: Option<f32>,
+    sensors: [SensorReading; 2],
+}
+
+#[get("/readings/latest")]
+pub async fn get_latest_reading(sd: web::Data<AccessSharedData>) -> HttpResponse {
+    let state = sd.snapshot();
+    HttpResponse::Ok().json(Reading {
+        timestamp: state.last_reading_time.to_string(),
+        temperature: state.average_temp,
+        humidity: state.average_humidity,
+        derived_metrics: state.average_derived_metrics,
+        quality_index: state.atmospheric_quality_index,
+        risk: state.risk_assessment.clone(),
+        hanging_weight: state.hanging_weight,
+        sensors: [
+            SensorReading {
+                id: 1,
+                temperature: state.temp_1,
+                humidity: state.humidity_1,
+                derived_metrics: state.derived_metrics_1,
+            },
+            SensorReading {
+                id: 2,
+                temperature: state.temp_2,
+                humidity: state.humidity_2,
+                derived_metrics: state.derived_metrics_2,
+            },
+        ],
+    })
+}
+
+// Same paging as /api/atmosphere/history, total in the X-Total-Count header. Bucketing
+// and downsampling are still only available there.
+#[get("/readings")]
+pub async fn get_readings(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    params: Query<HistoryParams>,
+) -> Result<HttpResponse, AtmosError> {
+    let query = history_query(&params)?;
+    let (readings, total) = sqlite_client
+        .call(move |db| db.read_atmosphere_data(&query))
+        .await?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header(("X-Total-Count", total.to_string()))
+        .body(readings))
+}
+
+#[derive(Serialize)]
+pub struct ActuatorResource {
+    name: Actuator,
+    status: RelayStatus,
+    last_turn_on: String,
+    last_turn_off: String,
+}
+
+impl ActuatorResource {
+    fn new(sd: &AccessSharedData, actuator: Actuator) -> Self {
+        ActuatorResource {
+            name: actuator,
+            status: sd.actuator_status(actuator),
+            last_turn_on: sd.actuator_turn_on_datetime(actuator).to_string(),
+            last_turn_off: sd.actuator_turn_off_datetime(actuator).to_string(),
+        }
+    }
+}
+
+fn actuator(name: &str) -> Result<Actuator, AtmosError> {
+    name.parse()
+        .map_err(|_| AtmosError::NotFound(format!("No actuator named {}", name)))
+}
+
+#[get("/actuators")]
+pub async fn get_actuators(sd: web::Data<AccessSharedData>) -> HttpResponse {
+    let actuators: Vec<ActuatorResource> = Actuator::ALL
+        .iter()
+        .map(|actuator| ActuatorResource::new(&sd, *actuator))
+        .collect();
+    HttpResponse::Ok().json(actuators)
+}
+
+#[get("/actuators/{name}")]
+pub async fn get_actuator(
+    sd: web::Data<AccessSharedData>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AtmosError> {
+    let actuator = actuator(&name)?;
+    Ok(HttpResponse::Ok().json(ActuatorResource::new(&sd, actuator)))
+}
+
+#[derive(Deserialize)]
+pub struct ActuatorUpdate {
+    status: RelayStatus,
+}
+
+// Sets the relay to the requested status rather than toggling it, so a retried request
+// doesn't undo itself.
+#[put("/actuators/{name}")]
+pub async fn put_actuator(
+    sd: web::Data<AccessSharedData>,
+    settings: web::Data<Settings>,
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    name: web::Path<String>,
+    update: web::Json<ActuatorUpdate>,
+) -> Result<HttpResponse, AtmosError> {
+    let actuator = actuator(&name)?;
+    if sd.actuator_status(actuator) != update.status {
+        switch_actuator(
+            &sd,
+            &settings,
+            &sqlite_client,
+            actuator,
+            update.status,
+            "set via API",
+        )?;
+    }
+    Ok(HttpResponse::Ok().json(ActuatorResource::new(&sd, actuator)))
+}
+
+// The settings the controller was started with
+#[get("/settings")]
+pub async fn get_settings(settings: web::Data<Settings>) -> HttpResponse {
+    HttpResponse::Ok().json(settings.get_ref())
+}
+
+#[get("/events")]
+pub async fn get_events(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    filter: Query<EventFilter>,
+) -> Result<web::Json<Vec<StoredActuatorEvent>>, AtmosError> {
+    let from = filter.from.as_deref().map(parse_timestamp).transpose()?;
+    let to = filter.to.as_deref().map(parse_timestamp).transpose()?;
+    let filter = filter.into_inner();
+    let events = sqlite_client
+        .call(move |db| db.read_actuator_events(&filter, from, to))
+        .await?;
+    Ok(web::Json(events))
+}
+
+fn program_not_found(id: i64) -> AtmosError {
+    AtmosError::NotFound(format!("No program with id {}", id))
+}
+
+// Duplicate names violate the UNIQUE constraint
+fn duplicate_program(e: AtmosError) -> AtmosError {
+    if e.is_constraint_violation() {
+        AtmosError::Conflict("A program with that name already exists".to_string())
+    } else {
+        e
+    }
+}
+
+#[get("/programs")]
+pub async fn get_programs(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+) -> Result<web::Json<Vec<StoredRecipe>>, AtmosError> {
+    let programs = sqlite_client.call(|db| db.read_recipes()).await?;
+    Ok(web::Json(programs))
+}
+
+#[post("/programs")]
+pub async fn post_program(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    program: web::Json<Recipe>,
+) -> Result<HttpResponse, AtmosError> {
+    program.validate()?;
+    let program = program.into_inner();
+    let stored = sqlite_client
+        .call(move |db| {
+            let id = db
+                .insert_recipe(&program, OffsetDateTime::now_utc())
+                .map_err(duplicate_program)?;
+            db.read_recipe(id)?.ok_or_else(|| program_not_found(id))
+        })
+        .await?;
+    Ok(HttpResponse::Created().json(stored))
+}
+
+#[get("/programs/{id}")]
+pub async fn get_program(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+) -> Result<web::Json<StoredRecipe>, AtmosError> {
+    let id = id.into_inner();
+    let program = sqlite_client.call(move |db| db.read_recipe(id)).await?;
+    program.map(web::Json).ok_or_else(|| program_not_found(id))
+}
+
+#[put("/programs/{id}")]
+pub async fn put_program(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+    program: web::Json<Recipe>,
+) -> Result<web::Json<StoredRecipe>, AtmosError> {
+    program.validate()?;
+    let (id, program) = (id.into_inner(), program.into_inner());
+    let stored = sqlite_client
+        .call(move |db| {
+            if !db
+                .update_recipe(id, &program, OffsetDateTime::now_utc())
+                .map_err(duplicate_program)?
+            {
+                return Err(program_not_found(id));
+            }
+            db.read_recipe(id)?.ok_or_else(|| program_not_found(id))
+        })
+        .await?;
+    Ok(web::Json(stored))
+}
+
+#[delete("/programs/{id}")]
+pub async fn delete_program(
+    sqlite_client: web::Data<Arc<SqliteClient>>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, AtmosError> {
+    let id = id.into_inner();
+    if !sqlite_client.call(move |db| db.delete_recipe(id)).await? {
+        return Err(program_not_found(id));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipes::RecipeFormat;
+
+    #[test]
+    fn test_successor() {
+        let cases = [
+            ("/atmosphere", Some("/api/v2/readings/latest")),
+            ("/api/atmosphere/full", Some("/api/v2/readings/latest")),
+            ("/api/atmosphere/history", Some("/api/v2/readings")),
+            ("/get_all_statuses", Some("/api/v2/actuators")),
+            ("/get_fridge_status", Some("/api/v2/actuators/fridge")),
+            (
+                "/change_dehumidifier_status",
+                Some("/api/v2/actuators/dehumidifier"),
+            ),
+            ("/api/events", Some("/api/v2/events")),
+            ("/api/recipes", Some("/api/v2/programs")),
+            ("/api/recipes/12", Some("/api/v2/programs/12")),
+            // No v2 counterpart
+            ("/get_heater_status", None),
+            ("/api/recipes/12/export", None),
+            ("/api/recipes/import", None),
+            ("/api/batches", None),
+            ("/api/v2/readings", None),
+        ];
+        for (path, successor_path) in cases {
+            assert_eq!(
+                successor(path).as_deref(),
+                successor_path,
+                "successor of {}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_duplicate_program_is_a_conflict() {
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        let program = Recipe::parse(
+            r#"{"name": "coppa", "target_loss_percent": 33.0, "stages": [{"name": "drying",
+                "duration_days": 60.0, "temperature_start": 11.0, "temperature_end": 14.0,
+                "humidity_start": 70.0, "humidity_end": 80.0}]}"#,
+            RecipeFormat::Json,
+        )
+        .unwrap();
+        let now = OffsetDateTime::now_utc();
+        sqlite_client.insert_recipe(&program, now).unwrap();
+
+        let error = duplicate_program(sqlite_client.insert_recipe(&program, now).unwrap_err());
+        assert!(matches!(error, AtmosError::Conflict(_)), "{:?}", error);
+        assert_eq!(error.status_code(), actix_web::http::StatusCode::CONFLICT);
+        // Anything else passes through unchanged
+        let error = duplicate_program(AtmosError::NotFound("Program 3".to_string()));
+        assert!(matches!(error, AtmosError::NotFound(_)));
+    }
+}
@@ -1,61 +1,96 @@
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use time::OffsetDateTime;
+use tokio::sync::{watch, Mutex, MutexGuard};
 
-use crate::relay_ctrl::RelayStatus;
+use crate::event_bus::{BusEvent, EventBus};
+use crate::psychrometrics::DerivedMetrics;
+use crate::quality_index::{QualityIndexComponents, QualityIndexState};
+use crate::relay_ctrl::{Actuator, RelayStatus};
+use crate::risk_model::RiskAssessment;
 
-// A struct to hold the values that will be shared across all threads in the application
-pub struct SharedData {
+// A snapshot of everything the tasks share: sensor readings, relay statuses and timers.
+// Published through a watch channel, so a reader always sees the values of one update.
+#[derive(Debug, Clone)]
+pub struct AtmosphereState {
     /// Number of times the sensors have been polled
-    polling_iterations: u64,
+    pub polling_iterations: u64,
     /// Temperature reading from the first sensor (in Celsius)
-    temp_1: f32,
+    pub temp_1: f32,
     /// Humidity reading from the first sensor (in percentage)
-    humidity_1: f32,
+    pub humidity_1: f32,
     /// Temperature reading from the second sensor (in Celsius)
-    temp_2: f32,
+    pub temp_2: f32,
     /// Humidity reading from the second sensor (in percentage)
-    humidity_2: f32,
+    pub humidity_2: f32,
     /// Average temperature from both sensors (in Celsius)
-    average_temp: f32,
+    pub average_temp: f32,
     /// Average humidity from both sensors (in percentage)
-    average_humidity: f32,
+    pub average_humidity: f32,
     /// Calculated atmospheric quality index
-    atmospheric_quality_index: f32,
+    pub atmospheric_quality_index: f32,
+    /// Breakdown of the atmospheric quality index
+    pub quality_index_components: QualityIndexComponents,
+    /// History the quality index carries between ticks
+    pub quality_index_state: QualityIndexState,
+    /// Weight hanging from the load cell (in grams), if one is fitted
+    pub hanging_weight: Option<f32>,
+    /// Latest mold and case-hardening risk indicators
+    pub risk_assessment: RiskAssessment,
+    /// Dew point, absolute humidity and VPD from the first sensor
+    pub derived_metrics_1: DerivedMetrics,
+    /// Dew point, absolute humidity and VPD from the second sensor
+    pub derived_metrics_2: DerivedMetrics,
+    /// Average of the derived metrics from both sensors
+    pub average_derived_metrics: DerivedMetrics,
     /// Current status of the fridge (true if on, false if off)
-    fridge_status: RelayStatus,
+    pub fridge_status: RelayStatus,
     /// Current status of the humidifier (true if on, false if off)
-    humidifier_status: RelayStatus,
+    pub humidifier_status: RelayStatus,
     /// Current status of the ventilator (true if on, false if off)
-    ventilator_status: RelayStatus,
+    pub ventilator_status: RelayStatus,
     /// Current status of the dehumidifier (true if on, false if off)
-    dehumidifier_status: RelayStatus,
+    pub dehumidifier_status: RelayStatus,
     /// Current status of the heater (true if on, false if off)
-    heater_status: RelayStatus,
+    pub heater_status: RelayStatus,
     /// Timestamp of the last sensor reading
-    last_reading_time: OffsetDateTime,
+    pub last_reading_time: OffsetDateTime,
     /// Timestamp when the fridge was last turned on
-    fridge_turn_on_datetime: OffsetDateTime,
+    pub fridge_turn_on_datetime: OffsetDateTime,
     /// Timestamp when the fridge was last turned off
-    fridge_turn_off_datetime: OffsetDateTime,
+    pub fridge_turn_off_datetime: OffsetDateTime,
     /// Timestamp when the humidifier was last turned on
-    humidifier_turn_on_datetime: OffsetDateTime,
+    pub humidifier_turn_on_datetime: OffsetDateTime,
     /// Timestamp when the humidifier was last turned off
-    humidifier_turn_off_datetime: OffsetDateTime,
+    pub humidifier_turn_off_datetime: OffsetDateTime,
     /// Timestamp when the dehumidifier was last turned on
-    dehumidifier_turn_on_datetime: OffsetDateTime,
+    pub dehumidifier_turn_on_datetime: OffsetDateTime,
     /// Timestamp when the dehumidifier was last turned off
-    dehumidifier_turn_off_datetime: OffsetDateTime,
+    pub dehumidifier_turn_off_datetime: OffsetDateTime,
     /// Timestamp when the ventilator was last turned on
-    ventilator_turn_on_datetime: OffsetDateTime,
+    pub ventilator_turn_on_datetime: OffsetDateTime,
     /// Timestamp when the ventilator was last turned off
-    ventilator_turn_off_datetime: OffsetDateTime,
+    pub ventilator_turn_off_datetime: OffsetDateTime,
     /// Timestamp when the heater was last turned on
-    heater_turn_on_datetime: OffsetDateTime,
+    pub heater_turn_on_datetime: OffsetDateTime,
     /// Timestamp when the heater was last turned off
-    heater_turn_off_datetime: OffsetDateTime,
+    pub heater_turn_off_datetime: OffsetDateTime,
+    /// Whether automatic control is paused for maintenance (door open, weighing, ...)
+    pub maintenance_active: bool,
+    /// Whether the chamber door reed switch currently reports the door as open
+    pub door_open: bool,
+    /// Timestamp when the current maintenance session started
+    pub maintenance_start_datetime: OffsetDateTime,
+    /// Timestamp from which the settle delay is counted (door closed or API release)
+    pub maintenance_release_datetime: Option<OffsetDateTime>,
+    /// Row id of the current maintenance session in SQLite
+    pub maintenance_session_id: Option<i64>,
+    /// Statuses set through the API that automatic control hasn't changed since
+    pub manual_overrides: BTreeMap<Actuator, RelayStatus>,
 }
 
-impl SharedData {
+impl AtmosphereState {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         polling_iterations: u64,
         temp_1: f32,
@@ -81,8 +116,8 @@ impl SharedData {
         heater_turn_off_datetime: OffsetDateTime,
         ventilator_turn_on_datetime: OffsetDateTime,
         ventilator_turn_off_datetime: OffsetDateTime,
-    ) -> SharedData {
-        SharedData {
+    ) -> AtmosphereState {
+        AtmosphereState {
             polling_iterations,
             temp_1,
             humidity_1,
@@ -91,6 +126,13 @@ impl SharedData {
             average_temp,
             average_humidity,
             atmospheric_quality_index,
+            quality_index_components: QualityIndexComponents::default(),
+            quality_index_state: QualityIndexState::default(),
+            hanging_weight: None,
+            risk_assessment: RiskAssessment::default(),
+            derived_metrics_1: DerivedMetrics::default(),
+            derived_metrics_2: DerivedMetrics::default(),
+            average_derived_metrics: DerivedMetrics::default(),
             fridge_status,
             humidifier_status,
             ventilator_status,
@@ -107,13 +149,63 @@ impl SharedData {
             ventilator_turn_off_datetime,
             heater_turn_on_datetime,
             heater_turn_off_datetime,
+            maintenance_active: false,
+            door_open: false,
+            maintenance_start_datetime: OffsetDateTime::UNIX_EPOCH,
+            maintenance_release_datetime: None,
+            maintenance_session_id: None,
+            manual_overrides: BTreeMap::new(),
+        }
+    }
+
+    pub fn actuator_status(&self, actuator: Actuator) -> RelayStatus {
+        match actuator {
+            Actuator::Fridge => self.fridge_status,
+            Actuator::Humidifier => self.humidifier_status,
+            Actuator::Dehumidifier => self.dehumidifier_status,
+            Actuator::Ventilator => self.ventilator_status,
+        }
+    }
+
+    // Sets the status along with the timestamp the cooldowns are counted from
+    pub fn record_switch(&mut self, actuator: Actuator, status: RelayStatus, now: OffsetDateTime) {
+        let (current, turned_on, turned_off) = match actuator {
+            Actuator::Fridge => (
+                &mut self.fridge_status,
+                &mut self.fridge_turn_on_datetime,
+                &mut self.fridge_turn_off_datetime,
+            ),
+            Actuator::Humidifier => (
+                &mut self.humidifier_status,
+                &mut self.humidifier_turn_on_datetime,
+                &mut self.humidifier_turn_off_datetime,
+            ),
+            Actuator::Dehumidifier => (
+                &mut self.dehumidifier_status,
+                &mut self.dehumidifier_turn_on_datetime,
+                &mut self.dehumidifier_turn_off_datetime,
+            ),
+            Actuator::Ventilator => (
+                &mut self.ventilator_status,
+                &mut self.ventilator_turn_on_datetime,
+                &mut self.ventilator_turn_off_datetime,
+            ),
+        };
+        *current = status;
+        match status {
+            RelayStatus::On => *turned_on = now,
+            RelayStatus::Off => *turned_off = now,
         }
     }
 }
 
 // The struct that will be used to manage access to the shared data struct.
 pub struct AccessSharedData {
-    pub sd: Arc<Mutex<SharedData>>,
+    pub sd: Arc<watch::Sender<AtmosphereState>>,
+    /// Where the tasks sharing this state publish what happens to it
+    pub events: EventBus,
+    /// Held while automatic control switches a relay and while maintenance mode starts
+    pub relays: Arc<Mutex<()>>,
 }
 
 // Clone here just makes a copy of the Arc pointer - not  the entire class of data
@@ -122,228 +214,217 @@ impl Clone for AccessSharedData {
     fn clone(&self) -> Self {
         AccessSharedData {
             sd: Arc::clone(&self.sd),
+            events: self.events.clone(),
+            relays: Arc::clone(&self.relays),
         }
     }
 }
 
-// Getters/Setters for access to the shared data. Everything is wrapped in a MutexGuard to
-// ensure thread safety for every access point.
 impl AccessSharedData {
-    pub fn polling_iterations(&self) -> u64 {
-        let lock = self.sd.lock().unwrap();
-        lock.polling_iterations
+    pub fn new(state: AtmosphereState) -> Self {
+        AccessSharedData {
+            sd: Arc::new(watch::Sender::new(state)),
+            events: EventBus::default(),
+            relays: Arc::default(),
+        }
     }
-    pub fn increment_polling_iterations(&self) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.polling_iterations += 1;
+
+    // A consistent copy of every field, taken in one borrow
+    pub fn snapshot(&self) -> AtmosphereState {
+        self.sd.borrow().clone()
     }
 
-    pub fn temp_one(&self) -> f32 {
-        let lock = self.sd.lock().unwrap();
-        lock.temp_1
+    // Applies several changes as one update, readers never see part of them
+    pub fn update(&self, f: impl FnOnce(&mut AtmosphereState)) {
+        self.sd.send_modify(f);
     }
-    pub fn set_temp_one(&self, new_val: f32) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.temp_1 = new_val;
+
+    // Receives a new snapshot after every update, for consumers that wait on changes
+    // instead of polling
+    pub fn subscribe(&self) -> watch::Receiver<AtmosphereState> {
+        self.sd.subscribe()
     }
 
-    pub fn humidity_one(&self) -> f32 {
-        let lock = self.sd.lock().unwrap();
-        lock.humidity_1
+    // Taken by automatic control around each switch, so a handler that was asleep or
+    // running alongside `start_maintenance` can't switch a relay maintenance mode just
+    // took over. `None` once maintenance is active, the relays are left alone then.
+    pub async fn lock_for_control(&self) -> Option<MutexGuard<'_, ()>> {
+        let guard = self.relays.lock().await;
+        (!self.maintenance_active()).then_some(guard)
     }
-    pub fn set_humidity_one(&self, new_val: f32) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.humidity_1 = new_val;
+
+    pub fn publish(&self, event: BusEvent) {
+        self.events.publish(event);
     }
+}
 
-    pub fn temp_two(&self) -> f32 {
-        let lock = self.sd.lock().unwrap();
-        lock.temp_2
+// Getters for single fields, use `snapshot` when several need to be consistent with each
+// other. Every change goes through `update`, so fields changed together are published
+// together.
+impl AccessSharedData {
+    pub fn polling_iterations(&self) -> u64 {
+        self.sd.borrow().polling_iterations
     }
-    pub fn set_temp_two(&self, new_val: f32) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.temp_2 = new_val;
+
+    pub fn temp_one(&self) -> f32 {
+        self.sd.borrow().temp_1
     }
 
-    pub fn humidity_two(&self) -> f32 {
-        let lock = self.sd.lock().unwrap();
-        lock.humidity_2
+    pub fn humidity_one(&self) -> f32 {
+        self.sd.borrow().humidity_1
     }
-    pub fn set_humidity_two(&self, new_val: f32) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.humidity_2 = new_val;
+
+    pub fn temp_two(&self) -> f32 {
+        self.sd.borrow().temp_2
     }
 
-    pub fn average_temp(&self) -> f32 {
-        let lock = self.sd.lock().unwrap();
-        lock.average_temp
+    pub fn humidity_two(&self) -> f32 {
+        self.sd.borrow().humidity_2
     }
-    pub fn set_average_temp(&self, new_val: f32) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.average_temp = new_val;
+
+    pub fn average_temp(&self) -> f32 {
+        self.sd.borrow().average_temp
     }
 
     pub fn average_humidity(&self) -> f32 {
-        let lock = self.sd.lock().unwrap();
-        lock.average_humidity
-    }
-    pub fn set_average_humidity(&self, new_val: f32) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.average_humidity = new_val;
+        self.sd.borrow().average_humidity
     }
 
     pub fn atmosphere_quality_index(&self) -> f32 {
-        let lock = self.sd.lock().unwrap();
-        lock.atmospheric_quality_index
+        self.sd.borrow().atmospheric_quality_index
     }
-    pub fn set_atmosphere_quality_index(&self, new_val: f32) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.atmospheric_quality_index = new_val;
+
+    pub fn quality_index_components(&self) -> QualityIndexComponents {
+        self.sd.borrow().quality_index_components
     }
 
-    pub fn fridge_status(&self) -> RelayStatus {
-        let lock = self.sd.lock().unwrap();
-        lock.fridge_status
+    pub fn quality_index_state(&self) -> QualityIndexState {
+        self.sd.borrow().quality_index_state
     }
-    pub fn set_fridge_status(&self, new_val: RelayStatus) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.fridge_status = new_val;
+
+    pub fn hanging_weight(&self) -> Option<f32> {
+        self.sd.borrow().hanging_weight
     }
 
-    pub fn humidifier_status(&self) -> RelayStatus {
-        let lock = self.sd.lock().unwrap();
-        lock.humidifier_status
+    pub fn risk_assessment(&self) -> RiskAssessment {
+        self.sd.borrow().risk_assessment.clone()
     }
-    pub fn set_humidifier_status(&self, new_val: RelayStatus) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.humidifier_status = new_val;
+
+    pub fn derived_metrics_one(&self) -> DerivedMetrics {
+        self.sd.borrow().derived_metrics_1
     }
-    pub fn dehumidifier_status(&self) -> RelayStatus {
-        let lock = self.sd.lock().unwrap();
-        lock.dehumidifier_status
+
+    pub fn derived_metrics_two(&self) -> DerivedMetrics {
+        self.sd.borrow().derived_metrics_2
     }
-    pub fn set_dehumidifier_status(&self, new_val: RelayStatus) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.dehumidifier_status = new_val;
+
+    pub fn average_derived_metrics(&self) -> DerivedMetrics {
+        self.sd.borrow().average_derived_metrics
     }
-    pub fn heater_status(&self) -> RelayStatus {
-        let lock = self.sd.lock().unwrap();
-        lock.heater_status
+
+    pub fn fridge_status(&self) -> RelayStatus {
+        self.sd.borrow().fridge_status
+    }
+
+    pub fn humidifier_status(&self) -> RelayStatus {
+        self.sd.borrow().humidifier_status
+    }
+    pub fn dehumidifier_status(&self) -> RelayStatus {
+        self.sd.borrow().dehumidifier_status
     }
-    pub fn set_heater_status(&self, new_val: RelayStatus) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.heater_status = new_val;
+    pub fn heater_status(&self) -> RelayStatus {
+        self.sd.borrow().heater_status
     }
 
     pub fn last_reading_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.last_reading_time
-    }
-    pub fn set_last_reading_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.last_reading_time = dt;
+        self.sd.borrow().last_reading_time
     }
 
     pub fn fridge_turn_on_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.fridge_turn_on_datetime
-    }
-    pub fn set_fridge_turn_on_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.fridge_turn_on_datetime = dt;
+        self.sd.borrow().fridge_turn_on_datetime
     }
 
     pub fn fridge_turn_off_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.fridge_turn_off_datetime
-    }
-    pub fn set_fridge_turn_off_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.fridge_turn_off_datetime = dt;
+        self.sd.borrow().fridge_turn_off_datetime
     }
 
     pub fn humidifier_turn_on_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.humidifier_turn_on_datetime
-    }
-    pub fn set_humidifier_turn_on_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.humidifier_turn_on_datetime = dt;
+        self.sd.borrow().humidifier_turn_on_datetime
     }
 
     pub fn humidifier_turn_off_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.humidifier_turn_off_datetime
-    }
-    pub fn set_humidifier_turn_off_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.humidifier_turn_off_datetime = dt;
+        self.sd.borrow().humidifier_turn_off_datetime
     }
 
     pub fn ventilator_status(&self) -> RelayStatus {
-        let lock = self.sd.lock().unwrap();
-        lock.ventilator_status
-    }
-    pub fn set_ventilator_status(&self, new_val: RelayStatus) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.ventilator_status = new_val;
+        self.sd.borrow().ventilator_status
     }
 
     pub fn dehumidifier_turn_on_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.dehumidifier_turn_on_datetime
-    }
-    pub fn set_dehumidifier_turn_on_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.dehumidifier_turn_on_datetime = dt;
+        self.sd.borrow().dehumidifier_turn_on_datetime
     }
 
     pub fn dehumidifier_turn_off_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.dehumidifier_turn_off_datetime
-    }
-    pub fn set_dehumidifier_turn_off_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.dehumidifier_turn_off_datetime = dt;
+        self.sd.borrow().dehumidifier_turn_off_datetime
     }
 
     pub fn ventilator_turn_on_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.ventilator_turn_on_datetime
+        self.sd.borrow().ventilator_turn_on_datetime
     }
 
-    pub fn set_ventilator_turn_on_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.ventilator_turn_on_datetime = dt;
+    pub fn ventilator_turn_off_datetime(&self) -> OffsetDateTime {
+        self.sd.borrow().ventilator_turn_off_datetime
     }
 
-    pub fn ventilator_turn_off_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.ventilator_turn_off_datetime
+    pub fn heater_turn_on_datetime(&self) -> OffsetDateTime {
+        self.sd.borrow().heater_turn_on_datetime
     }
 
-    pub fn set_ventilator_turn_off_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.ventilator_turn_off_datetime = dt;
+    pub fn heater_turn_off_datetime(&self) -> OffsetDateTime {
+        self.sd.borrow().heater_turn_off_datetime
     }
 
-    pub fn heater_turn_on_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.heater_turn_on_datetime
+    pub fn maintenance_active(&self) -> bool {
+        self.sd.borrow().maintenance_active
     }
 
-    pub fn set_heater_turn_on_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.heater_turn_on_datetime = dt;
+    pub fn door_open(&self) -> bool {
+        self.sd.borrow().door_open
     }
 
-    pub fn heater_turn_off_datetime(&self) -> OffsetDateTime {
-        let lock = self.sd.lock().unwrap();
-        lock.heater_turn_off_datetime
+    pub fn maintenance_start_datetime(&self) -> OffsetDateTime {
+        self.sd.borrow().maintenance_start_datetime
+    }
+
+    pub fn maintenance_release_datetime(&self) -> Option<OffsetDateTime> {
+        self.sd.borrow().maintenance_release_datetime
     }
 
-    pub fn set_heater_turn_off_datetime(&self, dt: OffsetDateTime) {
-        let mut lock = self.sd.lock().unwrap();
-        lock.heater_turn_off_datetime = dt;
+    pub fn maintenance_session_id(&self) -> Option<i64> {
+        self.sd.borrow().maintenance_session_id
+    }
+
+    pub fn actuator_status(&self, actuator: Actuator) -> RelayStatus {
+        self.sd.borrow().actuator_status(actuator)
+    }
+
+    pub fn actuator_turn_on_datetime(&self, actuator: Actuator) -> OffsetDateTime {
+        let state = self.sd.borrow();
+        match actuator {
+            Actuator::Fridge => state.fridge_turn_on_datetime,
+            Actuator::Humidifier => state.humidifier_turn_on_datetime,
+            Actuator::Dehumidifier => state.dehumidifier_turn_on_datetime,
+            Actuator::Ventilator => state.ventilator_turn_on_datetime,
+        }
+    }
+
+    pub fn actuator_turn_off_datetime(&self, actuator: Actuator) -> OffsetDateTime {
+        let state = self.sd.borrow();
+        match actuator {
+            Actuator::Fridge => state.fridge_turn_off_datetime,
+            Actuator::Humidifier => state.humidifier_turn_off_datetime,
+            Actuator::Dehumidifier => state.dehumidifier_turn_off_datetime,
+            Actuator::Ventilator => state.ventilator_turn_off_datetime,
+        }
     }
 }
@@ -0,0 +1,153 @@
+use crate::config::Settings;
+use crate::controller_state::ControllerState;
+use crate::error::AtmosError;
+use crate::events::EventLog;
+use crate::initialization::deinitialize_relay_pins;
+use crate::relay_ctrl::RelayDriver;
+use crate::shared_data::AccessSharedData;
+use crate::sqlite_client::SqliteClient;
+use log::info;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+// Stops the tasks first so none of them switches a relay back on, then switches every
+// relay off and writes out what was buffered and the controller state, both of which
+// include the shutdown. The relays are switched off even if a task failed.
+pub async fn shut_down(
+    shutdown_tx: &broadcast::Sender<()>,
+    main_task: JoinHandle<Result<(), AtmosError>>,
+    settings: &Settings,
+    sd: &AccessSharedData,
+    sqlite_client: &Arc<SqliteClient>,
+    driver: &dyn RelayDriver,
+    events: &EventLog,
+) -> Result<(), AtmosError> {
+    info!("Sending shutdown signal");
+    let _ = shutdown_tx.send(());
+    let tasks = main_task
+        .await
+        .map_err(|e| AtmosError::TaskJoinError(e.to_string()))
+        .and_then(|result| result);
+
+    let relays = deinitialize_relay_pins(settings, sd, driver, events);
+
+    sqlite_client.call(|db| db.flush_write_buffer()).await?;
+    if settings.controller_state.enabled {
+        let state = ControllerState::capture(sd, OffsetDateTime::now_utc());
+        sqlite_client
+            .call(move |db| db.save_controller_state(&state))
+            .await?;
+    }
+    relays?;
+    tasks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{test_settings, WriteBufferSettings};
+    use crate::events::{EventFilter, EventSource};
+    use crate::initialization::initialize_shared_data;
+    use crate::mock_relay_ctrl::MockRelayDriver;
+    use crate::relay_ctrl::{Actuator, RelayStatus};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_shutdown_switches_relays_off_and_saves_everything() {
+        let mut settings = test_settings();
+        settings.controller_state.enabled = true;
+        let pins = settings.relay_pins.clone();
+        let sqlite_client = Arc::new(SqliteClient::new(":memory:").unwrap());
+        sqlite_client
+            .configure_write_buffer(&WriteBufferSettings {
+                enabled: true,
+                flush_interval: 3600,
+                ..WriteBufferSettings::default()
+            })
+            .unwrap();
+        let sd = AccessSharedData::new(initialize_shared_data());
+        let events = EventLog::new(sqlite_client.clone(), sd.events.clone());
+        let driver = MockRelayDriver::default();
+        driver
+            .change_relay_status(Actuator::Fridge, pins.fridge, RelayStatus::On)
+            .unwrap();
+        sd.update(|state| state.fridge_status = RelayStatus::On);
+
+        // Stands in for `run_main`, which only returns once it got the signal
+        let (shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+        let main_task = tokio::spawn(async move {
+            let _ = shutdown_rx.recv().await;
+            Ok(())
+        });
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            shut_down(
+                &shutdown_tx,
+                main_task,
+                &settings,
+                &sd,
+                &sqlite_client,
+                &driver,
+                &events,
+            ),
+        )
+        .await
+        .expect("shutdown hung")
+        .unwrap();
+
+        assert_eq!(driver.status(pins.fridge), RelayStatus::Off);
+        assert_eq!(sd.fridge_status(), RelayStatus::Off);
+        // The shutdown event was flushed despite the hour long flush interval
+        let shutdown_events = sqlite_client
+            .read_actuator_events(
+                &EventFilter {
+                    source: Some(EventSource::Shutdown),
+                    ..EventFilter::default()
+                },
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(shutdown_events.len(), 1);
+        assert_eq!(shutdown_events[0].event.actuator, Actuator::Fridge);
+        // Saved after the relays went off, so the fridge cooldown starts now
+        let state = sqlite_client.read_controller_state().unwrap().unwrap();
+        assert_eq!(state.fridge.status, RelayStatus::Off);
+        assert_eq!(
+            state.fridge.turned_off_at,
+            sd.fridge_turn_off_datetime().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relays_are_switched_off_when_a_task_failed() {
+        let settings = test_settings();
+        let pins = &settings.relay_pins;
+        let sqlite_client = Arc::new(SqliteClient::new(":memory:").unwrap());
+        let sd = AccessSharedData::new(initialize_shared_data());
+        let driver = MockRelayDriver::default();
+        driver
+            .change_relay_status(Actuator::Humidifier, pins.humidifier, RelayStatus::On)
+            .unwrap();
+
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let main_task =
+            tokio::spawn(async { Err(AtmosError::SensorError("sensor gone".to_string())) });
+
+        let result = shut_down(
+            &shutdown_tx,
+            main_task,
+            &settings,
+            &sd,
+            &sqlite_client,
+            &driver,
+            &EventLog::disabled(),
+        )
+        .await;
+        assert!(matches!(result, Err(AtmosError::SensorError(_))));
+        assert_eq!(driver.status(pins.humidifier), RelayStatus::Off);
+    }
+}
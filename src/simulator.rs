@@ -0,0 +1,287 @@
+use crate::clock::Clock;
+use crate::config::Settings;
+use crate::error::AtmosError;
+use crate::read_atmosphere::record_sensor_readings;
+use crate::relay_ctrl::{Actuator, RelayDriver, RelayStatus};
+use crate::shared_data::AccessSharedData;
+use log::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{interval, Duration};
+
+#[derive(Debug, Clone)]
+pub struct ChamberParameters {
+    /// Fraction of the chamber/ambient temperature difference closed per hour
+    pub thermal_coupling: f32,
+    /// Fraction of the chamber/ambient humidity difference closed per hour
+    pub humidity_coupling: f32,
+    /// Cooling while the fridge compressor runs (°C per hour)
+    pub fridge_cooling_rate: f32,
+    /// Moisture condensed on the evaporator while the fridge runs (%RH per hour)
+    pub fridge_drying_rate: f32,
+    /// Moisture added per second of humidifier run time (%RH per second)
+    pub humidifier_output: f32,
+    /// Moisture extracted while the dehumidifier runs (%RH per hour)
+    pub dehumidifier_extraction: f32,
+    /// Extra ambient coupling while the ventilator runs (per hour)
+    pub ventilation_exchange: f32,
+    /// Moisture released by the drying product (%RH per hour)
+    pub product_moisture: f32,
+    /// How far sensor two reads from sensor one (°C, %RH)
+    pub sensor_offset: (f32, f32),
+}
+
+impl Default for ChamberParameters {
+    fn default() -> Self {
+        ChamberParameters {
+            thermal_coupling: 0.5,
+            humidity_coupling: 0.3,
+            fridge_cooling_rate: 10.0,
+            fridge_drying_rate: 6.0,
+            humidifier_output: 2.0,
+            dehumidifier_extraction: 20.0,
+            ventilation_exchange: 2.0,
+            product_moisture: 4.0,
+            sensor_offset: (0.2, -1.0),
+        }
+    }
+}
+
+// Lumped model of the curing chamber: one temperature and one humidity coupled to the
+// ambient air, pushed around by whichever actuators are currently switched on.
+#[derive(Debug, Clone)]
+pub struct ChamberModel {
+    pub parameters: ChamberParameters,
+    pub ambient_temperature: f32,
+    pub ambient_humidity: f32,
+    pub temperature: f32,
+    pub humidity: f32,
+    actuators: HashMap<Actuator, RelayStatus>,
+}
+
+impl ChamberModel {
+    pub fn new(
+        parameters: ChamberParameters,
+        ambient_temperature: f32,
+        ambient_humidity: f32,
+    ) -> Self {
+        ChamberModel {
+            parameters,
+            ambient_temperature,
+            ambient_humidity,
+            temperature: ambient_temperature,
+            humidity: ambient_humidity,
+            actuators: HashMap::new(),
+        }
+    }
+
+    pub fn actuator(&self, actuator: Actuator) -> RelayStatus {
+        *self.actuators.get(&actuator).unwrap_or(&RelayStatus::Off)
+    }
+
+    pub fn set_actuator(&mut self, actuator: Actuator, status: RelayStatus) {
+        self.actuators.insert(actuator, status);
+    }
+
+    pub fn step(&mut self, dt: Duration) {
+        let p = &self.parameters;
+        let seconds = dt.as_secs_f32();
+        let hours = seconds / 3600.0;
+        let on = |actuator| {
+            if self.actuator(actuator) == RelayStatus::On {
+                1.0
+            } else {
+                0.0
+            }
+        };
+
+        let coupling = on(Actuator::Ventilator) * p.ventilation_exchange;
+        let temperature_delta =
+            (self.ambient_temperature - self.temperature) * (p.thermal_coupling + coupling) * hours
+                - on(Actuator::Fridge) * p.fridge_cooling_rate * hours;
+        let humidity_delta =
+            (self.ambient_humidity - self.humidity) * (p.humidity_coupling + coupling) * hours
+                + p.product_moisture * hours
+                - on(Actuator::Fridge) * p.fridge_drying_rate * hours
+                - on(Actuator::Dehumidifier) * p.dehumidifier_extraction * hours
+                + on(Actuator::Humidifier) * p.humidifier_output * seconds;
+
+        self.temperature += temperature_delta;
+        self.humidity = (self.humidity + humidity_delta).clamp(0.0, 100.0);
+    }
+
+    pub fn sensor_readings(&self) -> (f32, f32, f32, f32) {
+        let (temp_offset, humidity_offset) = self.parameters.sensor_offset;
+        (
+            self.temperature,
+            self.humidity,
+            self.temperature + temp_offset,
+            (self.humidity + humidity_offset).clamp(0.0, 100.0),
+        )
+    }
+}
+
+// Relay driver that switches the actuators of a simulated chamber instead of GPIO pins.
+pub struct SimulatedRelayDriver {
+    model: Arc<Mutex<ChamberModel>>,
+}
+
+impl SimulatedRelayDriver {
+    pub fn new(model: Arc<Mutex<ChamberModel>>) -> Self {
+        SimulatedRelayDriver { model }
+    }
+}
+
+impl RelayDriver for SimulatedRelayDriver {
+    fn change_relay_status(
+        &self,
+        actuator: Actuator,
+        _pin: u8,
+        status: RelayStatus,
+    ) -> Result<(), AtmosError> {
+        let mut model = self.model.lock().unwrap();
+        model.set_actuator(actuator, status);
+        Ok(())
+    }
+}
+
+// Advances the chamber model every simulated second and feeds its readings into the
+// shared data on the sensor schedule, standing in for `request_atmosphere`.
+pub async fn run_chamber_simulation(
+    model: Arc<Mutex<ChamberModel>>,
+    sd: AccessSharedData,
+    settings: Settings,
+    clock: Arc<dyn Clock>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    let step = Duration::from_secs(1);
+    let mut interval = interval(step);
+    let mut seconds_since_reading = u64::MAX;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let mut model = model.lock().unwrap();
+                model.step(step);
+
+                seconds_since_reading = seconds_since_reading.saturating_add(1);
+                if seconds_since_reading >= settings.sensor_read_cooldown.duration {
+                    let (t1, h1, t2, h2) = model.sensor_readings();
+                    record_sensor_readings(&sd, t1, h1, t2, h2, clock.now());
+                    seconds_since_reading = 0;
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Received shutdown signal");
+                break Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TokioClock;
+    use crate::config::test_settings;
+    use crate::initialization::initialize_shared_data;
+    use crate::monitor_atmosphere::monitor_atmosphere;
+    use crate::sqlite_client::SqliteClient;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn test_chamber_model_relaxes_to_ambient() {
+        let parameters = ChamberParameters {
+            product_moisture: 0.0,
+            ..Default::default()
+        };
+        let mut model = ChamberModel::new(parameters, 20.0, 50.0);
+        model.temperature = 10.0;
+        model.humidity = 90.0;
+
+        for _ in 0..48 * 60 {
+            model.step(Duration::from_secs(60));
+        }
+
+        assert!((model.temperature - 20.0).abs() < 0.1);
+        assert!((model.humidity - 50.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_chamber_model_fridge_cools() {
+        let mut model = ChamberModel::new(ChamberParameters::default(), 20.0, 50.0);
+        model.set_actuator(Actuator::Fridge, RelayStatus::On);
+
+        model.step(Duration::from_secs(600));
+
+        assert!(model.temperature < 20.0);
+        assert!(model.humidity < 50.0 + ChamberParameters::default().product_moisture);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_control_loop_holds_chamber_in_ideal_range() {
+        let settings = test_settings();
+        let sd = AccessSharedData::new(initialize_shared_data());
+        let model = Arc::new(Mutex::new(ChamberModel::new(
+            ChamberParameters::default(),
+            20.0,
+            50.0,
+        )));
+        let clock: Arc<dyn Clock> = Arc::new(TokioClock::new(OffsetDateTime::now_utc()));
+        let sqlite_client = Arc::new(SqliteClient::new(":memory:").unwrap());
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel::<()>(1);
+
+        let simulation = tokio::spawn(run_chamber_simulation(
+            model.clone(),
+            sd.clone(),
+            settings.clone(),
+            clock.clone(),
+            shutdown_rx.resubscribe(),
+        ));
+        let monitor = tokio::spawn(monitor_atmosphere(
+            sd.clone(),
+            settings.clone(),
+            sqlite_client,
+            Arc::new(SimulatedRelayDriver::new(model.clone())),
+            clock,
+            shutdown_rx.resubscribe(),
+        ));
+
+        // Let the chamber pull down from ambient, then sample three days every 10 minutes
+        tokio::time::sleep(Duration::from_secs(6 * 3600)).await;
+        let mut samples = 0;
+        let mut temp_in_range = 0;
+        let mut humidity_in_range = 0;
+        for _ in 0..3 * 24 * 6 {
+            tokio::time::sleep(Duration::from_secs(600)).await;
+            let (temperature, humidity) = {
+                let model = model.lock().unwrap();
+                (model.temperature, model.humidity)
+            };
+            samples += 1;
+            if (10.5..=14.5).contains(&temperature) {
+                temp_in_range += 1;
+            }
+            if (57.0..=83.0).contains(&humidity) {
+                humidity_in_range += 1;
+            }
+        }
+
+        shutdown_tx.send(()).unwrap();
+        simulation.await.unwrap().unwrap();
+        monitor.await.unwrap().unwrap();
+
+        assert!(
+            temp_in_range * 100 / samples >= 95,
+            "temperature in range for {}/{} samples",
+            temp_in_range,
+            samples
+        );
+        assert!(
+            humidity_in_range * 100 / samples >= 95,
+            "humidity in range for {}/{} samples",
+            humidity_in_range,
+            samples
+        );
+    }
+}
@@ -1,81 +1,402 @@
+use crate::batches::{Batch, WeighIn};
+use crate::config::WriteBufferSettings;
+use crate::controller_state::ControllerState;
 use crate::error::AtmosError;
+use crate::events::{ActuatorEvent, EventFilter, StoredActuatorEvent};
+use crate::export::ExportDataset;
+use crate::history::{DutyCycle, HistoryBucket, MetricSummary};
+use crate::migrations::run_migrations;
+use crate::psychrometrics::DerivedMetrics;
+use crate::recipes::{Recipe, StoredRecipe};
 use crate::relay_ctrl::RelayStatus;
-use rusqlite::{params, Connection, Result, Row};
-use serde_json::json;
-use std::sync::{Arc, Mutex};
+use crate::write_buffer::{BufferedWrite, WriteBuffer};
+use log::warn;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, DatabaseName, OpenFlags, OptionalExtension, Result, Row};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
 use time::OffsetDateTime;
 
+#[derive(Debug, Clone)]
+pub struct AtmosphereRecord {
+    pub timestamp: OffsetDateTime,
+    pub average_temperature: f32,
+    pub average_humidity: f32,
+    pub fridge_status: RelayStatus,
+    pub dehumidifier_status: RelayStatus,
+    pub humidifier_status: RelayStatus,
+    pub ventilator_status: RelayStatus,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AtmospherePoint {
+    pub timestamp: OffsetDateTime,
+    pub average_temperature: f32,
+    pub average_humidity: f32,
+}
+
+// One value measured by one of the sensors, stored next to the averaged row so sensor
+// drift can be diagnosed after the fact.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorReading {
+    pub sensor_id: u8,
+    pub metric: &'static str,
+    pub value: f32,
+}
+
+// A row of `atmosphere_data` with its sensor readings and actuator states
+#[derive(Debug, Clone)]
+pub struct AtmosphereRow {
+    pub timestamp: OffsetDateTime,
+    pub average_temperature: f32,
+    pub average_humidity: f32,
+    pub derived_metrics: DerivedMetrics,
+    pub atmospheric_quality_index: f32,
+    pub hanging_weight: Option<f32>,
+    pub fridge_status: RelayStatus,
+    pub dehumidifier_status: RelayStatus,
+    pub humidifier_status: RelayStatus,
+    pub ventilator_status: RelayStatus,
+    pub heater_status: RelayStatus,
+    pub sensor_readings: Vec<SensorReading>,
+}
+
+// Parses timestamps as written by `OffsetDateTime::to_string()` (what the database holds)
+// or as RFC 3339 (what people type).
+pub fn parse_timestamp(timestamp: &str) -> Result<OffsetDateTime, AtmosError> {
+    let stored = format_description!(
+        "[year]-[month]-[day] [hour padding:none]:[minute]:[second].[subsecond] [offset_hour sign:mandatory]:[offset_minute]:[offset_second]"
+    );
+    OffsetDateTime::parse(timestamp, stored)
+        .or_else(|_| OffsetDateTime::parse(timestamp, &Rfc3339))
+        .map_err(|e| AtmosError::InvalidInput(format!("Invalid timestamp {}: {}", timestamp, e)))
+}
+
+// Sortable form of a timestamp, stored next to the text in `timestamp_ms` columns
+pub fn unix_millis(timestamp: OffsetDateTime) -> i64 {
+    (timestamp.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+// A page of atmosphere history between two instants
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryQuery {
+    pub from: OffsetDateTime,
+    pub to: OffsetDateTime,
+    pub limit: usize,
+    pub offset: usize,
+    /// Oldest first, newest first otherwise
+    pub ascending: bool,
+}
+
+// Merges a rollup into an existing bucket, in case rows for an already rolled up period
+// turn up later (clock corrections, changed retention settings)
+const ROLLUP_UPSERT: &str = "ON CONFLICT(bucket_start_ms) DO UPDATE SET
+    min_temperature = MIN(min_temperature, excluded.min_temperature),
+    max_temperature = MAX(max_temperature, excluded.max_temperature),
+    avg_temperature = (avg_temperature * samples + excluded.avg_temperature * excluded.samples)
+        / (samples + excluded.samples),
+    min_humidity = MIN(min_humidity, excluded.min_humidity),
+    max_humidity = MAX(max_humidity, excluded.max_humidity),
+    avg_humidity = (avg_humidity * samples + excluded.avg_humidity * excluded.samples)
+        / (samples + excluded.samples),
+    avg_vapour_pressure_deficit = COALESCE((avg_vapour_pressure_deficit * samples
+        + excluded.avg_vapour_pressure_deficit * excluded.samples) / (samples + excluded.samples),
+        avg_vapour_pressure_deficit, excluded.avg_vapour_pressure_deficit),
+    avg_atmospheric_quality_index = COALESCE((avg_atmospheric_quality_index * samples
+        + excluded.avg_atmospheric_quality_index * excluded.samples) / (samples + excluded.samples),
+        avg_atmospheric_quality_index, excluded.avg_atmospheric_quality_index),
+    fridge_duty_cycle = (fridge_duty_cycle * samples + excluded.fridge_duty_cycle * excluded.samples)
+        / (samples + excluded.samples),
+    humidifier_duty_cycle = (humidifier_duty_cycle * samples
+        + excluded.humidifier_duty_cycle * excluded.samples) / (samples + excluded.samples),
+    dehumidifier_duty_cycle = (dehumidifier_duty_cycle * samples
+        + excluded.dehumidifier_duty_cycle * excluded.samples) / (samples + excluded.samples),
+    ventilator_duty_cycle = (ventilator_duty_cycle * samples
+        + excluded.ventilator_duty_cycle * excluded.samples) / (samples + excluded.samples),
+    heater_duty_cycle = COALESCE((heater_duty_cycle * samples
+        + excluded.heater_duty_cycle * excluded.samples) / (samples + excluded.samples),
+        heater_duty_cycle, excluded.heater_duty_cycle),
+    samples = samples + excluded.samples";
+
+const ROLLUP_COLUMNS: &str = "bucket_start_ms, bucket_start, samples,
+    min_temperature, max_temperature, avg_temperature,
+    min_humidity, max_humidity, avg_humidity,
+    avg_vapour_pressure_deficit, avg_atmospheric_quality_index,
+    fridge_duty_cycle, humidifier_duty_cycle, dehumidifier_duty_cycle, ventilator_duty_cycle,
+    heater_duty_cycle";
+
+// The aggregates raw history is rolled up into once it's past the retention period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rollup {
+    Hourly,
+    Daily,
+}
+
+impl Rollup {
+    fn table(&self) -> &'static str {
+        match self {
+            Rollup::Hourly => "atmosphere_hourly",
+            Rollup::Daily => "atmosphere_daily",
+        }
+    }
+
+    fn period_ms(&self) -> i64 {
+        match self {
+            Rollup::Hourly => 3_600_000,
+            Rollup::Daily => 86_400_000,
+        }
+    }
+}
+
+// Read connections opened next to the write connection of an on-disk database
+const READ_CONNECTIONS: usize = 4;
+// How long a connection waits for another one's lock before failing with SQLITE_BUSY
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// The database runs in WAL mode with one write connection and a few read-only ones, so
+// queries never wait for an insert and inserts never wait for a long query.
 #[derive(Clone)]
 pub struct SqliteClient {
     conn: Arc<Mutex<Connection>>,
+    /// Empty for in-memory databases, which can't be shared between connections, reads
+    /// then go through `conn`
+    readers: Arc<Vec<Mutex<Connection>>>,
+    next_reader: Arc<AtomicUsize>,
+    write_buffer: Arc<Mutex<WriteBuffer>>,
 }
 
 impl SqliteClient {
     pub fn new(path: &str) -> Result<Self, AtmosError> {
         let conn = Connection::open(path)?;
-        let client = SqliteClient {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let in_memory = path.is_empty() || path == ":memory:";
+        if !in_memory {
+            let journal_mode: String =
+                conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+            if !journal_mode.eq_ignore_ascii_case("wal") {
+                warn!(
+                    "{} is in {} journal mode instead of WAL",
+                    path, journal_mode
+                );
+            }
+            conn.pragma_update(None, "synchronous", "NORMAL")?;
+        }
+        let mut client = SqliteClient {
             conn: Arc::new(Mutex::new(conn)),
+            readers: Arc::new(Vec::new()),
+            next_reader: Arc::new(AtomicUsize::new(0)),
+            write_buffer: Arc::new(Mutex::new(WriteBuffer::default())),
         };
         client.initialize_database()?;
+
+        // Opened after the migrations so they see the current schema
+        if !in_memory {
+            let readers = (0..READ_CONNECTIONS)
+                .map(|_| {
+                    let reader = Connection::open_with_flags(
+                        path,
+                        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                    )?;
+                    reader.busy_timeout(BUSY_TIMEOUT)?;
+                    Ok(Mutex::new(reader))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            client.readers = Arc::new(readers);
+        }
         Ok(client)
     }
 
+    // A free read connection, or the next one in turn when all are busy
+    fn reader(&self) -> MutexGuard<'_, Connection> {
+        if self.readers.is_empty() {
+            return self.conn.lock().unwrap();
+        }
+        let start = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        for index in 0..self.readers.len() {
+            if let Ok(reader) = self.readers[(start + index) % self.readers.len()].try_lock() {
+                return reader;
+            }
+        }
+        self.readers[start % self.readers.len()].lock().unwrap()
+    }
+
+    // Runs blocking database work on tokio's blocking pool, so async tasks and request
+    // handlers don't stall a worker thread while SQLite is busy.
+    pub async fn call<T, F>(self: &Arc<Self>, f: F) -> Result<T, AtmosError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SqliteClient) -> Result<T, AtmosError> + Send + 'static,
+    {
+        let client = self.clone();
+        tokio::task::spawn_blocking(move || f(&client))
+            .await
+            .map_err(|e| AtmosError::TaskJoinError(e.to_string()))?
+    }
+
     fn initialize_database(&self) -> Result<(), AtmosError> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS atmosphere_data (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                timestamp TEXT NOT NULL,
-                average_temperature REAL NOT NULL,
-                average_humidity REAL NOT NULL,
-                fridge_status TEXT NOT NULL,
-                dehumidifier_status TEXT NOT NULL,
-                humidifier_status TEXT NOT NULL,
-                ventilator_status TEXT NOT NULL
-            )",
-            [],
-        )?;
+        let mut conn = self.conn.lock().unwrap();
+        run_migrations(&mut conn)
+    }
+
+    pub fn insert_atmosphere_row(&self, row: &AtmosphereRow) -> Result<(), AtmosError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        Self::write_atmosphere_row(&tx, row)?;
+        tx.commit()?;
         Ok(())
     }
 
-    pub fn insert_atmosphere_data(
-        &self,
-        timestamp: OffsetDateTime,
-        average_temperature: f32,
-        average_humidity: f32,
-        fridge_status: RelayStatus,
-        dehumidifier_status: RelayStatus,
-        humidifier_status: RelayStatus,
-        ventilator_status: RelayStatus,
-    ) -> Result<(), AtmosError> {
-        let conn = self.conn.lock().unwrap();
+    fn write_atmosphere_row(conn: &Connection, row: &AtmosphereRow) -> Result<(), AtmosError> {
         conn.execute(
             "INSERT INTO atmosphere_data (
-                timestamp, average_temperature, average_humidity, 
-                fridge_status, dehumidifier_status, humidifier_status, ventilator_status
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                timestamp, average_temperature, average_humidity,
+                dew_point, absolute_humidity, vapour_pressure_deficit, atmospheric_quality_index,
+                hanging_weight,
+                fridge_status, dehumidifier_status, humidifier_status, ventilator_status,
+                timestamp_ms
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
             params![
-                timestamp.to_string(),
-                average_temperature,
-                average_humidity,
-                fridge_status.to_string(),
-                dehumidifier_status.to_string(),
-                humidifier_status.to_string(),
-                ventilator_status.to_string(),
+                row.timestamp.to_string(),
+                row.average_temperature,
+                row.average_humidity,
+                row.derived_metrics.dew_point,
+                row.derived_metrics.absolute_humidity,
+                row.derived_metrics.vpd,
+                row.atmospheric_quality_index,
+                row.hanging_weight,
+                row.fridge_status.to_string(),
+                row.dehumidifier_status.to_string(),
+                row.humidifier_status.to_string(),
+                row.ventilator_status.to_string(),
+                unix_millis(row.timestamp),
             ],
         )?;
+        let atmosphere_data_id = conn.last_insert_rowid();
+
+        {
+            let mut insert_reading = conn.prepare(
+                "INSERT INTO sensor_readings (atmosphere_data_id, sensor_id, metric, value)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for reading in &row.sensor_readings {
+                insert_reading.execute(params![
+                    atmosphere_data_id,
+                    reading.sensor_id,
+                    reading.metric,
+                    reading.value,
+                ])?;
+            }
+
+            let mut insert_state = conn.prepare(
+                "INSERT INTO actuator_states (atmosphere_data_id, actuator, status)
+                 VALUES (?1, ?2, ?3)",
+            )?;
+            for (actuator, status) in [
+                ("fridge", row.fridge_status),
+                ("dehumidifier", row.dehumidifier_status),
+                ("humidifier", row.humidifier_status),
+                ("ventilator", row.ventilator_status),
+                ("heater", row.heater_status),
+            ] {
+                insert_state.execute(params![atmosphere_data_id, actuator, status.to_string()])?;
+            }
+        }
         Ok(())
     }
 
-    pub fn read_atmosphere_data(&self, limit: usize) -> Result<String, AtmosError> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT timestamp, average_temperature, average_humidity, 
-             fridge_status, dehumidifier_status, humidifier_status, ventilator_status 
-             FROM atmosphere_data ORDER BY timestamp DESC LIMIT ?",
+    // Returns one page of the rows in the range as JSON, with the number of rows in the
+    // whole range.
+    pub fn read_atmosphere_data(
+        &self,
+        query: &HistoryQuery,
+    ) -> Result<(String, usize), AtmosError> {
+        let conn = self.reader();
+        let range = params![unix_millis(query.from), unix_millis(query.to)];
+        let total: usize = conn.query_row(
+            "SELECT COUNT(*) FROM atmosphere_data WHERE timestamp_ms BETWEEN ?1 AND ?2",
+            range,
+            |row| row.get(0),
         )?;
+        // The page's ids, shared by the readings and actuator state lookups below so each
+        // of them is a single query for the whole page
+        let page_ids = format!(
+            "SELECT id FROM atmosphere_data WHERE timestamp_ms BETWEEN ?1 AND ?2
+             ORDER BY timestamp_ms {0}, id {0} LIMIT ?3 OFFSET ?4",
+            if query.ascending { "ASC" } else { "DESC" }
+        );
+        let page = params![
+            unix_millis(query.from),
+            unix_millis(query.to),
+            query.limit,
+            query.offset,
+        ];
 
-        let rows = stmt.query_map([limit], |row: &Row| {
+        // {"1": {"temperature": .., "humidity": ..}, "2": {..}} per row, empty for rows
+        // written before readings were stored
+        let mut sensors: HashMap<i64, Map<String, Value>> = HashMap::new();
+        let mut readings_stmt = conn.prepare(&format!(
+            "SELECT atmosphere_data_id, sensor_id, metric, value FROM sensor_readings
+             WHERE atmosphere_data_id IN ({}) ORDER BY atmosphere_data_id, sensor_id, id",
+            page_ids
+        ))?;
+        let readings = readings_stmt.query_map(page, |row: &Row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, f32>(3)?,
+            ))
+        })?;
+        for reading in readings {
+            let (id, sensor_id, metric, value) = reading?;
+            if let Value::Object(metrics) = sensors
+                .entry(id)
+                .or_default()
+                .entry(sensor_id.to_string())
+                .or_insert_with(|| Value::Object(Map::new()))
+            {
+                metrics.insert(metric, json!(value));
+            }
+        }
+
+        let mut actuators: HashMap<i64, Map<String, Value>> = HashMap::new();
+        let mut states_stmt = conn.prepare(&format!(
+            "SELECT atmosphere_data_id, actuator, status FROM actuator_states
+             WHERE atmosphere_data_id IN ({}) ORDER BY id",
+            page_ids
+        ))?;
+        let states = states_stmt.query_map(page, |row: &Row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+        for state in states {
+            let (id, actuator, status) = state?;
+            actuators
+                .entry(id)
+                .or_default()
+                .insert(actuator, Value::String(status));
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT timestamp, average_temperature, average_humidity,
+             fridge_status, dehumidifier_status, humidifier_status, ventilator_status,
+             dew_point, absolute_humidity, vapour_pressure_deficit, atmospheric_quality_index,
+             hanging_weight, id
+             FROM atmosphere_data WHERE timestamp_ms BETWEEN ?1 AND ?2
+             ORDER BY timestamp_ms {0}, id {0} LIMIT ?3 OFFSET ?4",
+            if query.ascending { "ASC" } else { "DESC" }
+        ))?;
+        let rows = stmt.query_map(page, |row: &Row| {
+            let id = row.get::<_, i64>(12)?;
             Ok(json!({
                 "timestamp": row.get::<_, String>(0)?,
                 "average_temperature": row.get::<_, f32>(1)?,
@@ -84,10 +405,1205 @@ impl SqliteClient {
                 "dehumidifier_status": row.get::<_, String>(4)?,
                 "humidifier_status": row.get::<_, String>(5)?,
                 "ventilator_status": row.get::<_, String>(6)?,
+                "dew_point": row.get::<_, Option<f32>>(7)?,
+                "absolute_humidity": row.get::<_, Option<f32>>(8)?,
+                "vapour_pressure_deficit": row.get::<_, Option<f32>>(9)?,
+                "atmospheric_quality_index": row.get::<_, Option<f32>>(10)?,
+                "hanging_weight": row.get::<_, Option<f32>>(11)?,
+                "sensors": sensors.remove(&id).unwrap_or_default(),
+                "actuators": actuators.remove(&id).unwrap_or_default(),
+            }))
+        })?;
+        let data = rows.collect::<Result<Vec<_>, _>>()?;
+        Ok((serde_json::to_string(&data)?, total))
+    }
+
+    pub fn start_maintenance_session(
+        &self,
+        started_at: OffsetDateTime,
+        trigger: &str,
+    ) -> Result<i64, AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO maintenance_sessions (started_at, trigger) VALUES (?1, ?2)",
+            params![started_at.to_string(), trigger],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn end_maintenance_session(
+        &self,
+        id: i64,
+        ended_at: OffsetDateTime,
+    ) -> Result<(), AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE maintenance_sessions SET ended_at = ?1 WHERE id = ?2",
+            params![ended_at.to_string(), id],
+        )?;
+        Ok(())
+    }
+
+    pub fn read_maintenance_sessions(&self, limit: usize) -> Result<String, AtmosError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            "SELECT id, started_at, ended_at, trigger
+             FROM maintenance_sessions ORDER BY id DESC LIMIT ?",
+        )?;
+
+        let rows = stmt.query_map([limit], |row: &Row| {
+            Ok(json!({
+                "id": row.get::<_, i64>(0)?,
+                "started_at": row.get::<_, String>(1)?,
+                "ended_at": row.get::<_, Option<String>>(2)?,
+                "trigger": row.get::<_, String>(3)?,
             }))
         })?;
 
         let data: Vec<serde_json::Value> = rows.collect::<Result<_, _>>()?;
         Ok(serde_json::to_string(&data)?)
     }
+
+    // Min/max/avg of the readings and actuator duty cycles per `bucket`, counted from
+    // `from`. Empty buckets are left out. Periods that were already rolled up by the
+    // retention are read from `atmosphere_hourly` and `atmosphere_daily`, each hour or day
+    // lands in the bucket it starts in and its VPD and AQI averages count as its extremes.
+    pub fn read_atmosphere_buckets(
+        &self,
+        from: OffsetDateTime,
+        to: OffsetDateTime,
+        bucket: time::Duration,
+    ) -> Result<Vec<HistoryBucket>, AtmosError> {
+        let bucket_ms = bucket.whole_milliseconds() as i64;
+        let rollup = |rollup: Rollup| {
+            format!(
+                "SELECT bucket_start_ms, samples,
+                 min_temperature, max_temperature, avg_temperature * samples,
+                 min_humidity, max_humidity, avg_humidity * samples,
+                 avg_vapour_pressure_deficit, avg_vapour_pressure_deficit,
+                 avg_vapour_pressure_deficit * samples,
+                 CASE WHEN avg_vapour_pressure_deficit IS NOT NULL THEN samples ELSE 0 END,
+                 avg_atmospheric_quality_index, avg_atmospheric_quality_index,
+                 avg_atmospheric_quality_index * samples,
+                 CASE WHEN avg_atmospheric_quality_index IS NOT NULL THEN samples ELSE 0 END,
+                 fridge_duty_cycle * samples, humidifier_duty_cycle * samples,
+                 dehumidifier_duty_cycle * samples, ventilator_duty_cycle * samples,
+                 heater_duty_cycle * samples,
+                 CASE WHEN heater_duty_cycle IS NOT NULL THEN samples ELSE 0 END
+                 FROM {}
+                 WHERE bucket_start_ms > ?1 - {} AND bucket_start_ms <= ?2",
+                rollup.table(),
+                rollup.period_ms()
+            )
+        };
+        // Raw rows and rollups as sums and counts, so both can be averaged together
+        let sql = format!(
+            "SELECT (MAX(timestamp_ms, ?1) - ?1) / ?3 AS bucket, SUM(samples),
+             MIN(min_t), MAX(max_t), SUM(sum_t) / SUM(samples),
+             MIN(min_h), MAX(max_h), SUM(sum_h) / SUM(samples),
+             MIN(min_vpd), MAX(max_vpd), SUM(sum_vpd) / SUM(vpd_samples),
+             MIN(min_aqi), MAX(max_aqi), SUM(sum_aqi) / SUM(aqi_samples),
+             SUM(fridge_on) * 1.0 / SUM(samples), SUM(humidifier_on) * 1.0 / SUM(samples),
+             SUM(dehumidifier_on) * 1.0 / SUM(samples), SUM(ventilator_on) * 1.0 / SUM(samples),
+             SUM(heater_on) * 1.0 / SUM(heater_samples)
+             FROM (
+                SELECT a.timestamp_ms, 1 AS samples,
+                a.average_temperature AS min_t, a.average_temperature AS max_t,
+                a.average_temperature AS sum_t,
+                a.average_humidity AS min_h, a.average_humidity AS max_h,
+                a.average_humidity AS sum_h,
+                a.vapour_pressure_deficit AS min_vpd, a.vapour_pressure_deficit AS max_vpd,
+                a.vapour_pressure_deficit AS sum_vpd,
+                a.vapour_pressure_deficit IS NOT NULL AS vpd_samples,
+                a.atmospheric_quality_index AS min_aqi, a.atmospheric_quality_index AS max_aqi,
+                a.atmospheric_quality_index AS sum_aqi,
+                a.atmospheric_quality_index IS NOT NULL AS aqi_samples,
+                a.fridge_status = 'On' AS fridge_on, a.humidifier_status = 'On' AS humidifier_on,
+                a.dehumidifier_status = 'On' AS dehumidifier_on,
+                a.ventilator_status = 'On' AS ventilator_on,
+                heater.status = 'On' AS heater_on, heater.status IS NOT NULL AS heater_samples
+                FROM atmosphere_data a
+                LEFT JOIN actuator_states heater
+                   ON heater.atmosphere_data_id = a.id AND heater.actuator = 'heater'
+                WHERE a.timestamp_ms BETWEEN ?1 AND ?2
+                UNION ALL {}
+                UNION ALL {}
+             )
+             GROUP BY bucket ORDER BY bucket",
+            rollup(Rollup::Hourly),
+            rollup(Rollup::Daily)
+        );
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&sql)?;
+
+        let summary = |row: &Row, first: usize| -> Result<MetricSummary> {
+            Ok(MetricSummary {
+                min: row.get(first)?,
+                max: row.get(first + 1)?,
+                avg: row.get(first + 2)?,
+            })
+        };
+        // Columns added by later migrations are NULL in older rows
+        let optional_summary = |row: &Row, first: usize| -> Result<Option<MetricSummary>> {
+            Ok(
+                match (
+                    row.get::<_, Option<f32>>(first)?,
+                    row.get::<_, Option<f32>>(first + 1)?,
+                    row.get::<_, Option<f32>>(first + 2)?,
+                ) {
+                    (Some(min), Some(max), Some(avg)) => Some(MetricSummary { min, max, avg }),
+                    _ => None,
+                },
+            )
+        };
+        let rows = stmt.query_map(
+            params![unix_millis(from), unix_millis(to), bucket_ms],
+            |row: &Row| {
+                let index: i64 = row.get(0)?;
+                Ok(HistoryBucket {
+                    start: (from + time::Duration::milliseconds(index * bucket_ms)).to_string(),
+                    samples: row.get(1)?,
+                    temperature: summary(row, 2)?,
+                    humidity: summary(row, 5)?,
+                    vapour_pressure_deficit: optional_summary(row, 8)?,
+                    atmospheric_quality_index: optional_summary(row, 11)?,
+                    duty_cycle: DutyCycle {
+                        fridge: row.get(14)?,
+                        humidifier: row.get(15)?,
+                        dehumidifier: row.get(16)?,
+                        ventilator: row.get(17)?,
+                        heater: row.get(18)?,
+                    },
+                })
+            },
+        )?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn read_atmosphere_records(
+        &self,
+        from: OffsetDateTime,
+        to: OffsetDateTime,
+    ) -> Result<Vec<AtmosphereRecord>, AtmosError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            "SELECT timestamp, average_temperature, average_humidity,
+             fridge_status, dehumidifier_status, humidifier_status, ventilator_status
+             FROM atmosphere_data WHERE timestamp_ms BETWEEN ?1 AND ?2
+             ORDER BY timestamp_ms ASC, id ASC",
+        )?;
+
+        let rows = stmt.query_map([unix_millis(from), unix_millis(to)], |row: &Row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, f32>(1)?,
+                row.get::<_, f32>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+            ))
+        })?;
+
+        let mut records = Vec::new();
+        for row in rows {
+            let (timestamp, temperature, humidity, fridge, dehumidifier, humidifier, ventilator) =
+                row?;
+            records.push(AtmosphereRecord {
+                timestamp: parse_timestamp(&timestamp)?,
+                average_temperature: temperature,
+                average_humidity: humidity,
+                fridge_status: fridge.parse()?,
+                dehumidifier_status: dehumidifier.parse()?,
+                humidifier_status: humidifier.parse()?,
+                ventilator_status: ventilator.parse()?,
+            });
+        }
+        Ok(records)
+    }
+
+    // Average temperature and humidity between `from` and `to`, oldest first. Periods the
+    // retention already rolled up give one point per hour or day, at its start.
+    pub fn read_atmosphere_points(
+        &self,
+        from: OffsetDateTime,
+        to: OffsetDateTime,
+    ) -> Result<Vec<AtmospherePoint>, AtmosError> {
+        let rollup = |rollup: Rollup| {
+            format!(
+                "SELECT bucket_start_ms, bucket_start, avg_temperature, avg_humidity
+                 FROM {}
+                 WHERE bucket_start_ms > ?1 - {} AND bucket_start_ms <= ?2",
+                rollup.table(),
+                rollup.period_ms()
+            )
+        };
+        let sql = format!(
+            "SELECT timestamp_ms, timestamp, average_temperature, average_humidity
+             FROM atmosphere_data WHERE timestamp_ms BETWEEN ?1 AND ?2
+             UNION ALL {}
+             UNION ALL {}
+             ORDER BY 1",
+            rollup(Rollup::Hourly),
+            rollup(Rollup::Daily)
+        );
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&sql)?;
+
+        let rows = stmt.query_map([unix_millis(from), unix_millis(to)], |row: &Row| {
+            Ok((
+                row.get::<_, String>(1)?,
+                row.get::<_, f32>(2)?,
+                row.get::<_, f32>(3)?,
+            ))
+        })?;
+
+        let mut points = Vec::new();
+        for row in rows {
+            let (timestamp, temperature, humidity) = row?;
+            points.push(AtmospherePoint {
+                timestamp: parse_timestamp(&timestamp)?,
+                average_temperature: temperature,
+                average_humidity: humidity,
+            });
+        }
+        Ok(points)
+    }
+
+    pub fn create_batch(
+        &self,
+        name: &str,
+        start_weight: f32,
+        target_loss_percent: f32,
+        program: Option<&str>,
+        recipe_id: Option<i64>,
+        started_at: OffsetDateTime,
+    ) -> Result<i64, AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO batches (
+                name, start_weight, target_loss_percent, program, recipe_id, started_at
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                name,
+                start_weight,
+                target_loss_percent,
+                program,
+                recipe_id,
+                started_at.to_string()
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn finish_batch(&self, id: i64, finished_at: OffsetDateTime) -> Result<bool, AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE batches SET finished_at = ?1 WHERE id = ?2 AND finished_at IS NULL",
+            params![finished_at.to_string(), id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn batch_from_row(row: &Row) -> Result<Batch> {
+        Ok(Batch {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            start_weight: row.get(2)?,
+            target_loss_percent: row.get(3)?,
+            program: row.get(4)?,
+            started_at: row.get(5)?,
+            finished_at: row.get(6)?,
+            recipe_id: row.get(7)?,
+        })
+    }
+
+    pub fn read_batches(&self) -> Result<Vec<Batch>, AtmosError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, start_weight, target_loss_percent, program, started_at, finished_at,
+             recipe_id
+             FROM batches ORDER BY id DESC",
+        )?;
+        let batches = stmt
+            .query_map([], Self::batch_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(batches)
+    }
+
+    pub fn read_batch(&self, id: i64) -> Result<Option<Batch>, AtmosError> {
+        let conn = self.reader();
+        let batch = conn
+            .query_row(
+                "SELECT id, name, start_weight, target_loss_percent, program, started_at, finished_at,
+             recipe_id
+                 FROM batches WHERE id = ?",
+                [id],
+                Self::batch_from_row,
+            )
+            .optional()?;
+        Ok(batch)
+    }
+
+    // The batch currently hanging in the chamber: the most recently started unfinished one
+    pub fn read_active_batch(&self) -> Result<Option<Batch>, AtmosError> {
+        let conn = self.reader();
+        let batch = conn
+            .query_row(
+                "SELECT id, name, start_weight, target_loss_percent, program, started_at, finished_at,
+             recipe_id
+                 FROM batches WHERE finished_at IS NULL ORDER BY id DESC LIMIT 1",
+                [],
+                Self::batch_from_row,
+            )
+            .optional()?;
+        Ok(batch)
+    }
+
+    pub fn insert_weigh_in(
+        &self,
+        batch_id: i64,
+        timestamp: OffsetDateTime,
+        weight: f32,
+        source: &str,
+    ) -> Result<i64, AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO weigh_ins (batch_id, timestamp, timestamp_ms, weight, source)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                batch_id,
+                timestamp.to_string(),
+                unix_millis(timestamp),
+                weight,
+                source
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    // Oldest first. Manual weigh-ins can be backdated, so they are sorted by their
+    // timestamp rather than by when they were logged.
+    pub fn read_weigh_ins(&self, batch_id: i64) -> Result<Vec<WeighIn>, AtmosError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            "SELECT id, batch_id, timestamp, weight, source FROM weigh_ins
+             WHERE batch_id = ? ORDER BY timestamp_ms ASC, id ASC",
+        )?;
+        let weigh_ins = stmt
+            .query_map([batch_id], |row: &Row| {
+                Ok(WeighIn {
+                    id: row.get(0)?,
+                    batch_id: row.get(1)?,
+                    timestamp: row.get(2)?,
+                    weight: row.get(3)?,
+                    source: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(weigh_ins)
+    }
+
+    fn recipe_from_row(row: &Row) -> Result<(i64, String, String, String)> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    }
+
+    fn stored_recipe(
+        (id, definition, created_at, updated_at): (i64, String, String, String),
+    ) -> Result<StoredRecipe, AtmosError> {
+        Ok(StoredRecipe {
+            id,
+            created_at,
+            updated_at,
+            recipe: serde_json::from_str(&definition)?,
+        })
+    }
+
+    pub fn insert_recipe(&self, recipe: &Recipe, now: OffsetDateTime) -> Result<i64, AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO recipes (name, definition, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?3)",
+            params![recipe.name, serde_json::to_string(recipe)?, now.to_string()],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn update_recipe(
+        &self,
+        id: i64,
+        recipe: &Recipe,
+        now: OffsetDateTime,
+    ) -> Result<bool, AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE recipes SET name = ?1, definition = ?2, updated_at = ?3 WHERE id = ?4",
+            params![
+                recipe.name,
+                serde_json::to_string(recipe)?,
+                now.to_string(),
+                id
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    pub fn delete_recipe(&self, id: i64) -> Result<bool, AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        // Batches keep their program name but lose the link to the deleted recipe
+        conn.execute(
+            "UPDATE batches SET recipe_id = NULL WHERE recipe_id = ?",
+            [id],
+        )?;
+        let deleted = conn.execute("DELETE FROM recipes WHERE id = ?", [id])?;
+        Ok(deleted > 0)
+    }
+
+    pub fn read_recipes(&self) -> Result<Vec<StoredRecipe>, AtmosError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            "SELECT id, definition, created_at, updated_at FROM recipes ORDER BY name ASC",
+        )?;
+        let rows = stmt
+            .query_map([], Self::recipe_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter().map(Self::stored_recipe).collect()
+    }
+
+    pub fn read_recipe(&self, id: i64) -> Result<Option<StoredRecipe>, AtmosError> {
+        let conn = self.reader();
+        let row = conn
+            .query_row(
+                "SELECT id, definition, created_at, updated_at FROM recipes WHERE id = ?",
+                [id],
+                Self::recipe_from_row,
+            )
+            .optional()?;
+        row.map(Self::stored_recipe).transpose()
+    }
+
+    // Replaces the saved controller state, written outside the write buffer so a restart
+    // right after a relay switched off still knows about it
+    pub fn save_controller_state(&self, state: &ControllerState) -> Result<(), AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO controller_state (id, saved_at, state) VALUES (1, ?1, ?2)
+             ON CONFLICT(id) DO UPDATE SET saved_at = excluded.saved_at, state = excluded.state",
+            params![state.saved_at, serde_json::to_string(state)?],
+        )?;
+        Ok(())
+    }
+
+    pub fn read_controller_state(&self) -> Result<Option<ControllerState>, AtmosError> {
+        let conn = self.reader();
+        let state: Option<String> = conn
+            .query_row(
+                "SELECT state FROM controller_state WHERE id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(state
+            .map(|state| serde_json::from_str(&state))
+            .transpose()?)
+    }
+
+    pub fn insert_actuator_event(&self, event: &ActuatorEvent) -> Result<i64, AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        Self::insert_actuator_event_row(&conn, event)?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn insert_actuator_event_row(
+        conn: &Connection,
+        event: &ActuatorEvent,
+    ) -> Result<(), AtmosError> {
+        conn.execute(
+            "INSERT INTO actuator_events (
+                timestamp, actuator, old_status, new_status, source, reason, readings,
+                timestamp_ms
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                event.timestamp,
+                event.actuator.to_string(),
+                event.old_status.to_string(),
+                event.new_status.to_string(),
+                event.source.to_string(),
+                event.reason,
+                serde_json::to_string(&event.readings)?,
+                unix_millis(parse_timestamp(&event.timestamp)?),
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Applies the write buffer settings and the matching `synchronous` level
+    pub fn configure_write_buffer(&self, settings: &WriteBufferSettings) -> Result<(), AtmosError> {
+        self.conn.lock().unwrap().pragma_update(
+            None,
+            "synchronous",
+            settings.durability.synchronous(),
+        )?;
+        self.write_buffer.lock().unwrap().configure(settings);
+        Ok(())
+    }
+
+    // Queues a write for the next flush, or writes it right away when buffering is off
+    pub fn buffer_write(&self, write: BufferedWrite) -> Result<(), AtmosError> {
+        match self.queue_write(write) {
+            Some(write) => self.write_now(&write),
+            None => Ok(()),
+        }
+    }
+
+    // Queues a write for the next flush without touching the database. Hands it back when
+    // buffering is off, to be written with `write_now` from a blocking context.
+    pub fn queue_write(&self, write: BufferedWrite) -> Option<BufferedWrite> {
+        self.write_buffer.lock().unwrap().push(write)
+    }
+
+    pub fn write_now(&self, write: &BufferedWrite) -> Result<(), AtmosError> {
+        self.write_all(std::slice::from_ref(write))
+    }
+
+    // Writes everything queued in one transaction. Returns the number of rows written, on
+    // failure they stay queued for the next flush.
+    pub fn flush_write_buffer(&self) -> Result<usize, AtmosError> {
+        let pending = self.write_buffer.lock().unwrap().take();
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        match self.write_all(&pending) {
+            Ok(()) => Ok(pending.len()),
+            Err(e) => {
+                self.write_buffer.lock().unwrap().restore(pending);
+                Err(e)
+            }
+        }
+    }
+
+    // Completes when the buffer filled up and wants flushing before the interval is up
+    pub async fn write_buffer_full(&self) {
+        let notify = self.write_buffer.lock().unwrap().full_notify();
+        notify.notified().await
+    }
+
+    fn write_all(&self, writes: &[BufferedWrite]) -> Result<(), AtmosError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for write in writes {
+            match write {
+                BufferedWrite::Atmosphere(row) => Self::write_atmosphere_row(&tx, row)?,
+                BufferedWrite::ActuatorEvent(event) => Self::insert_actuator_event_row(&tx, event)?,
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Newest first
+    pub fn read_actuator_events(
+        &self,
+        filter: &EventFilter,
+        from: Option<OffsetDateTime>,
+        to: Option<OffsetDateTime>,
+    ) -> Result<Vec<StoredActuatorEvent>, AtmosError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            "SELECT id, timestamp, actuator, old_status, new_status, source, reason, readings
+             FROM actuator_events
+             WHERE (?1 IS NULL OR actuator = ?1) AND (?2 IS NULL OR source = ?2)
+             AND (?3 IS NULL OR timestamp_ms >= ?3) AND (?4 IS NULL OR timestamp_ms <= ?4)
+             ORDER BY timestamp_ms DESC, id DESC LIMIT ?5",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                filter.actuator.map(|actuator| actuator.to_string()),
+                filter.source.map(|source| source.to_string()),
+                from.map(unix_millis),
+                to.map(unix_millis),
+                filter.limit.unwrap_or(100),
+            ],
+            |row: &Row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                    row.get::<_, String>(6)?,
+                    row.get::<_, String>(7)?,
+                ))
+            },
+        )?;
+
+        let mut events = Vec::new();
+        for row in rows {
+            let (id, timestamp, actuator, old_status, new_status, source, reason, readings) = row?;
+            events.push(StoredActuatorEvent {
+                id,
+                event: ActuatorEvent {
+                    timestamp,
+                    actuator: actuator.parse()?,
+                    old_status: old_status.parse()?,
+                    new_status: new_status.parse()?,
+                    source: source.parse()?,
+                    reason,
+                    readings: serde_json::from_str(&readings)?,
+                },
+            });
+        }
+        Ok(events)
+    }
+
+    // Aggregates raw rows recorded before `before` into `atmosphere_hourly` and deletes
+    // them with their sensor readings and actuator states. Returns the deleted row count.
+    pub fn roll_up_raw_history(&self, before: OffsetDateTime) -> Result<usize, AtmosError> {
+        let before = unix_millis(before);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            &format!(
+                "INSERT INTO atmosphere_hourly ({})
+                 SELECT (a.timestamp_ms / 3600000) * 3600000 AS bucket,
+                 strftime('%Y-%m-%dT%H:%M:%SZ', (a.timestamp_ms / 3600000) * 3600, 'unixepoch'),
+                 COUNT(*),
+                 MIN(a.average_temperature), MAX(a.average_temperature),
+                 AVG(a.average_temperature),
+                 MIN(a.average_humidity), MAX(a.average_humidity), AVG(a.average_humidity),
+                 AVG(a.vapour_pressure_deficit), AVG(a.atmospheric_quality_index),
+                 AVG(a.fridge_status = 'On'), AVG(a.humidifier_status = 'On'),
+                 AVG(a.dehumidifier_status = 'On'), AVG(a.ventilator_status = 'On'),
+                 AVG(heater.status = 'On')
+                 FROM atmosphere_data a
+                 LEFT JOIN actuator_states heater
+                    ON heater.atmosphere_data_id = a.id AND heater.actuator = 'heater'
+                 WHERE a.timestamp_ms < ?1
+                 GROUP BY bucket
+                 {}",
+                ROLLUP_COLUMNS, ROLLUP_UPSERT
+            ),
+            [before],
+        )?;
+        for child in ["sensor_readings", "actuator_states"] {
+            tx.execute(
+                &format!(
+                    "DELETE FROM {} WHERE atmosphere_data_id IN
+                     (SELECT id FROM atmosphere_data WHERE timestamp_ms < ?1)",
+                    child
+                ),
+                [before],
+            )?;
+        }
+        let deleted = tx.execute(
+            "DELETE FROM atmosphere_data WHERE timestamp_ms < ?1",
+            [before],
+        )?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    // Aggregates hourly rollups starting before `before` into `atmosphere_daily` and
+    // deletes them. Returns the deleted hour count.
+    pub fn roll_up_hourly_history(&self, before: OffsetDateTime) -> Result<usize, AtmosError> {
+        let before = unix_millis(before);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            &format!(
+                "INSERT INTO atmosphere_daily ({})
+                 SELECT (bucket_start_ms / 86400000) * 86400000 AS bucket,
+                 strftime('%Y-%m-%dT%H:%M:%SZ', (bucket_start_ms / 86400000) * 86400, 'unixepoch'),
+                 SUM(samples),
+                 MIN(min_temperature), MAX(max_temperature),
+                 SUM(avg_temperature * samples) / SUM(samples),
+                 MIN(min_humidity), MAX(max_humidity),
+                 SUM(avg_humidity * samples) / SUM(samples),
+                 SUM(avg_vapour_pressure_deficit * samples)
+                    / SUM(CASE WHEN avg_vapour_pressure_deficit IS NOT NULL THEN samples END),
+                 SUM(avg_atmospheric_quality_index * samples)
+                    / SUM(CASE WHEN avg_atmospheric_quality_index IS NOT NULL THEN samples END),
+                 SUM(fridge_duty_cycle * samples) / SUM(samples),
+                 SUM(humidifier_duty_cycle * samples) / SUM(samples),
+                 SUM(dehumidifier_duty_cycle * samples) / SUM(samples),
+                 SUM(ventilator_duty_cycle * samples) / SUM(samples),
+                 SUM(heater_duty_cycle * samples)
+                    / SUM(CASE WHEN heater_duty_cycle IS NOT NULL THEN samples END)
+                 FROM atmosphere_hourly
+                 WHERE bucket_start_ms < ?1
+                 GROUP BY bucket
+                 {}",
+                ROLLUP_COLUMNS, ROLLUP_UPSERT
+            ),
+            [before],
+        )?;
+        let deleted = tx.execute(
+            "DELETE FROM atmosphere_hourly WHERE bucket_start_ms < ?1",
+            [before],
+        )?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    pub fn incremental_vacuum_enabled(&self) -> Result<bool, AtmosError> {
+        let auto_vacuum: i64 = self
+            .reader()
+            .query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+        // 2 = INCREMENTAL
+        Ok(auto_vacuum == 2)
+    }
+
+    // Switching an existing database to incremental auto-vacuum only takes effect after a
+    // full VACUUM, which rewrites the whole file and needs as much free space again. Only
+    // run by `atmos vacuum`, never by the controller. Returns false if it was already on.
+    pub fn enable_incremental_vacuum(&self) -> Result<bool, AtmosError> {
+        if self.incremental_vacuum_enabled()? {
+            return Ok(false);
+        }
+        let conn = self.conn.lock().unwrap();
+        conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+        conn.execute_batch("VACUUM")?;
+        Ok(true)
+    }
+
+    // Returns the pages freed by deleted rows to the file system
+    pub fn incremental_vacuum(&self) -> Result<(), AtmosError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch("PRAGMA incremental_vacuum")?;
+        Ok(())
+    }
+
+    // Copies the database to `path` with SQLite's online backup API, in a single step on a
+    // read connection. The step reads one WAL snapshot, so it can't land in the middle of
+    // an insert's transaction, and the write connection carries on during the copy.
+    pub fn backup_to(&self, path: &Path) -> Result<(), AtmosError> {
+        let conn = self.reader();
+        let mut target = Connection::open(path)?;
+        let backup = Backup::new(&conn, &mut target)?;
+        match backup.step(-1)? {
+            StepResult::Done => Ok(()),
+            result => Err(AtmosError::IoError(std::io::Error::other(format!(
+                "Backup to {} did not complete: {:?}",
+                path.display(),
+                result
+            )))),
+        }
+    }
+
+    // Replaces the whole database with the content of the backup at `path`. Migrations
+    // bring an older backup up to date on the next start.
+    pub fn restore_from(&self, path: &Path) -> Result<(), AtmosError> {
+        let backup = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let integrity: String = backup.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        if integrity != "ok" {
+            return Err(AtmosError::InvalidInput(format!(
+                "Backup {} is corrupt: {}",
+                path.display(),
+                integrity
+            )));
+        }
+
+        let mut conn = self.conn.lock().unwrap();
+        conn.restore(
+            DatabaseName::Main,
+            path,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        Ok(())
+    }
+
+    // One page of an export, rows with an id above `after_id` in id order. Returns the rows
+    // in `dataset.columns()` order and the last id scanned, None once there are no more.
+    pub fn read_export_page(
+        &self,
+        dataset: ExportDataset,
+        from: OffsetDateTime,
+        to: OffsetDateTime,
+        after_id: i64,
+        limit: usize,
+    ) -> Result<(Vec<Vec<Value>>, Option<i64>), AtmosError> {
+        let sql = match dataset {
+            ExportDataset::Readings => {
+                "SELECT a.id, a.timestamp, a.average_temperature, a.average_humidity,
+                 a.fridge_status, a.dehumidifier_status, a.humidifier_status, a.ventilator_status,
+                 (SELECT status FROM actuator_states
+                    WHERE atmosphere_data_id = a.id AND actuator = 'heater'),
+                 a.dew_point, a.absolute_humidity, a.vapour_pressure_deficit,
+                 a.atmospheric_quality_index, a.hanging_weight,
+                 (SELECT value FROM sensor_readings
+                    WHERE atmosphere_data_id = a.id AND sensor_id = 1 AND metric = 'temperature'),
+                 (SELECT value FROM sensor_readings
+                    WHERE atmosphere_data_id = a.id AND sensor_id = 1 AND metric = 'humidity'),
+                 (SELECT value FROM sensor_readings
+                    WHERE atmosphere_data_id = a.id AND sensor_id = 2 AND metric = 'temperature'),
+                 (SELECT value FROM sensor_readings
+                    WHERE atmosphere_data_id = a.id AND sensor_id = 2 AND metric = 'humidity')
+                 FROM atmosphere_data a
+                 WHERE a.id > ?1 AND a.timestamp_ms BETWEEN ?2 AND ?3
+                 ORDER BY a.id LIMIT ?4"
+            }
+            ExportDataset::Events => {
+                "SELECT id, timestamp, actuator, old_status, new_status, source, reason, readings
+                 FROM actuator_events
+                 WHERE id > ?1 AND timestamp_ms BETWEEN ?2 AND ?3
+                 ORDER BY id LIMIT ?4"
+            }
+            ExportDataset::Batches => {
+                "SELECT w.id, b.id, b.name, b.start_weight, b.target_loss_percent,
+                 b.started_at, b.finished_at, w.timestamp, w.weight, w.source
+                 FROM weigh_ins w JOIN batches b ON b.id = w.batch_id
+                 WHERE w.id > ?1 AND w.timestamp_ms BETWEEN ?2 AND ?3
+                 ORDER BY w.id LIMIT ?4"
+            }
+        };
+
+        let conn = self.reader();
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(
+            params![after_id, unix_millis(from), unix_millis(to), limit],
+            export_values,
+        )?;
+
+        let mut last_id = None;
+        let mut page = Vec::new();
+        for row in rows {
+            let (id, mut values) = row?;
+            last_id = Some(id);
+            match dataset {
+                ExportDataset::Events => {
+                    if let Some(Value::String(readings)) = values.last() {
+                        let readings = serde_json::from_str(readings)?;
+                        *values.last_mut().unwrap() = readings;
+                    }
+                }
+                ExportDataset::Batches | ExportDataset::Readings => {}
+            }
+            page.push(values);
+        }
+        Ok((page, last_id))
+    }
+
+    // A page of `rollup` aggregates overlapping `from` to `to` in the readings export
+    // columns, after the one starting at `after_ms`. Actuators count as on for the period
+    // when they were on for at least half of it.
+    pub fn read_rollup_export_page(
+        &self,
+        rollup: Rollup,
+        from: OffsetDateTime,
+        to: OffsetDateTime,
+        after_ms: i64,
+        limit: usize,
+    ) -> Result<(Vec<Vec<Value>>, Option<i64>), AtmosError> {
+        let status = |duty_cycle: &str| {
+            format!(
+                "CASE WHEN {0} IS NULL THEN NULL WHEN {0} >= 0.5 THEN 'On' ELSE 'Off' END",
+                duty_cycle
+            )
+        };
+        let sql = format!(
+            "SELECT bucket_start_ms, bucket_start, avg_temperature, avg_humidity,
+             {}, {}, {}, {}, {},
+             NULL, NULL, avg_vapour_pressure_deficit, avg_atmospheric_quality_index,
+             NULL, NULL, NULL, NULL, NULL
+             FROM {}
+             WHERE bucket_start_ms > ?1 AND bucket_start_ms > ?2 - {} AND bucket_start_ms <= ?3
+             ORDER BY bucket_start_ms LIMIT ?4",
+            status("fridge_duty_cycle"),
+            status("dehumidifier_duty_cycle"),
+            status("humidifier_duty_cycle"),
+            status("ventilator_duty_cycle"),
+            status("heater_duty_cycle"),
+            rollup.table(),
+            rollup.period_ms()
+        );
+
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(
+            params![after_ms, unix_millis(from), unix_millis(to), limit],
+            export_values,
+        )?;
+        let mut last_ms = None;
+        let mut page = Vec::new();
+        for row in rows {
+            let (bucket_start_ms, values) = row?;
+            last_ms = Some(bucket_start_ms);
+            page.push(values);
+        }
+        Ok((page, last_ms))
+    }
+}
+
+// Splits an export row into its key (first column) and the exported values
+fn export_values(row: &Row) -> Result<(i64, Vec<Value>)> {
+    let key: i64 = row.get(0)?;
+    let values = (1..row.as_ref().column_count())
+        .map(|index| {
+            Ok(match row.get_ref(index)? {
+                ValueRef::Null => Value::Null,
+                ValueRef::Integer(value) => Value::from(value),
+                // Readings are written as f32, print them without the noise of the f64
+                // they're stored as
+                ValueRef::Real(value) => (value as f32)
+                    .to_string()
+                    .parse::<f64>()
+                    .map_or(Value::Null, Value::from),
+                ValueRef::Text(text) | ValueRef::Blob(text) => {
+                    Value::String(String::from_utf8_lossy(text).into_owned())
+                }
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((key, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn temp_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("atmos-{}-{}.db", name, std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    fn insert(sqlite_client: &SqliteClient, timestamp: OffsetDateTime) {
+        sqlite_client
+            .insert_atmosphere_row(&AtmosphereRow {
+                timestamp,
+                average_temperature: 12.0,
+                average_humidity: 80.0,
+                derived_metrics: DerivedMetrics::default(),
+                atmospheric_quality_index: 90.0,
+                hanging_weight: None,
+                fridge_status: RelayStatus::On,
+                dehumidifier_status: RelayStatus::Off,
+                humidifier_status: RelayStatus::Off,
+                ventilator_status: RelayStatus::Off,
+                heater_status: RelayStatus::Off,
+                sensor_readings: Vec::new(),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_history_includes_sensor_readings_and_actuator_states() {
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        let now = OffsetDateTime::now_utc();
+        for (seconds, heater_status) in [(0, RelayStatus::On), (1, RelayStatus::Off)] {
+            sqlite_client
+                .insert_atmosphere_row(&AtmosphereRow {
+                    timestamp: now + time::Duration::seconds(seconds),
+                    average_temperature: 12.0,
+                    average_humidity: 80.0,
+                    derived_metrics: DerivedMetrics::default(),
+                    atmospheric_quality_index: 90.0,
+                    hanging_weight: None,
+                    fridge_status: RelayStatus::Off,
+                    dehumidifier_status: RelayStatus::Off,
+                    humidifier_status: RelayStatus::Off,
+                    ventilator_status: RelayStatus::Off,
+                    heater_status,
+                    sensor_readings: vec![
+                        SensorReading {
+                            sensor_id: 1,
+                            metric: "temperature",
+                            value: 11.5,
+                        },
+                        SensorReading {
+                            sensor_id: 2,
+                            metric: "humidity",
+                            value: 81.0 + seconds as f32,
+                        },
+                    ],
+                })
+                .unwrap();
+        }
+        insert(&sqlite_client, now + time::Duration::seconds(2));
+
+        let query = HistoryQuery {
+            from: now,
+            to: now + time::Duration::seconds(2),
+            limit: 2,
+            offset: 1,
+            ascending: false,
+        };
+        let (json_data, total) = sqlite_client.read_atmosphere_data(&query).unwrap();
+        let rows: Vec<Value> = serde_json::from_str(&json_data).unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["sensors"]["2"]["humidity"], json!(82.0));
+        assert_eq!(rows[0]["actuators"]["heater"], json!("Off"));
+        assert_eq!(rows[1]["sensors"]["1"]["temperature"], json!(11.5));
+        assert_eq!(rows[1]["sensors"]["2"]["humidity"], json!(81.0));
+        assert_eq!(rows[1]["actuators"]["heater"], json!("On"));
+        assert_eq!(rows[1]["actuators"]["fridge"], json!("Off"));
+    }
+
+    #[test]
+    fn test_backdated_weigh_ins_are_read_in_time_order() {
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        let started_at = OffsetDateTime::UNIX_EPOCH;
+        let batch_id = sqlite_client
+            .create_batch("coppa", 1000.0, 30.0, None, None, started_at)
+            .unwrap();
+        let day = |days| started_at + time::Duration::days(days);
+        sqlite_client
+            .insert_weigh_in(batch_id, day(10), 800.0, "manual")
+            .unwrap();
+        // Backfilled after the later one
+        sqlite_client
+            .insert_weigh_in(batch_id, day(5), 900.0, "manual")
+            .unwrap();
+
+        let weights: Vec<f32> = sqlite_client
+            .read_weigh_ins(batch_id)
+            .unwrap()
+            .iter()
+            .map(|weigh_in| weigh_in.weight)
+            .collect();
+        assert_eq!(weights, vec![900.0, 800.0]);
+    }
+
+    #[test]
+    fn test_file_database_reads_through_wal_readers() {
+        let path = temp_db("wal");
+        let sqlite_client = SqliteClient::new(&path).unwrap();
+        assert_eq!(sqlite_client.readers.len(), READ_CONNECTIONS);
+        let journal_mode: String = sqlite_client
+            .reader()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        let now = OffsetDateTime::now_utc();
+        insert(&sqlite_client, now);
+        // Held while the write connection keeps inserting
+        let _busy_reader = sqlite_client.reader();
+        insert(&sqlite_client, now);
+        assert_eq!(
+            sqlite_client
+                .read_atmosphere_records(now - time::Duration::SECOND, now)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_backup_does_not_hold_the_write_connection() {
+        let path = temp_db("backup-source");
+        let backup = temp_db("backup-copy");
+        let sqlite_client = SqliteClient::new(&path).unwrap();
+        insert(&sqlite_client, OffsetDateTime::now_utc());
+
+        // Would deadlock if the backup needed the write connection
+        let writer = sqlite_client.conn.lock().unwrap();
+        sqlite_client.backup_to(Path::new(&backup)).unwrap();
+        drop(writer);
+
+        let copy = SqliteClient::new(&backup).unwrap();
+        let (_, total) = copy
+            .read_atmosphere_data(&HistoryQuery {
+                from: OffsetDateTime::UNIX_EPOCH,
+                to: OffsetDateTime::now_utc(),
+                limit: 1,
+                offset: 0,
+                ascending: true,
+            })
+            .unwrap();
+        assert_eq!(total, 1);
+    }
+
+    // Worst delay of a 10 ms control tick inserting a reading while eight tasks keep
+    // reading the whole history, on a runtime with as few workers as the Raspberry Pi.
+    async fn worst_tick_delay(sqlite_client: Arc<SqliteClient>, blocking_reads: bool) -> Duration {
+        let to = OffsetDateTime::now_utc();
+        let from = to - time::Duration::days(365);
+        let deadline = Instant::now() + Duration::from_secs(3);
+
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let sqlite_client = sqlite_client.clone();
+                tokio::spawn(async move {
+                    while Instant::now() < deadline {
+                        if blocking_reads {
+                            sqlite_client.read_atmosphere_records(from, to).unwrap();
+                        } else {
+                            sqlite_client
+                                .call(move |db| db.read_atmosphere_records(from, to))
+                                .await
+                                .unwrap();
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+
+        let mut worst = Duration::ZERO;
+        let mut interval = tokio::time::interval(Duration::from_millis(10));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval.tick().await;
+        while Instant::now() < deadline {
+            let scheduled = interval.tick().await;
+            sqlite_client
+                .call(move |db| {
+                    insert(db, OffsetDateTime::now_utc());
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            worst = worst.max(scheduled.elapsed());
+        }
+        for reader in readers {
+            reader.await.unwrap();
+        }
+        worst
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_history_queries_do_not_stall_ticks
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore]
+    async fn bench_history_queries_do_not_stall_ticks() {
+        let path = temp_db("bench");
+        let sqlite_client = Arc::new(SqliteClient::new(&path).unwrap());
+        let start = OffsetDateTime::now_utc() - time::Duration::days(30);
+        // A month of readings every 30 seconds
+        {
+            let mut conn = sqlite_client.conn.lock().unwrap();
+            let tx = conn.transaction().unwrap();
+            for i in 0..30 * 24 * 120 {
+                let timestamp = start + time::Duration::seconds(30 * i);
+                tx.execute(
+                    "INSERT INTO atmosphere_data (
+                        timestamp, timestamp_ms, average_temperature, average_humidity,
+                        fridge_status, dehumidifier_status, humidifier_status, ventilator_status
+                    ) VALUES (?1, ?2, 12.0, 80.0, 'On', 'Off', 'Off', 'Off')",
+                    params![timestamp.to_string(), unix_millis(timestamp)],
+                )
+                .unwrap();
+            }
+            tx.commit().unwrap();
+        }
+
+        // Everything on the one connection, queried from the async tasks: how it used to be
+        let single_connection = Arc::new(SqliteClient {
+            conn: sqlite_client.conn.clone(),
+            readers: Arc::new(Vec::new()),
+            next_reader: Arc::new(AtomicUsize::new(0)),
+            write_buffer: sqlite_client.write_buffer.clone(),
+        });
+        let before = worst_tick_delay(single_connection, true).await;
+        let after = worst_tick_delay(sqlite_client, false).await;
+        println!(
+            "worst control tick delay: {:?} single connection, {:?} with WAL readers",
+            before, after
+        );
+        assert!(after < before);
+    }
 }
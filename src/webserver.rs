@@ -1,7 +1,25 @@
+use crate::dry_run::RecordingRelayDriver;
+use crate::load_cell::LoadCell;
+use crate::relay_ctrl::RelayDriver;
+use crate::routes::admin::post_backup;
 use crate::routes::atmosphere::get_atmosphere;
 use crate::routes::atmosphere::get_atmosphere_history;
+use crate::routes::batches::{
+    create_batch, finish_batch, get_batch, get_batch_atmosphere, get_batches, log_weigh_in,
+};
+use crate::routes::dry_run::get_dry_run_actions;
+use crate::routes::events::get_events;
+use crate::routes::export::get_export;
 use crate::routes::get_full_atmospheric_data;
 use crate::routes::heartbeat::pulse;
+use crate::routes::load_cell::{calibrate_load_cell, get_load_cell, tare_load_cell};
+use crate::routes::maintenance::{
+    get_maintenance_sessions, get_maintenance_status, start_maintenance_mode, stop_maintenance_mode,
+};
+use crate::routes::recipes::{
+    delete_recipe, export_recipe, get_recipe, get_recipes, import_recipe, post_recipe, put_recipe,
+    start_batch_from_recipe,
+};
 use crate::routes::relay_control::{
     change_dehumidifier_status, change_fridge_status, change_humidifier_status,
     change_ventilator_status,
@@ -10,10 +28,14 @@ use crate::routes::relay_status::{
     get_all_statuses, get_dehumidifier_status, get_fridge_status, get_humidifier_status,
     get_ventilator_status,
 };
+use crate::routes::stream::get_stream;
+use crate::routes::v2;
 use crate::AccessSharedData;
 use crate::Arc;
 use crate::Settings;
 use crate::SqliteClient;
+use actix_web::dev::Service;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
 use actix_web::{web, App, HttpServer};
 use log::info;
 
@@ -22,18 +44,49 @@ pub async fn run_app(
     settings: Settings,
     mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
     sqlite_client: Arc<SqliteClient>,
+    relay_driver: Arc<dyn RelayDriver>,
+    dry_run_driver: Arc<RecordingRelayDriver>,
+    load_cell: Arc<LoadCell>,
 ) -> std::io::Result<()> {
     info!("Starting HTTP server at http://localhost:8080");
 
     let common_data = web::Data::new(sd);
     let common_settings = web::Data::new(settings);
     let common_sqlite_client = web::Data::new(sqlite_client);
+    let common_relay_driver = web::Data::new(relay_driver);
+    let common_dry_run_driver = web::Data::new(dry_run_driver);
+    let common_load_cell = web::Data::new(load_cell);
 
     let server = HttpServer::new(move || {
         App::new()
             .app_data(common_data.clone())
             .app_data(common_settings.clone())
             .app_data(common_sqlite_client.clone())
+            .app_data(common_relay_driver.clone())
+            .app_data(common_dry_run_driver.clone())
+            .app_data(common_load_cell.clone())
+            // Responses from routes that have a v2 replacement point to it
+            .wrap_fn(|req, srv| {
+                let successor = v2::successor(req.path());
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    if let Some(successor) = successor {
+                        let headers = response.headers_mut();
+                        headers.insert(
+                            HeaderName::from_static("deprecation"),
+                            HeaderValue::from_static("true"),
+                        );
+                        if let Ok(link) = HeaderValue::from_str(&format!(
+                            "<{}>; rel=\"successor-version\"",
+                            successor
+                        )) {
+                            headers.insert(header::LINK, link);
+                        }
+                    }
+                    Ok(response)
+                }
+            })
             .service(get_atmosphere)
             .service(get_full_atmospheric_data)
             .service(get_atmosphere_history)
@@ -47,6 +100,33 @@ pub async fn run_app(
             .service(get_humidifier_status)
             .service(get_dehumidifier_status)
             .service(get_ventilator_status)
+            .service(get_maintenance_status)
+            .service(get_maintenance_sessions)
+            .service(start_maintenance_mode)
+            .service(stop_maintenance_mode)
+            .service(get_dry_run_actions)
+            .service(get_batches)
+            .service(create_batch)
+            .service(get_batch)
+            .service(log_weigh_in)
+            .service(finish_batch)
+            .service(get_batch_atmosphere)
+            .service(get_recipes)
+            .service(post_recipe)
+            .service(import_recipe)
+            .service(get_recipe)
+            .service(put_recipe)
+            .service(delete_recipe)
+            .service(export_recipe)
+            .service(start_batch_from_recipe)
+            .service(get_load_cell)
+            .service(tare_load_cell)
+            .service(calibrate_load_cell)
+            .service(get_events)
+            .service(get_export)
+            .service(post_backup)
+            .service(get_stream)
+            .service(v2::scope())
     })
     .bind(("0.0.0.0", 8080))?
     .run();
@@ -0,0 +1,188 @@
+use crate::config::{Settings, WriteBufferSettings};
+use crate::error::AtmosError;
+use crate::events::ActuatorEvent;
+use crate::sqlite_client::{AtmosphereRow, SqliteClient};
+use log::{debug, error, info};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::interval;
+
+// Flushes that failed are retried with the next one, up to this many windows of rows
+const MAX_BUFFERED_WINDOWS: usize = 10;
+
+#[derive(Debug, Clone)]
+pub enum BufferedWrite {
+    Atmosphere(AtmosphereRow),
+    ActuatorEvent(ActuatorEvent),
+}
+
+// Writes waiting for the next flush, owned by the `SqliteClient`
+pub struct WriteBuffer {
+    settings: WriteBufferSettings,
+    pending: Vec<BufferedWrite>,
+    full: Arc<Notify>,
+}
+
+// Off until `configure` is called, nothing flushes the buffer of a client used without
+// `monitor_write_buffer` (CLI commands, tests)
+impl Default for WriteBuffer {
+    fn default() -> Self {
+        WriteBuffer {
+            settings: WriteBufferSettings {
+                enabled: false,
+                ..WriteBufferSettings::default()
+            },
+            pending: Vec::new(),
+            full: Arc::default(),
+        }
+    }
+}
+
+impl WriteBuffer {
+    pub fn configure(&mut self, settings: &WriteBufferSettings) {
+        self.settings = settings.clone();
+    }
+
+    // Queues `write`, or hands it back when buffering is off
+    pub fn push(&mut self, write: BufferedWrite) -> Option<BufferedWrite> {
+        if !self.settings.enabled {
+            return Some(write);
+        }
+        self.pending.push(write);
+        if self.pending.len() >= self.settings.max_rows {
+            self.full.notify_one();
+        }
+        None
+    }
+
+    pub fn take(&mut self) -> Vec<BufferedWrite> {
+        std::mem::take(&mut self.pending)
+    }
+
+    // Puts back writes whose flush failed, ahead of anything queued since. The oldest are
+    // dropped if the database stays unwritable for too long.
+    pub fn restore(&mut self, mut writes: Vec<BufferedWrite>) {
+        writes.append(&mut self.pending);
+        let limit = self.settings.max_rows.max(1) * MAX_BUFFERED_WINDOWS;
+        if writes.len() > limit {
+            let dropped = writes.len() - limit;
+            error!("Write buffer full, dropping the {} oldest rows", dropped);
+            writes.drain(..dropped);
+        }
+        self.pending = writes;
+    }
+
+    pub fn full_notify(&self) -> Arc<Notify> {
+        self.full.clone()
+    }
+}
+
+async fn flush(sqlite_client: &Arc<SqliteClient>) {
+    match sqlite_client.call(|db| db.flush_write_buffer()).await {
+        Ok(0) => {}
+        Ok(rows) => debug!("flush() -> wrote {} buffered rows", rows),
+        Err(e) => error!("Failed to flush the write buffer: {}", e),
+    }
+}
+
+// Flushes the write buffer every `flush_interval` seconds, when it fills up and once more
+// on shutdown.
+pub async fn monitor_write_buffer(
+    settings: Settings,
+    sqlite_client: Arc<SqliteClient>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), AtmosError> {
+    let write_buffer = settings.write_buffer;
+    if !write_buffer.enabled {
+        info!("Write buffering disabled, writing every reading right away");
+        let _ = shutdown_rx.recv().await;
+        return Ok(());
+    }
+
+    let mut interval = interval(std::time::Duration::from_secs(write_buffer.flush_interval));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => flush(&sqlite_client).await,
+            _ = sqlite_client.write_buffer_full() => flush(&sqlite_client).await,
+            _ = shutdown_rx.recv() => {
+                info!("Received shutdown signal");
+                flush(&sqlite_client).await;
+                break Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventFilter;
+    use crate::initialization::initialize_shared_data;
+    use crate::relay_ctrl::{Actuator, RelayStatus};
+    use crate::shared_data::AccessSharedData;
+    use crate::{events::EventSource, sqlite_client::HistoryQuery};
+    use time::OffsetDateTime;
+
+    #[test]
+    fn test_writes_wait_for_flush() {
+        let sqlite_client = SqliteClient::new(":memory:").unwrap();
+        sqlite_client
+            .configure_write_buffer(&WriteBufferSettings {
+                enabled: true,
+                max_rows: 2,
+                ..Default::default()
+            })
+            .unwrap();
+        let sd = AccessSharedData::new(initialize_shared_data());
+        let now = OffsetDateTime::now_utc();
+
+        sqlite_client
+            .buffer_write(BufferedWrite::ActuatorEvent(ActuatorEvent::new(
+                &sd,
+                now,
+                Actuator::Fridge,
+                RelayStatus::Off,
+                RelayStatus::On,
+                EventSource::Auto,
+                "test",
+            )))
+            .unwrap();
+        sqlite_client
+            .buffer_write(BufferedWrite::Atmosphere(AtmosphereRow {
+                timestamp: now,
+                average_temperature: 12.0,
+                average_humidity: 80.0,
+                derived_metrics: Default::default(),
+                atmospheric_quality_index: 90.0,
+                hanging_weight: None,
+                fridge_status: RelayStatus::On,
+                dehumidifier_status: RelayStatus::Off,
+                humidifier_status: RelayStatus::Off,
+                ventilator_status: RelayStatus::Off,
+                heater_status: RelayStatus::Off,
+                sensor_readings: Vec::new(),
+            }))
+            .unwrap();
+        let events = || {
+            sqlite_client
+                .read_actuator_events(&EventFilter::default(), None, None)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(events(), 0);
+
+        assert_eq!(sqlite_client.flush_write_buffer().unwrap(), 2);
+        assert_eq!(sqlite_client.flush_write_buffer().unwrap(), 0);
+        assert_eq!(events(), 1);
+        let (_, readings) = sqlite_client
+            .read_atmosphere_data(&HistoryQuery {
+                from: now - time::Duration::SECOND,
+                to: now,
+                limit: 10,
+                offset: 0,
+                ascending: true,
+            })
+            .unwrap();
+        assert_eq!(readings, 1);
+    }
+}